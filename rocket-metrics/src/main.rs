@@ -1,34 +1,15 @@
-use std::time::Duration;
-
 use anyhow::Result;
-use once_cell::sync::Lazy;
-use opentelemetry::{global, metrics::MetricsError, KeyValue};
-use opentelemetry_otlp::TonicExporterBuilder;
-use opentelemetry_sdk::{
-    metrics::{
-        reader::{DefaultAggregationSelector, DefaultTemporalitySelector},
-        SdkMeterProvider,
-    },
-    Resource,
-};
+use opentelemetry::global;
+use opentelemetry_sdk::{metrics::SdkMeterProvider, Resource};
 use rocket_metrics::MetricsFairings;
+use simple_observability_pipeline::{
+    config::DEFAULT_GRPC_ENDPOINT, init_exporter_builder, init_metrics, ExporterConfig, MetricView,
+    MetricsConfig, ResourceBuilder,
+};
 
 #[macro_use]
 extern crate rocket;
 
-static RESOURCE: Lazy<Resource> = Lazy::new(|| {
-    Resource::new(vec![
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-            "rocket-server",
-        ),
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
-            "localhost",
-        ),
-    ])
-});
-
 mod routes {
     use rocket::serde::json::Json;
     use serde::Deserialize;
@@ -75,29 +56,37 @@ fn main() -> Result<(), rocket::Error> {
     Ok(())
 }
 
+fn resource() -> Resource {
+    ResourceBuilder::new("rocket-server")
+        .with_service_version(env!("CARGO_PKG_VERSION"))
+        .with_attribute(opentelemetry::KeyValue::new(
+            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
+            "localhost",
+        ))
+        .build()
+}
+
+fn metrics_config() -> Result<MetricsConfig> {
+    Ok(MetricsConfig::from_env()?
+        // The path contains the user-supplied name, so keeping it would create a new series for
+        // every name.
+        .with_view(
+            MetricView::new("service.request").with_allowed_attributes(["method", "sdk.version"]),
+        )
+        .with_view(
+            MetricView::new("service.response").with_allowed_attributes(["method", "status"]),
+        ))
+}
+
 async fn init_observability() -> Result<SdkMeterProvider> {
-    let exporter = opentelemetry_otlp::new_exporter().tonic();
+    let exporter_config = ExporterConfig::grpc(DEFAULT_GRPC_ENDPOINT).merge_env()?;
+    let exporter = init_exporter_builder(&exporter_config)?;
 
     // Initialize the metrics pipeline
-    let meter_provider = init_metrics(exporter, RESOURCE.clone())?;
+    let meter_provider = init_metrics(exporter, resource(), &metrics_config()?)?;
 
     // Set globals
     global::set_meter_provider(meter_provider.clone());
 
     Ok(meter_provider)
 }
-
-fn init_metrics(
-    tonic_exporter_builder: TonicExporterBuilder,
-    resource: Resource,
-) -> Result<opentelemetry_sdk::metrics::SdkMeterProvider, MetricsError> {
-    opentelemetry_otlp::new_pipeline()
-        .metrics(opentelemetry_sdk::runtime::Tokio)
-        .with_exporter(tonic_exporter_builder)
-        .with_period(Duration::from_secs(3))
-        .with_timeout(Duration::from_secs(10))
-        .with_resource(resource)
-        .with_aggregation_selector(DefaultAggregationSelector::new())
-        .with_temporality_selector(DefaultTemporalitySelector::new())
-        .build()
-}
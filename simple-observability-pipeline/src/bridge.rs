@@ -0,0 +1,433 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::time::SystemTime;
+
+use opentelemetry::logs::{AnyValue, LogRecord as _, Logger as _, LoggerProvider as _, Severity};
+use opentelemetry::trace::{Event as SpanEvent, SpanContext, Status, TraceContextExt, TraceState};
+use opentelemetry::{Key, KeyValue};
+use opentelemetry_sdk::logs::{Logger, LoggerProvider, TraceContext};
+use opentelemetry_semantic_conventions::trace::{EXCEPTION_MESSAGE, EXCEPTION_TYPE};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Which fields of the enclosing spans are copied onto the log records.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SpanFields {
+    #[default]
+    None,
+    All,
+    Only(Vec<String>),
+}
+
+impl SpanFields {
+    fn is_none(&self) -> bool {
+        matches!(self, SpanFields::None)
+            || matches!(self, SpanFields::Only(names) if names.is_empty())
+    }
+
+    fn allows(&self, name: &str) -> bool {
+        match self {
+            SpanFields::None => false,
+            SpanFields::All => true,
+            SpanFields::Only(names) => names.iter().any(|n| n == name),
+        }
+    }
+}
+
+/// The OTel severity of the log records emitted for each `tracing` level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeverityMapping {
+    pub trace: Severity,
+    pub debug: Severity,
+    pub info: Severity,
+    pub warn: Severity,
+    pub error: Severity,
+}
+
+impl Default for SeverityMapping {
+    fn default() -> Self {
+        Self {
+            trace: Severity::Trace,
+            debug: Severity::Debug,
+            info: Severity::Info,
+            warn: Severity::Warn,
+            error: Severity::Error,
+        }
+    }
+}
+
+impl SeverityMapping {
+    pub fn severity(&self, level: &Level) -> Severity {
+        match *level {
+            Level::TRACE => self.trace,
+            Level::DEBUG => self.debug,
+            Level::INFO => self.info,
+            Level::WARN => self.warn,
+            Level::ERROR => self.error,
+        }
+    }
+}
+
+/// Controls how `tracing` events are turned into OTel log records and span events by
+/// [`crate::create_providers`].
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    pub span_fields: SpanFields,
+    pub severity: SeverityMapping,
+    /// Whether to prefix copied span fields with the span name, eg. `make_request.attempt`.
+    pub prefix_span_fields: bool,
+    /// Add an `exception` event to the current span for every `error!` event that carries an
+    /// error value, eg. `error!(error = &e as &dyn Error, "request failed")`.
+    pub exception_events: bool,
+    /// Set the span status to `Error`, described by the error message, when an
+    /// `#[instrument(err)]` function returns `Err` or an `error!` event carries an error value.
+    ///
+    /// `tracing-opentelemetry` marks the span of any `error!` event as failed even when this is
+    /// disabled, only without a description.
+    pub error_status: bool,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            span_fields: SpanFields::None,
+            severity: SeverityMapping::default(),
+            prefix_span_fields: false,
+            exception_events: true,
+            error_status: true,
+        }
+    }
+}
+
+impl BridgeConfig {
+    pub fn with_span_fields(mut self, span_fields: SpanFields) -> Self {
+        self.span_fields = span_fields;
+        self
+    }
+
+    pub fn with_severity(mut self, severity: SeverityMapping) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn with_prefixed_span_fields(mut self, prefix: bool) -> Self {
+        self.prefix_span_fields = prefix;
+        self
+    }
+
+    pub fn with_exception_events(mut self, enabled: bool) -> Self {
+        self.exception_events = enabled;
+        self
+    }
+
+    pub fn with_error_status(mut self, enabled: bool) -> Self {
+        self.error_status = enabled;
+        self
+    }
+}
+
+/// The fields recorded on a span, kept as a span extension when span fields are copied onto log
+/// records.
+struct RecordedFields(Vec<(&'static str, AnyValue)>);
+
+impl Visit for RecordedFields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.push(field, value.into()),
+            Err(_) => self.push(field, value.to_string().into()),
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_owned().into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format!("{:?}", value).into());
+    }
+}
+
+impl RecordedFields {
+    fn push(&mut self, field: &Field, value: AnyValue) {
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some((_, existing)) => *existing = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+}
+
+/// Emits a log record for every `tracing` event, replacing `OpenTelemetryTracingBridge` so that
+/// span fields, severities and the trace context can be controlled.
+pub struct LogBridgeLayer {
+    logger: Logger,
+    config: BridgeConfig,
+}
+
+impl LogBridgeLayer {
+    pub fn new(provider: &LoggerProvider, config: BridgeConfig) -> Self {
+        Self {
+            logger: provider.logger("simple-observability-pipeline"),
+            config,
+        }
+    }
+}
+
+/// Collects the fields of an event into a log record.
+struct EventVisitor<'a> {
+    record: &'a mut opentelemetry_sdk::logs::LogRecord,
+}
+
+impl Visit for EventVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record.add_attribute(Key::new(field.name()), value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.record.add_attribute(Key::new(field.name()), value),
+            Err(_) => self
+                .record
+                .add_attribute(Key::new(field.name()), value.to_string()),
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record.add_attribute(Key::new(field.name()), value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record.add_attribute(Key::new(field.name()), value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.record.set_body(value.to_owned().into()),
+            name => self.record.add_attribute(Key::new(name), value.to_owned()),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.record.set_body(format!("{:?}", value).into()),
+            name => self
+                .record
+                .add_attribute(Key::new(name), format!("{:?}", value)),
+        }
+    }
+}
+
+impl<S> Layer<S> for LogBridgeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if self.config.span_fields.is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = RecordedFields(Vec::new());
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if self.config.span_fields.is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<RecordedFields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let severity = self.config.severity.severity(metadata.level());
+
+        let mut record = self.logger.create_log_record();
+        record.set_target(metadata.target().to_string());
+        record.set_event_name(metadata.name());
+        record.set_severity_number(severity);
+        record.set_severity_text(Cow::Borrowed(severity.name()));
+        record.set_observed_timestamp(SystemTime::now());
+
+        if let Some(scope) = ctx.event_scope(event) {
+            // Copy the fields from the root span down, so that inner spans win on conflicts.
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+
+                if !self.config.span_fields.is_none() {
+                    if let Some(fields) = extensions.get::<RecordedFields>() {
+                        for (name, value) in &fields.0 {
+                            if !self.config.span_fields.allows(name) {
+                                continue;
+                            }
+                            let key = match self.config.prefix_span_fields {
+                                true => Key::from(format!("{}.{}", span.name(), name)),
+                                false => Key::from_static_str(name),
+                            };
+                            record.add_attribute(key, value.clone());
+                        }
+                    }
+                }
+
+                // The innermost span ends up providing the trace context.
+                if let Some(otel_data) = extensions.get::<OtelData>() {
+                    let parent_span = otel_data.parent_cx.span();
+                    let parent = parent_span.span_context();
+                    let trace_id = otel_data.builder.trace_id.unwrap_or(parent.trace_id());
+                    if let Some(span_id) = otel_data.builder.span_id {
+                        record.trace_context = Some(TraceContext::from(&SpanContext::new(
+                            trace_id,
+                            span_id,
+                            parent.trace_flags(),
+                            false,
+                            TraceState::default(),
+                        )));
+                    }
+                }
+            }
+        }
+
+        event.record(&mut EventVisitor {
+            record: &mut record,
+        });
+
+        self.logger.emit(record);
+    }
+}
+
+/// The error carried by an `error!` event.
+#[derive(Default)]
+struct ErrorVisitor {
+    has_message: bool,
+    exception_type: Option<String>,
+    exception_message: Option<String>,
+}
+
+impl Visit for ErrorVisitor {
+    fn record_error(&mut self, _field: &Field, value: &(dyn Error + 'static)) {
+        self.exception_type = Some(error_type_name(value));
+        self.exception_message = Some(value.to_string());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.has_message = true,
+            "error" if self.exception_message.is_none() => {
+                self.exception_message = Some(value.to_string())
+            }
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.has_message = true,
+            "error" if self.exception_message.is_none() => {
+                self.exception_message = Some(format!("{:?}", value))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The type of a `dyn Error` isn't available at runtime, so it's taken from the start of its
+/// `Debug` output, eg. `ParseIntError` for `ParseIntError { kind: InvalidDigit }`.
+fn error_type_name(error: &(dyn Error + 'static)) -> String {
+    let debug = format!("{:?}", error);
+    let name: String = debug
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_' || *c == ':')
+        .collect();
+    match name.is_empty() {
+        true => "Error".to_string(),
+        false => name,
+    }
+}
+
+/// Records `error!` events that carry an error value as exception events on the current span.
+///
+/// `tracing-opentelemetry` already maps the events of `#[instrument(err)]`, which have no message.
+/// This layer handles the events that do, and must be added after the `OpenTelemetryLayer` so that
+/// its span status wins.
+pub struct ExceptionLayer {
+    config: BridgeConfig,
+}
+
+impl ExceptionLayer {
+    pub fn new(config: BridgeConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for ExceptionLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+
+        let mut visitor = ErrorVisitor::default();
+        event.record(&mut visitor);
+        let (true, Some(message)) = (visitor.has_message, visitor.exception_message) else {
+            return;
+        };
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+
+        let mut extensions = span.extensions_mut();
+        let Some(otel_data) = extensions.get_mut::<OtelData>() else {
+            return;
+        };
+
+        if self.config.exception_events {
+            let mut attributes = vec![KeyValue::new(EXCEPTION_MESSAGE, message.clone())];
+            if let Some(exception_type) = visitor.exception_type {
+                attributes.push(KeyValue::new(EXCEPTION_TYPE, exception_type));
+            }
+            otel_data
+                .builder
+                .events
+                .get_or_insert_with(Vec::new)
+                .push(SpanEvent::new(
+                    "exception",
+                    SystemTime::now(),
+                    attributes,
+                    0,
+                ));
+        }
+
+        if self.config.error_status {
+            otel_data.builder.status = Status::error(message);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use opentelemetry_otlp::Compression;
+
+use crate::bridge::BridgeConfig;
+use crate::connector::DisconnectedPolicy;
+use crate::filter::FilterConfig;
+use crate::interceptor::{InterceptorChain, MetadataInterceptor};
+use crate::metrics::MetricsConfig;
+use crate::sampling::SamplerConfig;
+
+pub const DEFAULT_SOCK: &str = "/tmp/proxy-server.sock";
+pub const DEFAULT_GRPC_ENDPOINT: &str = "http://localhost:4317";
+pub const DEFAULT_HTTP_ENDPOINT: &str = "http://localhost:4318";
+
+// Standard OTLP exporter environment variables.
+// See https://opentelemetry.io/docs/specs/otel/protocol/exporter/
+pub const OTEL_EXPORTER_OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+pub const OTEL_EXPORTER_OTLP_PROTOCOL: &str = "OTEL_EXPORTER_OTLP_PROTOCOL";
+pub const OTEL_EXPORTER_OTLP_TIMEOUT: &str = "OTEL_EXPORTER_OTLP_TIMEOUT";
+pub const OTEL_EXPORTER_OTLP_COMPRESSION: &str = "OTEL_EXPORTER_OTLP_COMPRESSION";
+pub const OTEL_EXPORTER_OTLP_HEADERS: &str = "OTEL_EXPORTER_OTLP_HEADERS";
+
+/// Where the OTLP exporters send their data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportTarget {
+    /// gRPC over a Unix domain socket, eg. the local `proxy-server`.
+    Uds(PathBuf),
+    /// gRPC over TCP, eg. `http://localhost:4317`.
+    Grpc(String),
+    /// OTLP/HTTP, eg. `http://localhost:4318`. The per-signal paths (`/v1/traces` etc.) are
+    /// appended to the endpoint.
+    Http {
+        endpoint: String,
+        encoding: HttpEncoding,
+    },
+}
+
+/// Payload encoding used by OTLP/HTTP exporters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpEncoding {
+    #[default]
+    Protobuf,
+    Json,
+}
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackoffConfig {
+    /// Delay before the first retry.
+    pub initial: Duration,
+    /// Upper bound for the delay between retries.
+    pub max: Duration,
+    /// Factor applied to the delay after every failed attempt.
+    pub multiplier: u32,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+            multiplier: 2,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Returns the delay to wait before the given (zero-indexed) retry.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1).saturating_pow(attempt);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Configuration for the OTLP exporters created by this library.
+///
+/// Use [`ExporterConfig::from_env`] to read the standard `OTEL_EXPORTER_OTLP_*` variables, or one
+/// of the constructors together with the `with_*` methods to configure it in code.
+#[derive(Debug, Clone)]
+pub struct ExporterConfig {
+    pub target: ExportTarget,
+    /// Backoff between attempts to (re)connect to a UDS target.
+    pub connect_backoff: BackoffConfig,
+    /// What to do with telemetry while a UDS target is unreachable.
+    pub disconnected_policy: DisconnectedPolicy,
+    /// Timeout for each export request.
+    pub timeout: Duration,
+    pub compression: Option<Compression>,
+    /// Additional headers (gRPC metadata) sent with each export request.
+    pub headers: HashMap<String, String>,
+    /// Run on every gRPC export request, after the static headers have been added.
+    pub interceptors: InterceptorChain,
+}
+
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        Self::uds(DEFAULT_SOCK)
+    }
+}
+
+impl ExporterConfig {
+    fn new(target: ExportTarget) -> Self {
+        Self {
+            target,
+            connect_backoff: BackoffConfig::default(),
+            disconnected_policy: DisconnectedPolicy::default(),
+            timeout: Duration::from_secs(3),
+            compression: None,
+            headers: HashMap::new(),
+            interceptors: InterceptorChain::default(),
+        }
+    }
+
+    pub fn uds(path: impl Into<PathBuf>) -> Self {
+        Self::new(ExportTarget::Uds(path.into()))
+    }
+
+    pub fn grpc(endpoint: impl Into<String>) -> Self {
+        Self::new(ExportTarget::Grpc(endpoint.into()))
+    }
+
+    /// OTLP/HTTP with protobuf payloads.
+    pub fn http(endpoint: impl Into<String>) -> Self {
+        Self::new(ExportTarget::Http {
+            endpoint: endpoint.into(),
+            encoding: HttpEncoding::Protobuf,
+        })
+    }
+
+    /// OTLP/HTTP with JSON payloads.
+    pub fn http_json(endpoint: impl Into<String>) -> Self {
+        Self::new(ExportTarget::Http {
+            endpoint: endpoint.into(),
+            encoding: HttpEncoding::Json,
+        })
+    }
+
+    /// Reads the configuration from the `OTEL_EXPORTER_OTLP_*` environment variables, falling
+    /// back to the local proxy socket for anything that isn't set.
+    pub fn from_env() -> Result<Self> {
+        Self::default().merge_env()
+    }
+
+    /// Overrides the fields of this configuration with any `OTEL_EXPORTER_OTLP_*` environment
+    /// variables that are set.
+    ///
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT` accepts `unix://` URIs to select a UDS target.
+    pub fn merge_env(mut self) -> Result<Self> {
+        let protocol = env_var(OTEL_EXPORTER_OTLP_PROTOCOL);
+        let endpoint = env_var(OTEL_EXPORTER_OTLP_ENDPOINT);
+
+        if protocol.is_some() || endpoint.is_some() {
+            self.target = parse_target(protocol.as_deref(), endpoint.as_deref(), &self.target)?;
+        }
+
+        if let Some(timeout) = env_var(OTEL_EXPORTER_OTLP_TIMEOUT) {
+            // The spec defines the timeout in milliseconds.
+            let millis = timeout
+                .parse::<u64>()
+                .map_err(|e| anyhow!("Invalid {}: {}", OTEL_EXPORTER_OTLP_TIMEOUT, e))?;
+            self.timeout = Duration::from_millis(millis);
+        }
+
+        if let Some(compression) = env_var(OTEL_EXPORTER_OTLP_COMPRESSION) {
+            self.compression =
+                match compression.as_str() {
+                    "none" => None,
+                    other => Some(Compression::from_str(other).map_err(|e| {
+                        anyhow!("Invalid {}: {}", OTEL_EXPORTER_OTLP_COMPRESSION, e)
+                    })?),
+                };
+        }
+
+        if let Some(headers) = env_var(OTEL_EXPORTER_OTLP_HEADERS) {
+            self.headers.extend(parse_headers(&headers));
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_disconnected_policy(mut self, disconnected_policy: DisconnectedPolicy) -> Self {
+        self.disconnected_policy = disconnected_policy;
+        self
+    }
+
+    pub fn with_connect_backoff(mut self, connect_backoff: BackoffConfig) -> Self {
+        self.connect_backoff = connect_backoff;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn with_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+
+    /// Appends an interceptor to the chain. Only supported by the gRPC targets.
+    pub fn with_interceptor(mut self, interceptor: impl MetadataInterceptor + 'static) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Appends all the interceptors of `chain`, eg. [`InterceptorChain::logging_service`].
+    pub fn with_interceptors(mut self, chain: InterceptorChain) -> Self {
+        self.interceptors.extend(chain);
+        self
+    }
+}
+
+pub(crate) fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.trim().is_empty())
+}
+
+pub(crate) fn parse_target(
+    protocol: Option<&str>,
+    endpoint: Option<&str>,
+    current: &ExportTarget,
+) -> Result<ExportTarget> {
+    if let Some(path) = endpoint.and_then(|e| e.strip_prefix("unix://")) {
+        return Ok(ExportTarget::Uds(PathBuf::from(path)));
+    }
+
+    let protocol = match protocol {
+        Some(protocol) => protocol,
+        // Keep the current transport if only the endpoint was overridden.
+        None => match current {
+            ExportTarget::Http {
+                encoding: HttpEncoding::Protobuf,
+                ..
+            } => "http/protobuf",
+            ExportTarget::Http {
+                encoding: HttpEncoding::Json,
+                ..
+            } => "http/json",
+            _ => "grpc",
+        },
+    };
+
+    match protocol {
+        "grpc" => Ok(ExportTarget::Grpc(
+            endpoint.unwrap_or(DEFAULT_GRPC_ENDPOINT).to_string(),
+        )),
+        "http/protobuf" => Ok(ExportTarget::Http {
+            endpoint: endpoint.unwrap_or(DEFAULT_HTTP_ENDPOINT).to_string(),
+            encoding: HttpEncoding::Protobuf,
+        }),
+        "http/json" => Ok(ExportTarget::Http {
+            endpoint: endpoint.unwrap_or(DEFAULT_HTTP_ENDPOINT).to_string(),
+            encoding: HttpEncoding::Json,
+        }),
+        other => bail!("Unsupported {}: {}", OTEL_EXPORTER_OTLP_PROTOCOL, other),
+    }
+}
+
+/// Parses headers in the `key1=value1,key2=value2` format used by `OTEL_EXPORTER_OTLP_HEADERS`.
+fn parse_headers(value: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    value.split(',').filter_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() || value.is_empty() {
+            return None;
+        }
+        Some((key.to_string(), value.replace("%20", " ")))
+    })
+}
+
+/// Everything [`crate::create_providers`] needs besides the resource.
+#[derive(Debug, Clone, Default)]
+pub struct ProvidersConfig {
+    pub exporter: ExporterConfig,
+    pub filters: FilterConfig,
+    pub sampler: SamplerConfig,
+    pub metrics: MetricsConfig,
+    pub bridge: BridgeConfig,
+}
+
+impl ProvidersConfig {
+    pub fn new(exporter: ExporterConfig) -> Self {
+        Self {
+            exporter,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_filters(mut self, filters: FilterConfig) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    pub fn with_sampler(mut self, sampler: SamplerConfig) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: MetricsConfig) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_bridge(mut self, bridge: BridgeConfig) -> Self {
+        self.bridge = bridge;
+        self
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use hyper_util::rt::TokioIo;
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+use tokio::sync::watch;
+use tonic::transport::Uri;
+use tower::Service;
+
+use crate::config::BackoffConfig;
+
+/// Connectors are shared per socket path so that every exporter talking to the same socket
+/// observes the same connection state and backoff.
+static UDS_CONNECTORS: Lazy<Mutex<HashMap<PathBuf, UdsConnector>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The state of the connection to a UDS export target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// No connection has been attempted yet.
+    Idle,
+    Connected,
+    /// The socket could not be reached, or the connection was dropped.
+    Disconnected {
+        consecutive_failures: u32,
+    },
+}
+
+/// What happens to telemetry that is exported while the socket is unreachable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisconnectedPolicy {
+    /// Fail the export straight away while waiting for the next reconnect attempt. The SDK drops
+    /// the batch that was being exported.
+    #[default]
+    Drop,
+    /// Hold the export until the socket is reachable again. New telemetry stays in the SDK batch
+    /// queues until they are full, and the export is still bounded by the batch export timeout.
+    Buffer,
+}
+
+/// A tonic connector for Unix domain sockets that connects lazily, on the first export, and
+/// reconnects with exponential backoff whenever the connection is lost.
+#[derive(Clone)]
+pub struct UdsConnector {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    backoff: BackoffConfig,
+    policy: DisconnectedPolicy,
+    state: watch::Sender<ConnectionState>,
+    retry: Mutex<RetryState>,
+    open_connections: AtomicUsize,
+}
+
+#[derive(Default)]
+struct RetryState {
+    consecutive_failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl UdsConnector {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        backoff: BackoffConfig,
+        policy: DisconnectedPolicy,
+    ) -> Self {
+        let (state, _) = watch::channel(ConnectionState::Idle);
+        Self {
+            inner: Arc::new(Inner {
+                path: path.into(),
+                backoff,
+                policy,
+                state,
+                retry: Mutex::new(RetryState::default()),
+                open_connections: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the connector for `path`, creating it if this is the first exporter to use it.
+    ///
+    /// `backoff` and `policy` are only applied when the connector is created.
+    pub fn shared(path: &Path, backoff: BackoffConfig, policy: DisconnectedPolicy) -> Self {
+        UDS_CONNECTORS
+            .lock()
+            .expect("UDS connector registry poisoned")
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Self::new(path, backoff, policy))
+            .clone()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state.borrow().clone()
+    }
+
+    /// Subscribes to changes of the connection state.
+    pub fn watch(&self) -> watch::Receiver<ConnectionState> {
+        self.inner.state.subscribe()
+    }
+
+    async fn connect(self) -> io::Result<TokioIo<MonitoredStream>> {
+        loop {
+            if let Some(wait) = self.backoff_remaining() {
+                match self.inner.policy {
+                    DisconnectedPolicy::Drop => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            format!(
+                                "UDS socket {} is unreachable, retrying in {:?}",
+                                self.inner.path.display(),
+                                wait
+                            ),
+                        ))
+                    }
+                    DisconnectedPolicy::Buffer => tokio::time::sleep(wait).await,
+                }
+            }
+
+            match UnixStream::connect(&self.inner.path).await {
+                Ok(stream) => {
+                    self.on_connected();
+                    return Ok(TokioIo::new(MonitoredStream {
+                        stream,
+                        connector: self.clone(),
+                    }));
+                }
+                Err(e) => {
+                    self.on_connect_failed();
+                    if self.inner.policy == DisconnectedPolicy::Drop {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn backoff_remaining(&self) -> Option<std::time::Duration> {
+        let retry = self.inner.retry.lock().expect("retry state poisoned");
+        retry
+            .next_attempt
+            .and_then(|at| at.checked_duration_since(Instant::now()))
+    }
+
+    fn on_connected(&self) {
+        *self.inner.retry.lock().expect("retry state poisoned") = RetryState::default();
+        self.inner.open_connections.fetch_add(1, Ordering::SeqCst);
+        self.inner.state.send_replace(ConnectionState::Connected);
+    }
+
+    fn on_connect_failed(&self) {
+        let mut retry = self.inner.retry.lock().expect("retry state poisoned");
+        let delay = self.inner.backoff.delay(retry.consecutive_failures);
+        retry.consecutive_failures += 1;
+        retry.next_attempt = Some(Instant::now() + delay);
+
+        if self.inner.open_connections.load(Ordering::SeqCst) == 0 {
+            self.inner
+                .state
+                .send_replace(ConnectionState::Disconnected {
+                    consecutive_failures: retry.consecutive_failures,
+                });
+        }
+    }
+
+    fn on_connection_closed(&self) {
+        if self.inner.open_connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner
+                .state
+                .send_replace(ConnectionState::Disconnected {
+                    consecutive_failures: 0,
+                });
+        }
+    }
+}
+
+impl Service<Uri> for UdsConnector {
+    type Response = TokioIo<MonitoredStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Tonic will ignore this uri because uds do not use it.
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect())
+    }
+}
+
+/// A connected socket that reports back to its connector when it is closed.
+pub struct MonitoredStream {
+    stream: UnixStream,
+    connector: UdsConnector,
+}
+
+impl Drop for MonitoredStream {
+    fn drop(&mut self) {
+        self.connector.on_connection_closed();
+    }
+}
+
+impl AsyncRead for MonitoredStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MonitoredStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Returns the state of the connection to the UDS socket at `path`, if any exporter uses it.
+pub fn uds_connection_state(path: &Path) -> Option<ConnectionState> {
+    UDS_CONNECTORS
+        .lock()
+        .expect("UDS connector registry poisoned")
+        .get(path)
+        .map(UdsConnector::state)
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use opentelemetry_otlp::{
+    ExportConfig, HttpExporterBuilder, LogExporterBuilder, MetricsExporterBuilder, Protocol,
+    SpanExporterBuilder, TonicExporterBuilder, WithExportConfig,
+};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+
+use crate::config::{ExportTarget, ExporterConfig, HttpEncoding};
+use crate::connector::UdsConnector;
+
+/// An OTLP exporter builder for any of the supported transports.
+///
+/// This can be passed to [`crate::init_tracer_provider`], [`crate::init_metrics`] and
+/// [`crate::init_logs`] in place of a [`TonicExporterBuilder`].
+#[derive(Debug)]
+pub enum OtlpExporterBuilder {
+    /// gRPC, either over TCP or a Unix domain socket.
+    Tonic(TonicExporterBuilder),
+    /// OTLP/HTTP. `endpoint` is the base URL that the per-signal paths are appended to.
+    Http {
+        builder: HttpExporterBuilder,
+        endpoint: String,
+    },
+}
+
+impl OtlpExporterBuilder {
+    fn http_with_path(
+        builder: HttpExporterBuilder,
+        endpoint: &str,
+        path: &str,
+    ) -> HttpExporterBuilder {
+        builder.with_endpoint(format!("{}{}", endpoint.trim_end_matches('/'), path))
+    }
+}
+
+impl From<TonicExporterBuilder> for OtlpExporterBuilder {
+    fn from(builder: TonicExporterBuilder) -> Self {
+        OtlpExporterBuilder::Tonic(builder)
+    }
+}
+
+impl From<OtlpExporterBuilder> for SpanExporterBuilder {
+    fn from(builder: OtlpExporterBuilder) -> Self {
+        match builder {
+            OtlpExporterBuilder::Tonic(builder) => builder.into(),
+            OtlpExporterBuilder::Http { builder, endpoint } => {
+                OtlpExporterBuilder::http_with_path(builder, &endpoint, "/v1/traces").into()
+            }
+        }
+    }
+}
+
+impl From<OtlpExporterBuilder> for MetricsExporterBuilder {
+    fn from(builder: OtlpExporterBuilder) -> Self {
+        match builder {
+            OtlpExporterBuilder::Tonic(builder) => builder.into(),
+            OtlpExporterBuilder::Http { builder, endpoint } => {
+                OtlpExporterBuilder::http_with_path(builder, &endpoint, "/v1/metrics").into()
+            }
+        }
+    }
+}
+
+impl From<OtlpExporterBuilder> for LogExporterBuilder {
+    fn from(builder: OtlpExporterBuilder) -> Self {
+        match builder {
+            OtlpExporterBuilder::Tonic(builder) => builder.into(),
+            OtlpExporterBuilder::Http { builder, endpoint } => {
+                OtlpExporterBuilder::http_with_path(builder, &endpoint, "/v1/logs").into()
+            }
+        }
+    }
+}
+
+/// Creates an exporter builder for whichever transport `config` selects.
+///
+/// Every call opens a new gRPC channel. To share one channel between the exporters of all three
+/// signals, create it once with [`init_channel`] and use [`init_exporter_builder_with_channel`].
+/// gRPC targets must be set up from a Tokio runtime.
+pub fn init_exporter_builder(config: &ExporterConfig) -> Result<OtlpExporterBuilder> {
+    let channel = match &config.target {
+        ExportTarget::Http { .. } => None,
+        _ => Some(init_channel(config)?),
+    };
+
+    init_exporter_builder_with_channel(config, channel)
+}
+
+/// Creates an exporter builder that sends over `channel`. `channel` is ignored for OTLP/HTTP
+/// targets and required for the gRPC ones.
+pub fn init_exporter_builder_with_channel(
+    config: &ExporterConfig,
+    channel: Option<Channel>,
+) -> Result<OtlpExporterBuilder> {
+    match (&config.target, channel) {
+        (ExportTarget::Http { endpoint, .. }, _) => Ok(OtlpExporterBuilder::Http {
+            builder: init_http_exporter_builder(config)?,
+            endpoint: endpoint.clone(),
+        }),
+        (_, Some(channel)) => Ok(tonic_exporter_builder_with_channel(config, channel)?.into()),
+        (target, None) => bail!("A gRPC channel is required to export to {:?}", target),
+    }
+}
+
+/// Creates an OTLP/HTTP exporter builder. The endpoint is set per signal by
+/// [`OtlpExporterBuilder`], so prefer [`init_exporter_builder`] unless you need the raw builder.
+pub fn init_http_exporter_builder(config: &ExporterConfig) -> Result<HttpExporterBuilder> {
+    let ExportTarget::Http { encoding, .. } = &config.target else {
+        bail!("Cannot build an OTLP/HTTP exporter for {:?}", config.target);
+    };
+
+    if let Some(compression) = config.compression {
+        bail!(
+            "Compression ({}) is not supported by the OTLP/HTTP exporter",
+            compression
+        );
+    }
+
+    if !config.interceptors.is_empty() {
+        bail!("Interceptors are not supported by the OTLP/HTTP exporter, use headers instead");
+    }
+
+    let protocol = match encoding {
+        HttpEncoding::Protobuf => Protocol::HttpBinary,
+        HttpEncoding::Json => Protocol::HttpJson,
+    };
+
+    Ok(opentelemetry_otlp::new_exporter()
+        .http()
+        .with_protocol(protocol)
+        .with_timeout(config.timeout)
+        .with_headers(config.headers.clone()))
+}
+
+/// Creates the gRPC channel for a UDS or TCP target. Both connect lazily, on the first export.
+///
+/// The channel is cheap to clone, and all clones share the same connection.
+pub fn init_channel(config: &ExporterConfig) -> Result<Channel> {
+    match &config.target {
+        ExportTarget::Uds(path) => {
+            let connector = UdsConnector::shared(
+                path,
+                config.connect_backoff.clone(),
+                config.disconnected_policy,
+            );
+
+            // The URI is ignored by the connector, but tonic requires one. Connecting lazily means
+            // the socket doesn't have to exist yet when the exporters are created.
+            Ok(Endpoint::try_from("http://127.0.0.1:4371")?
+                .timeout(config.timeout)
+                .connect_with_connector_lazy(connector))
+        }
+        ExportTarget::Grpc(endpoint) => Ok(Endpoint::from_shared(endpoint.clone())?
+            .timeout(config.timeout)
+            .connect_lazy()),
+        ExportTarget::Http { endpoint, .. } => {
+            bail!(
+                "Cannot build a gRPC channel for the OTLP/HTTP endpoint {}",
+                endpoint
+            )
+        }
+    }
+}
+
+/// Creates a gRPC exporter builder. The interceptors in `config` run, in order, on every export
+/// request. Must be called from a Tokio runtime.
+pub fn init_tonic_exporter_builder(config: &ExporterConfig) -> Result<TonicExporterBuilder> {
+    tonic_exporter_builder_with_channel(config, init_channel(config)?)
+}
+
+fn tonic_exporter_builder_with_channel(
+    config: &ExporterConfig,
+    channel: Channel,
+) -> Result<TonicExporterBuilder> {
+    let mut exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_channel(channel)
+        .with_export_config(ExportConfig {
+            endpoint: "".to_string(),
+            protocol: Protocol::Grpc,
+            timeout: config.timeout,
+        });
+
+    if let Some(compression) = config.compression {
+        exporter = exporter.with_compression(compression);
+    }
+
+    if !config.headers.is_empty() {
+        exporter = exporter.with_metadata(metadata_from_headers(&config.headers)?);
+    }
+
+    if !config.interceptors.is_empty() {
+        exporter = exporter.with_interceptor(config.interceptors.clone());
+    }
+
+    Ok(exporter)
+}
+
+pub(crate) fn metadata_from_headers(headers: &HashMap<String, String>) -> Result<MetadataMap> {
+    let mut metadata = MetadataMap::new();
+    for (key, value) in headers {
+        let key = MetadataKey::from_bytes(key.to_lowercase().as_bytes())?;
+        let value = MetadataValue::try_from(value.as_str())?;
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use tracing_subscriber::EnvFilter;
+
+/// Directives that restrict the crates used by the OTLP exporters to `error` level logs.
+///
+/// This ensures events generated from these crates within the OTLP Exporter are not looped back,
+/// thus preventing infinite event generation.
+/// Note: This will also drop events from these crates used outside the OTLP Exporter.
+/// For more details, see: https://github.com/open-telemetry/opentelemetry-rust/issues/761
+pub const DEFAULT_LOOP_PREVENTION_DIRECTIVES: &[&str] = &[
+    "hyper=error",
+    "tonic=error",
+    "tower=error",
+    "h2=error",
+    "reqwest=error",
+];
+
+/// Controls which `tracing` events and spans reach each layer of the subscriber built by
+/// [`crate::create_providers`].
+///
+/// Every layer is filtered independently: it uses its own directives if they are set, and the
+/// base directives otherwise. The loop-prevention directives are added to every filter.
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    /// Directives used by layers without their own, eg. `info` or `info,my_crate=debug`.
+    pub base: String,
+    /// Read the base directives from `RUST_LOG` when it is set.
+    pub use_rust_log: bool,
+    pub loop_prevention: Vec<String>,
+    /// Whether to print events to stdout.
+    pub console_enabled: bool,
+    pub console: Option<String>,
+    /// Spans exported as OTel traces.
+    pub traces: Option<String>,
+    /// Events exported as OTel log records.
+    pub logs: Option<String>,
+    /// Events recorded as OTel metrics by the `MetricsLayer`.
+    pub metrics: Option<String>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            base: "info".to_string(),
+            use_rust_log: true,
+            loop_prevention: DEFAULT_LOOP_PREVENTION_DIRECTIVES
+                .iter()
+                .map(|d| d.to_string())
+                .collect(),
+            console_enabled: true,
+            console: None,
+            traces: None,
+            logs: None,
+            metrics: None,
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn with_base(mut self, directives: impl Into<String>) -> Self {
+        self.base = directives.into();
+        self
+    }
+
+    pub fn with_rust_log(mut self, use_rust_log: bool) -> Self {
+        self.use_rust_log = use_rust_log;
+        self
+    }
+
+    /// Replaces the default loop-prevention directives. Pass an empty list to disable them.
+    pub fn with_loop_prevention<I, D>(mut self, directives: I) -> Self
+    where
+        I: IntoIterator<Item = D>,
+        D: Into<String>,
+    {
+        self.loop_prevention = directives.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_console(mut self, enabled: bool) -> Self {
+        self.console_enabled = enabled;
+        self
+    }
+
+    pub fn with_console_filter(mut self, directives: impl Into<String>) -> Self {
+        self.console = Some(directives.into());
+        self
+    }
+
+    pub fn with_traces_filter(mut self, directives: impl Into<String>) -> Self {
+        self.traces = Some(directives.into());
+        self
+    }
+
+    pub fn with_logs_filter(mut self, directives: impl Into<String>) -> Self {
+        self.logs = Some(directives.into());
+        self
+    }
+
+    pub fn with_metrics_filter(mut self, directives: impl Into<String>) -> Self {
+        self.metrics = Some(directives.into());
+        self
+    }
+
+    pub(crate) fn console_filter(&self) -> Result<EnvFilter> {
+        self.build(self.console.as_deref())
+    }
+
+    pub(crate) fn traces_filter(&self) -> Result<EnvFilter> {
+        self.build(self.traces.as_deref())
+    }
+
+    pub(crate) fn logs_filter(&self) -> Result<EnvFilter> {
+        self.build(self.logs.as_deref())
+    }
+
+    pub(crate) fn metrics_filter(&self) -> Result<EnvFilter> {
+        self.build(self.metrics.as_deref())
+    }
+
+    fn build(&self, directives: Option<&str>) -> Result<EnvFilter> {
+        let rust_log = match self.use_rust_log {
+            true => std::env::var(EnvFilter::DEFAULT_ENV).ok(),
+            false => None,
+        };
+        let directives = directives
+            .or(rust_log.as_deref())
+            .unwrap_or(self.base.as_str());
+
+        let mut filter = EnvFilter::try_new(directives)
+            .map_err(|e| anyhow!("Invalid filter directives {:?}: {}", directives, e))?;
+        for directive in &self.loop_prevention {
+            filter = filter.add_directive(
+                directive
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid filter directive {:?}: {}", directive, e))?,
+            );
+        }
+
+        Ok(filter)
+    }
+}
@@ -0,0 +1,223 @@
+// Interceptors fail with a `Status`, like tonic's own.
+#![allow(clippy::result_large_err)]
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// The header the proxy server uses to recognise its own telemetry.
+pub const ORIGIN_HEADER: &str = "x-origin";
+/// The origin tagged on the telemetry the proxy server exports about itself.
+pub const PROXY_SERVER_ORIGIN: &str = "proxy-server";
+
+/// Adds metadata to every export request sent by a gRPC exporter.
+///
+/// Returning an error fails the export.
+pub trait MetadataInterceptor: fmt::Debug + Send + Sync {
+    fn intercept(&self, metadata: &mut MetadataMap) -> Result<(), Status>;
+}
+
+/// The interceptors applied, in order, to every export request.
+#[derive(Debug, Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<Arc<dyn MetadataInterceptor>>,
+}
+
+impl InterceptorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The chain used by the proxy server for its own telemetry, which tags every request with
+    /// `x-origin: proxy-server` so that the server can tell it apart.
+    pub fn logging_service() -> Self {
+        Self::new().with(StaticHeader::origin(PROXY_SERVER_ORIGIN))
+    }
+
+    pub fn with(mut self, interceptor: impl MetadataInterceptor + 'static) -> Self {
+        self.push(interceptor);
+        self
+    }
+
+    pub fn push(&mut self, interceptor: impl MetadataInterceptor + 'static) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    /// Appends the interceptors of `other` to this chain.
+    pub fn extend(&mut self, other: InterceptorChain) {
+        self.interceptors.extend(other.interceptors);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+}
+
+impl Interceptor for InterceptorChain {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        for interceptor in &self.interceptors {
+            interceptor.intercept(request.metadata_mut())?;
+        }
+        Ok(request)
+    }
+}
+
+/// Sets a header to a fixed value.
+#[derive(Debug, Clone)]
+pub struct StaticHeader {
+    key: AsciiMetadataKey,
+    value: AsciiMetadataValue,
+}
+
+impl StaticHeader {
+    pub fn new(key: &str, value: &str) -> Result<Self> {
+        Ok(Self {
+            key: AsciiMetadataKey::from_bytes(key.to_lowercase().as_bytes())?,
+            value: AsciiMetadataValue::try_from(value)?,
+        })
+    }
+
+    /// Tags requests with an `x-origin` header.
+    pub fn origin(origin: &'static str) -> Self {
+        Self {
+            key: AsciiMetadataKey::from_static(ORIGIN_HEADER),
+            value: AsciiMetadataValue::from_static(origin),
+        }
+    }
+}
+
+impl MetadataInterceptor for StaticHeader {
+    fn intercept(&self, metadata: &mut MetadataMap) -> Result<(), Status> {
+        metadata.insert(self.key.clone(), self.value.clone());
+        Ok(())
+    }
+}
+
+/// Sets a header to the value of an environment variable, read on every request. Nothing is added
+/// while the variable is unset.
+#[derive(Debug, Clone)]
+pub struct EnvHeader {
+    key: AsciiMetadataKey,
+    var: String,
+}
+
+impl EnvHeader {
+    pub fn new(key: &str, var: impl Into<String>) -> Result<Self> {
+        Ok(Self {
+            key: AsciiMetadataKey::from_bytes(key.to_lowercase().as_bytes())?,
+            var: var.into(),
+        })
+    }
+}
+
+impl MetadataInterceptor for EnvHeader {
+    fn intercept(&self, metadata: &mut MetadataMap) -> Result<(), Status> {
+        if let Ok(value) = std::env::var(&self.var) {
+            let value = AsciiMetadataValue::try_from(value.trim()).map_err(|e| {
+                Status::invalid_argument(format!("Invalid value in {}: {}", self.var, e))
+            })?;
+            metadata.insert(self.key.clone(), value);
+        }
+        Ok(())
+    }
+}
+
+/// Where a [`BearerToken`] reads its token from.
+#[derive(Debug)]
+pub enum TokenSource {
+    Static(String),
+    /// Read from this environment variable on every request.
+    Env(String),
+    /// Read from this file, and read again once `refresh_interval` has passed, eg. for tokens
+    /// that are rotated by a sidecar.
+    File {
+        path: PathBuf,
+        refresh_interval: Duration,
+    },
+}
+
+/// Sets `authorization: Bearer <token>`.
+#[derive(Debug)]
+pub struct BearerToken {
+    source: TokenSource,
+    cached: Mutex<Option<(Instant, AsciiMetadataValue)>>,
+}
+
+impl BearerToken {
+    pub fn new(source: TokenSource) -> Self {
+        Self {
+            source,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn from_env(var: impl Into<String>) -> Self {
+        Self::new(TokenSource::Env(var.into()))
+    }
+
+    pub fn from_file(path: impl Into<PathBuf>, refresh_interval: Duration) -> Self {
+        Self::new(TokenSource::File {
+            path: path.into(),
+            refresh_interval,
+        })
+    }
+
+    fn header_value(token: &str) -> Result<AsciiMetadataValue, Status> {
+        AsciiMetadataValue::try_from(format!("Bearer {}", token.trim()))
+            .map_err(|e| Status::unauthenticated(format!("Invalid bearer token: {}", e)))
+    }
+
+    fn token(&self) -> Result<Option<AsciiMetadataValue>, Status> {
+        match &self.source {
+            TokenSource::Static(token) => Self::header_value(token).map(Some),
+            TokenSource::Env(var) => match std::env::var(var) {
+                Ok(token) => Self::header_value(&token).map(Some),
+                Err(_) => Ok(None),
+            },
+            TokenSource::File {
+                path,
+                refresh_interval,
+            } => {
+                let mut cached = self.cached.lock().expect("token cache poisoned");
+                if let Some((read_at, value)) = cached.as_ref() {
+                    if read_at.elapsed() < *refresh_interval {
+                        return Ok(Some(value.clone()));
+                    }
+                }
+
+                match std::fs::read_to_string(path) {
+                    Ok(token) => {
+                        let value = Self::header_value(&token)?;
+                        *cached = Some((Instant::now(), value.clone()));
+                        Ok(Some(value))
+                    }
+                    // Keep using the last token if the file is briefly missing while it's being
+                    // replaced.
+                    Err(e) => match cached.as_ref() {
+                        Some((_, value)) => Ok(Some(value.clone())),
+                        None => Err(Status::unauthenticated(format!(
+                            "Failed to read token file {}: {}",
+                            path.display(),
+                            e
+                        ))),
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl MetadataInterceptor for BearerToken {
+    fn intercept(&self, metadata: &mut MetadataMap) -> Result<(), Status> {
+        if let Some(value) = self.token()? {
+            metadata.insert("authorization", value);
+        }
+        Ok(())
+    }
+}
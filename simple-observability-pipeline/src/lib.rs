@@ -1,183 +1,250 @@
-use std::time::Duration;
+use std::sync::Arc;
 
 use anyhow::Result;
-use hyper_util::rt::TokioIo;
 use opentelemetry::trace::{TraceError, TracerProvider};
 use opentelemetry::{logs::LogError, metrics::MetricsError};
-use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
-use opentelemetry_otlp::{ExportConfig, TonicExporterBuilder, WithExportConfig};
+use opentelemetry_otlp::{LogExporterBuilder, MetricsExporterBuilder, SpanExporterBuilder};
 use opentelemetry_sdk::logs::LoggerProvider;
-use opentelemetry_sdk::metrics::reader::{DefaultAggregationSelector, DefaultTemporalitySelector};
-use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
 use opentelemetry_sdk::{trace as sdktrace, Resource};
-use tokio::net::UnixStream;
-use tonic::metadata::MetadataValue;
-use tonic::transport::{Endpoint, Uri};
-use tonic::{Request, Status};
-use tower::service_fn;
-use tracing::Subscriber;
-use tracing_opentelemetry::{layer, MetricsLayer, OpenTelemetryLayer};
+use tracing_opentelemetry::{MetricsLayer, OpenTelemetryLayer};
 use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::{fmt, Layer};
 
 // Re-exports
 pub use opentelemetry;
 pub use opentelemetry_sdk;
 
-pub const DEFAULT_SOCK: &str = "/tmp/proxy-server.sock";
-
-/// If `exporting_from_logging_service` is true, the exporter will be configured to intercept
-/// requests to add additional headers (extensions) to the request.
-pub async fn init_tonic_exporter_builder(
-    use_proxy: bool,
-    exporting_from_logging_service: bool,
-) -> Result<TonicExporterBuilder> {
-    let mut exporter = if use_proxy {
-        // Tonic will ignore this uri because uds do not use it
-        // if the connector does use the uri it will be provided
-        // as the request to the `MakeConnection`.
-        let service_fn = service_fn(|_: Uri| async {
-            // Try connecting up to 5 times
-            for _ in 0..5 {
-                match UnixStream::connect(DEFAULT_SOCK).await {
-                    Ok(stream) => return Ok(TokioIo::new(stream)),
-                    Err(e) => {
-                        println!("Failed to connect to UDS socket: {}", e);
-
-                        // Wait for 1 seconds before retrying.
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                    }
-                }
-            }
-
-            Err(MetricsError::Other(
-                "Failed to connect to UDS socket".to_string(),
-            ))
-        });
-        
-        let channel = Endpoint::try_from("http://127.0.0.1:4371")
-            .map_err(|e| MetricsError::Other(e.to_string()))?
-            .connect_with_connector(service_fn)
-            .await
-            .map_err(|e| MetricsError::Other(e.to_string()))?;
-
-        // First, create a OTLP exporter builder. Configure it as you need.
-        opentelemetry_otlp::new_exporter()
-            .tonic()
-            .with_channel(channel)
-            .with_export_config(ExportConfig {
-                endpoint: "".to_string(),
-                protocol: opentelemetry_otlp::Protocol::Grpc,
-                timeout: Duration::from_secs(3),
-            })
-    } else {
-        opentelemetry_otlp::new_exporter().tonic()
-    };
+pub mod bridge;
+pub mod config;
+pub mod connector;
+pub mod exporter;
+pub mod filter;
+pub mod interceptor;
+pub mod metrics;
+pub mod providers;
+pub mod proxy;
+pub mod resource;
+pub mod sampling;
+pub mod telemetry;
+pub mod testing;
+
+pub use bridge::{BridgeConfig, ExceptionLayer, LogBridgeLayer, SeverityMapping, SpanFields};
+pub use config::{
+    BackoffConfig, ExportTarget, ExporterConfig, HttpEncoding, ProvidersConfig, DEFAULT_SOCK,
+};
+pub use connector::{uds_connection_state, ConnectionState, DisconnectedPolicy, UdsConnector};
+pub use exporter::{
+    init_channel, init_exporter_builder, init_exporter_builder_with_channel,
+    init_http_exporter_builder, init_tonic_exporter_builder, OtlpExporterBuilder,
+};
+pub use filter::FilterConfig;
+pub use interceptor::{
+    BearerToken, EnvHeader, InterceptorChain, MetadataInterceptor, StaticHeader, TokenSource,
+};
+pub use metrics::{MetricView, MetricsConfig, TemporalityPreference};
+pub use providers::{BoxedSubscriber, ObservabilityProviders};
+pub use resource::ResourceBuilder;
+pub use sampling::{ConfiguredSampler, RateLimitingSampler, SamplerConfig};
+pub use telemetry::{PipelineHealth, PipelineTelemetry, Signal, SignalHealth};
+pub use testing::{create_test_providers, TestTelemetry};
+
+use telemetry::InstrumentedExporter;
 
-    if exporting_from_logging_service {
-        exporter = exporter.with_interceptor(intercept);
-    }
+pub fn init_tracer_provider(
+    exporter_builder: impl Into<SpanExporterBuilder>,
+    resource: Resource,
+    sampler: &SamplerConfig,
+) -> Result<sdktrace::TracerProvider, TraceError> {
+    build_tracer_provider(exporter_builder, resource, sampler, None)
+}
 
-    Ok(exporter)
+pub fn init_metrics(
+    exporter_builder: impl Into<MetricsExporterBuilder>,
+    resource: Resource,
+    config: &MetricsConfig,
+) -> Result<SdkMeterProvider, MetricsError> {
+    build_meter_provider(exporter_builder, resource, config, None)
 }
 
-// An interceptor function.
-fn intercept(mut req: Request<()>) -> Result<Request<()>, Status> {
-    req.metadata_mut()
-        .insert("x-origin", MetadataValue::from_static("proxy-server"));
-    Ok(req)
+pub fn init_logs(
+    exporter_builder: impl Into<LogExporterBuilder>,
+    resource: Resource,
+) -> Result<LoggerProvider, LogError> {
+    build_logger_provider(exporter_builder, resource, None)
 }
 
-pub fn init_tracer_provider(
-    tonic_exporter_builder: TonicExporterBuilder,
+// The providers are assembled here rather than with the OTLP pipelines, so that the exporters can
+// be wrapped to record the pipeline's own telemetry.
+
+fn build_tracer_provider(
+    exporter_builder: impl Into<SpanExporterBuilder>,
     resource: Resource,
+    sampler: &SamplerConfig,
+    telemetry: Option<Arc<PipelineTelemetry>>,
 ) -> Result<sdktrace::TracerProvider, TraceError> {
-    opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(tonic_exporter_builder)
-        .with_trace_config(sdktrace::config().with_resource(resource))
-        .install_batch(opentelemetry_sdk::runtime::Tokio)
+    let exporter = exporter_builder.into().build_span_exporter()?;
+    let builder = sdktrace::TracerProvider::builder().with_config(
+        sdktrace::Config::default()
+            .with_resource(resource)
+            .with_sampler(sampler.build()),
+    );
+
+    let builder = match telemetry {
+        Some(telemetry) => builder.with_batch_exporter(
+            InstrumentedExporter::new(exporter, telemetry),
+            opentelemetry_sdk::runtime::Tokio,
+        ),
+        None => builder.with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio),
+    };
+
+    Ok(builder.build())
 }
 
-pub fn init_metrics(
-    tonic_exporter_builder: TonicExporterBuilder,
+fn build_meter_provider(
+    exporter_builder: impl Into<MetricsExporterBuilder>,
     resource: Resource,
-) -> Result<opentelemetry_sdk::metrics::SdkMeterProvider, MetricsError> {
-    opentelemetry_otlp::new_pipeline()
-        .metrics(opentelemetry_sdk::runtime::Tokio)
-        .with_exporter(tonic_exporter_builder)
-        .with_period(Duration::from_secs(3))
-        .with_timeout(Duration::from_secs(10))
-        .with_resource(resource)
-        .with_aggregation_selector(DefaultAggregationSelector::new())
-        .with_temporality_selector(DefaultTemporalitySelector::new())
-        .build()
+    config: &MetricsConfig,
+    telemetry: Option<Arc<PipelineTelemetry>>,
+) -> Result<SdkMeterProvider, MetricsError> {
+    let exporter = exporter_builder
+        .into()
+        .build_metrics_exporter(Box::new(config.temporality), config.aggregation_selector())?;
+
+    let reader = match telemetry {
+        Some(telemetry) => PeriodicReader::builder(
+            InstrumentedExporter::new(exporter, telemetry),
+            opentelemetry_sdk::runtime::Tokio,
+        )
+        .with_interval(config.export_interval)
+        .with_timeout(config.export_timeout)
+        .build(),
+        None => PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::Tokio)
+            .with_interval(config.export_interval)
+            .with_timeout(config.export_timeout)
+            .build(),
+    };
+
+    let mut builder = SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource);
+    for view in &config.views {
+        builder = builder.with_view(view.build()?);
+    }
+
+    Ok(builder.build())
 }
 
-pub fn init_logs(
-    tonic_exporter_builder: TonicExporterBuilder,
+fn build_logger_provider(
+    exporter_builder: impl Into<LogExporterBuilder>,
     resource: Resource,
-) -> Result<opentelemetry_sdk::logs::LoggerProvider, LogError> {
-    opentelemetry_otlp::new_pipeline()
-        .logging()
-        .with_resource(resource)
-        .with_exporter(tonic_exporter_builder)
-        .install_batch(opentelemetry_sdk::runtime::Tokio)
+    telemetry: Option<Arc<PipelineTelemetry>>,
+) -> Result<LoggerProvider, LogError> {
+    let exporter = exporter_builder.into().build_log_exporter()?;
+    let builder = LoggerProvider::builder().with_resource(resource);
+
+    let builder = match telemetry {
+        Some(telemetry) => builder.with_batch_exporter(
+            InstrumentedExporter::new(exporter, telemetry),
+            opentelemetry_sdk::runtime::Tokio,
+        ),
+        None => builder.with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio),
+    };
+
+    Ok(builder.build())
 }
 
+/// Creates the tracer, meter and logger providers, all exporting over a single connection, and
+/// the subscriber that bridges `tracing` to them.
+///
+/// Call [`ObservabilityProviders::install_globals`] on the result to start using them.
 pub async fn create_providers(
     resource: Resource,
-    use_proxy: bool,
-    exporting_from_logging_service: bool,
-) -> Result<(
-    opentelemetry_sdk::trace::TracerProvider,
-    SdkMeterProvider,
-    impl Subscriber,
-    LoggerProvider,
-)> {
+    config: &ProvidersConfig,
+) -> Result<ObservabilityProviders> {
+    let exporter_config = &config.exporter;
+
+    // One channel is shared by the exporters of all three signals.
+    let channel = match &exporter_config.target {
+        ExportTarget::Http { .. } => None,
+        _ => Some(init_channel(exporter_config)?),
+    };
+    let exporter_builder = || init_exporter_builder_with_channel(exporter_config, channel.clone());
+
+    // Every export is recorded, and published through the meter provider created below.
+    let telemetry = PipelineTelemetry::new();
+
     // Initialize the tracing pipeline
-    let tracing_provider = init_tracer_provider(
-        init_tonic_exporter_builder(use_proxy, exporting_from_logging_service).await?,
+    let tracing_provider = build_tracer_provider(
+        exporter_builder()?,
         resource.clone(),
+        &config.sampler,
+        Some(telemetry.clone()),
     )?;
-    let tracer = tracing_provider.tracer("basic-tracer");
 
     // Initialize the metrics pipeline
-    let meter_provider = init_metrics(
-        init_tonic_exporter_builder(use_proxy, exporting_from_logging_service).await?,
+    let meter_provider = build_meter_provider(
+        exporter_builder()?,
         resource.clone(),
+        &config.metrics,
+        Some(telemetry.clone()),
     )?;
+    telemetry.register(&meter_provider);
 
     // Initialize the logs pipeline
-    let logger_provider = init_logs(
-        init_tonic_exporter_builder(use_proxy, exporting_from_logging_service).await?,
+    let logger_provider = build_logger_provider(
+        exporter_builder()?,
         resource.clone(),
+        Some(telemetry.clone()),
     )?;
 
-    // Create a new OpenTelemetryTracingBridge using the above LoggerProvider.
-    let layer = OpenTelemetryTracingBridge::new(&logger_provider);
-
-    // Add a tracing filter to filter events from crates used by opentelemetry-otlp.
-    // The filter levels are set as follows:
-    // - Allow `info` level and above by default.
-    // - Restrict `hyper`, `tonic`, and `reqwest` to `error` level logs only.
-    // This ensures events generated from these crates within the OTLP Exporter are not looped back,
-    // thus preventing infinite event generation.
-    // Note: This will also drop events from these crates used outside the OTLP Exporter.
-    // For more details, see: https://github.com/open-telemetry/opentelemetry-rust/issues/761
-    let filter = EnvFilter::new("info")
-        .add_directive("hyper=error".parse().unwrap())
-        .add_directive("tonic=error".parse().unwrap())
-        .add_directive("tower=error".parse().unwrap())
-        .add_directive("h2=error".parse().unwrap())
-        .add_directive("reqwest=error".parse().unwrap());
+    build_providers(
+        tracing_provider,
+        meter_provider,
+        logger_provider,
+        config,
+        telemetry,
+    )
+}
 
-    let sub = tracing_subscriber::registry()
-        .with(filter)
-        .with(fmt::layer())
-        .with(layer)
-        .with(MetricsLayer::new(meter_provider.clone()))
-        .with(OpenTelemetryLayer::new(tracer));
+/// Creates the subscriber that bridges `tracing` to the providers, and wraps them all in a handle.
+pub(crate) fn build_providers(
+    tracing_provider: sdktrace::TracerProvider,
+    meter_provider: SdkMeterProvider,
+    logger_provider: LoggerProvider,
+    config: &ProvidersConfig,
+    telemetry: Arc<PipelineTelemetry>,
+) -> Result<ObservabilityProviders> {
+    let tracer = tracing_provider.tracer("basic-tracer");
+
+    // Events become log records, and error events also become exceptions on their spans. See
+    // `BridgeConfig` for how they are mapped.
+    let log_bridge = LogBridgeLayer::new(&logger_provider, config.bridge.clone());
+    let otel_layer = OpenTelemetryLayer::new(tracer)
+        .with_error_events_to_status(config.bridge.error_status)
+        .with_error_events_to_exceptions(config.bridge.exception_events);
+
+    // Each layer gets its own filter so that, eg. console output can be more verbose than the
+    // exported logs. See `FilterConfig` for the loop-prevention directives added to each of them.
+    let filters = &config.filters;
+    let console_layer = match filters.console_enabled {
+        true => Some(fmt::layer().with_filter(filters.console_filter()?)),
+        false => None,
+    };
 
-    Ok((tracing_provider, meter_provider, sub, logger_provider))
+    let sub = tracing_subscriber::registry()
+        .with(console_layer)
+        .with(log_bridge.with_filter(filters.logs_filter()?))
+        .with(MetricsLayer::new(meter_provider.clone()).with_filter(filters.metrics_filter()?))
+        .with(otel_layer.with_filter(filters.traces_filter()?))
+        .with(ExceptionLayer::new(config.bridge.clone()).with_filter(filters.traces_filter()?));
+
+    Ok(ObservabilityProviders::new(
+        tracing_provider,
+        meter_provider,
+        logger_provider,
+        Box::new(sub),
+        config.exporter.clone(),
+        telemetry,
+    ))
 }
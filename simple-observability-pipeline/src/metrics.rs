@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use opentelemetry::metrics::MetricsError;
+use opentelemetry::Key;
+use opentelemetry_sdk::metrics::data::Temporality;
+use opentelemetry_sdk::metrics::reader::{
+    AggregationSelector, DefaultAggregationSelector, TemporalitySelector,
+};
+use opentelemetry_sdk::metrics::{new_view, Aggregation, Instrument, InstrumentKind, Stream, View};
+
+pub const OTEL_METRIC_EXPORT_INTERVAL: &str = "OTEL_METRIC_EXPORT_INTERVAL";
+pub const OTEL_METRIC_EXPORT_TIMEOUT: &str = "OTEL_METRIC_EXPORT_TIMEOUT";
+pub const OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE: &str =
+    "OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE";
+
+/// Which temporality the exporter reports metrics with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TemporalityPreference {
+    #[default]
+    Cumulative,
+    /// Counters and histograms report the change since the last export. Up-down counters stay
+    /// cumulative, as recommended by the OTLP exporter spec.
+    Delta,
+}
+
+impl TemporalitySelector for TemporalityPreference {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        match (self, kind) {
+            (TemporalityPreference::Cumulative, _)
+            | (
+                TemporalityPreference::Delta,
+                InstrumentKind::UpDownCounter | InstrumentKind::ObservableUpDownCounter,
+            ) => Temporality::Cumulative,
+            (TemporalityPreference::Delta, _) => Temporality::Delta,
+        }
+    }
+}
+
+/// A view that changes how the matching instruments are exported.
+///
+/// Only the fields that are set are changed.
+#[derive(Debug, Clone, Default)]
+pub struct MetricView {
+    /// The instrument name to match. `*` matches any number of characters and `?` exactly one.
+    pub instrument_name: String,
+    pub rename: Option<String>,
+    pub description: Option<String>,
+    /// Only these attributes are kept, all others are dropped.
+    pub allowed_attribute_keys: Option<Vec<Key>>,
+    pub aggregation: Option<Aggregation>,
+}
+
+impl MetricView {
+    pub fn new(instrument_name: impl Into<String>) -> Self {
+        Self {
+            instrument_name: instrument_name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_rename(mut self, name: impl Into<String>) -> Self {
+        self.rename = Some(name.into());
+        self
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn with_allowed_attributes<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<Key>,
+    {
+        self.allowed_attribute_keys = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = Some(aggregation);
+        self
+    }
+
+    /// Aggregates the instrument as a histogram with these bucket boundaries. This also works for
+    /// counters.
+    pub fn with_histogram_buckets(self, boundaries: Vec<f64>) -> Self {
+        self.with_aggregation(Aggregation::ExplicitBucketHistogram {
+            boundaries,
+            record_min_max: true,
+        })
+    }
+
+    /// Stops exporting the instrument.
+    pub fn dropped(self) -> Self {
+        self.with_aggregation(Aggregation::Drop)
+    }
+
+    pub(crate) fn build(&self) -> Result<Box<dyn View>, MetricsError> {
+        let mut mask = Stream::new();
+        if let Some(name) = &self.rename {
+            mask = mask.name(name.clone());
+        }
+        if let Some(description) = &self.description {
+            mask = mask.description(description.clone());
+        }
+        if let Some(keys) = &self.allowed_attribute_keys {
+            mask = mask.allowed_attribute_keys(keys.iter().cloned());
+        }
+        if let Some(aggregation) = &self.aggregation {
+            mask = mask.aggregation(aggregation.clone());
+        }
+
+        new_view(Instrument::new().name(self.instrument_name.clone()), mask)
+    }
+}
+
+/// Configures the meter provider created by [`crate::init_metrics`].
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub export_interval: Duration,
+    pub export_timeout: Duration,
+    pub temporality: TemporalityPreference,
+    /// Bucket boundaries for every histogram without a view of its own. The SDK defaults are used
+    /// if this is `None`.
+    pub histogram_buckets: Option<Vec<f64>>,
+    pub views: Vec<MetricView>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            export_interval: Duration::from_secs(3),
+            export_timeout: Duration::from_secs(10),
+            temporality: TemporalityPreference::default(),
+            histogram_buckets: None,
+            views: Vec::new(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// Reads `OTEL_METRIC_EXPORT_INTERVAL`, `OTEL_METRIC_EXPORT_TIMEOUT` (both in milliseconds)
+    /// and `OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE` on top of the defaults.
+    pub fn from_env() -> Result<Self> {
+        Self::default().merge_env()
+    }
+
+    /// Overrides the fields of this config with the variables that are set.
+    pub fn merge_env(mut self) -> Result<Self> {
+        if let Some(interval) = env_millis(OTEL_METRIC_EXPORT_INTERVAL)? {
+            self.export_interval = interval;
+        }
+        if let Some(timeout) = env_millis(OTEL_METRIC_EXPORT_TIMEOUT)? {
+            self.export_timeout = timeout;
+        }
+        if let Ok(temporality) = std::env::var(OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE) {
+            self.temporality = match temporality.trim().to_lowercase().as_str() {
+                "cumulative" => TemporalityPreference::Cumulative,
+                "delta" => TemporalityPreference::Delta,
+                other => bail!(
+                    "Unsupported {}: {}",
+                    OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE,
+                    other
+                ),
+            };
+        }
+
+        Ok(self)
+    }
+
+    pub fn with_export_interval(mut self, interval: Duration) -> Self {
+        self.export_interval = interval;
+        self
+    }
+
+    pub fn with_export_timeout(mut self, timeout: Duration) -> Self {
+        self.export_timeout = timeout;
+        self
+    }
+
+    pub fn with_temporality(mut self, temporality: TemporalityPreference) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    pub fn with_histogram_buckets(mut self, boundaries: Vec<f64>) -> Self {
+        self.histogram_buckets = Some(boundaries);
+        self
+    }
+
+    pub fn with_view(mut self, view: MetricView) -> Self {
+        self.views.push(view);
+        self
+    }
+
+    pub(crate) fn aggregation_selector(&self) -> Box<dyn AggregationSelector> {
+        match &self.histogram_buckets {
+            Some(boundaries) => {
+                let boundaries = boundaries.clone();
+                Box::new(move |kind: InstrumentKind| match kind {
+                    InstrumentKind::Histogram => Aggregation::ExplicitBucketHistogram {
+                        boundaries: boundaries.clone(),
+                        record_min_max: true,
+                    },
+                    _ => DefaultAggregationSelector::new().aggregation(kind),
+                })
+            }
+            None => Box::new(DefaultAggregationSelector::new()),
+        }
+    }
+}
+
+fn env_millis(name: &str) -> Result<Option<Duration>> {
+    match std::env::var(name) {
+        Ok(value) => {
+            let millis: u64 = value
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("Invalid {}: {}", name, e))?;
+            Ok(Some(Duration::from_millis(millis)))
+        }
+        Err(_) => Ok(None),
+    }
+}
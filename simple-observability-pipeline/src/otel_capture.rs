@@ -0,0 +1,518 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use clap::{Args, Parser, Subcommand};
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::common::v1::AnyValue;
+use opentelemetry_proto::tonic::logs::v1::LogRecord;
+use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+use opentelemetry_proto::tonic::metrics::v1::{number_data_point, AggregationTemporality};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_proto::tonic::trace::v1::status::StatusCode;
+use opentelemetry_proto::tonic::trace::v1::Span;
+use simple_observability_pipeline::proxy::routing::SeverityLevel;
+use simple_observability_pipeline::proxy::{
+    read_capture_file, CaptureFilter, ExportRequest, RetryConfig, UpstreamClient, UpstreamConfig,
+};
+use simple_observability_pipeline::telemetry::Signal;
+
+/// Queries and replays the capture files written by proxy-server.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the spans of every trace as a tree, with their durations.
+    Traces(Query),
+    /// Print the log records, oldest first.
+    Logs(Query),
+    /// Summarize the data points of every metric.
+    Metrics(Query),
+    /// Send the exports to an OTLP endpoint, in the order they were captured.
+    Replay {
+        #[command(flatten)]
+        query: Query,
+        /// Accepts `unix://` URIs, like `OTEL_EXPORTER_OTLP_ENDPOINT`.
+        #[arg(long)]
+        endpoint: String,
+        /// `grpc` (the default), `http/protobuf` or `http/json`.
+        #[arg(long)]
+        protocol: Option<String>,
+        /// Sent with every export, as `KEY=VALUE`.
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+        #[arg(long, default_value_t = 10_000)]
+        timeout_ms: u64,
+    },
+}
+
+#[derive(Args)]
+struct Query {
+    /// Capture files, in either format, compressed or not, including rotated files.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Only data from resources with this `service.name`.
+    #[arg(long)]
+    service: Option<String>,
+    /// Only the spans and logs of this trace, in hex.
+    #[arg(long, value_parser = parse_trace_id)]
+    trace_id: Option<TraceId>,
+    /// Only data from this time on, in RFC 3339, eg. `2024-01-01T12:00:00Z`.
+    #[arg(long, value_parser = parse_time)]
+    since: Option<u64>,
+    /// Only data up to this time, in RFC 3339.
+    #[arg(long, value_parser = parse_time)]
+    until: Option<u64>,
+    /// Only logs at this severity or above: trace, debug, info, warn, error or fatal.
+    #[arg(long, value_parser = parse_severity)]
+    min_severity: Option<SeverityLevel>,
+}
+
+#[derive(Debug, Clone)]
+struct TraceId(Vec<u8>);
+
+impl Query {
+    /// Reads the files, and keeps what matches the filters, of `signal` if given.
+    fn read(&self, signal: Option<Signal>) -> Result<Vec<ExportRequest>> {
+        let filter = CaptureFilter {
+            service: self.service.clone(),
+            trace_id: self.trace_id.clone().map(|TraceId(id)| id),
+            since: self.since,
+            until: self.until,
+            min_severity: self.min_severity,
+        };
+
+        let mut requests = Vec::new();
+        for path in &self.files {
+            requests.extend(
+                read_capture_file(path)?
+                    .into_iter()
+                    .filter(|request| signal.is_none_or(|signal| request.signal() == signal))
+                    .filter_map(|mut request| filter.apply(&mut request).then_some(request)),
+            );
+        }
+        Ok(requests)
+    }
+}
+
+fn main() -> Result<()> {
+    let mut out = io::stdout().lock();
+    let printed = match Cli::parse().command {
+        Command::Traces(query) => print_traces(&mut out, &query.read(Some(Signal::Traces))?),
+        Command::Logs(query) => print_logs(&mut out, &query.read(Some(Signal::Logs))?),
+        Command::Metrics(query) => print_metrics(&mut out, &query.read(Some(Signal::Metrics))?),
+        Command::Replay {
+            query,
+            endpoint,
+            protocol,
+            headers,
+            timeout_ms,
+        } => {
+            let requests = query.read(None)?;
+            let config = UpstreamConfig {
+                name: "replay".to_string(),
+                endpoint,
+                protocol,
+                headers: headers.into_iter().collect(),
+                timeout_ms,
+                queue_size: 1,
+                retry: RetryConfig::default(),
+            };
+            return tokio::runtime::Runtime::new()?.block_on(replay(&config, &requests));
+        }
+    };
+
+    // Stop quietly when piped into eg. `head`.
+    match printed {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+struct TraceSpan<'a> {
+    service: &'a str,
+    span: &'a Span,
+}
+
+fn print_traces(out: &mut impl Write, requests: &[ExportRequest]) -> io::Result<()> {
+    // The same span is in the combined file and in the traces file, if both are given.
+    let mut seen = HashSet::new();
+    let mut traces: HashMap<&[u8], Vec<TraceSpan>> = HashMap::new();
+    for request in requests {
+        let ExportRequest::Traces(request) = request else {
+            continue;
+        };
+        for resource_spans in &request.resource_spans {
+            let service = service_name(&resource_spans.resource);
+            for span in resource_spans
+                .scope_spans
+                .iter()
+                .flat_map(|scope_spans| &scope_spans.spans)
+            {
+                if seen.insert((&span.trace_id, &span.span_id)) {
+                    traces
+                        .entry(&span.trace_id)
+                        .or_default()
+                        .push(TraceSpan { service, span });
+                }
+            }
+        }
+    }
+
+    let mut traces = traces.into_iter().collect::<Vec<_>>();
+    for (_, spans) in &mut traces {
+        spans.sort_by_key(|span| span.span.start_time_unix_nano);
+    }
+    traces.sort_by_key(|(_, spans)| spans[0].span.start_time_unix_nano);
+
+    for (trace_id, spans) in &traces {
+        let start = spans[0].span.start_time_unix_nano;
+        let end = spans
+            .iter()
+            .map(|span| span.span.end_time_unix_nano)
+            .max()
+            .unwrap_or(start);
+        writeln!(
+            out,
+            "Trace {} at {}, {} spans, {:?}",
+            hex(trace_id),
+            timestamp(start),
+            spans.len(),
+            duration(start, end)
+        )?;
+
+        // Spans whose parent wasn't captured are shown as roots.
+        let span_ids = spans
+            .iter()
+            .map(|span| span.span.span_id.as_slice())
+            .collect::<HashSet<_>>();
+        let mut children: HashMap<&[u8], Vec<&TraceSpan>> = HashMap::new();
+        let mut roots = Vec::new();
+        for span in spans {
+            match span_ids.contains(span.span.parent_span_id.as_slice()) {
+                true => children
+                    .entry(&span.span.parent_span_id)
+                    .or_default()
+                    .push(span),
+                false => roots.push(span),
+            }
+        }
+        for root in roots {
+            print_span(out, root, &children, 1)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_span(
+    out: &mut impl Write,
+    span: &TraceSpan,
+    children: &HashMap<&[u8], Vec<&TraceSpan>>,
+    depth: usize,
+) -> io::Result<()> {
+    let status = span
+        .span
+        .status
+        .as_ref()
+        .filter(|status| status.code == StatusCode::Error as i32)
+        .map_or(String::new(), |status| match status.message.as_str() {
+            "" => " ERROR".to_string(),
+            message => format!(" ERROR: {}", message),
+        });
+    writeln!(
+        out,
+        "{:indent$}{} [{}] {:?}{}",
+        "",
+        span.span.name,
+        span.service,
+        duration(span.span.start_time_unix_nano, span.span.end_time_unix_nano),
+        status,
+        indent = depth * 2
+    )?;
+
+    for child in children
+        .get(span.span.span_id.as_slice())
+        .into_iter()
+        .flatten()
+    {
+        print_span(out, child, children, depth + 1)?;
+    }
+    Ok(())
+}
+
+fn print_logs(out: &mut impl Write, requests: &[ExportRequest]) -> io::Result<()> {
+    let mut records: Vec<(&str, &LogRecord)> = Vec::new();
+    for request in requests {
+        let ExportRequest::Logs(request) = request else {
+            continue;
+        };
+        for resource_logs in &request.resource_logs {
+            let service = service_name(&resource_logs.resource);
+            records.extend(
+                resource_logs
+                    .scope_logs
+                    .iter()
+                    .flat_map(|scope_logs| &scope_logs.log_records)
+                    .map(|record| (service, record)),
+            );
+        }
+    }
+    records.sort_by_key(|(_, record)| log_time(record));
+
+    for (service, record) in records {
+        let mut line = format!(
+            "{} {:5} [{}] {}",
+            timestamp(log_time(record)),
+            severity(record),
+            service,
+            record.body.as_ref().map_or(String::new(), display_value)
+        );
+        if !record.trace_id.is_empty() {
+            line.push_str(&format!(" trace_id={}", hex(&record.trace_id)));
+        }
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// What the data points of one metric of one service add up to.
+#[derive(Default)]
+struct MetricSummary {
+    kind: &'static str,
+    unit: String,
+    points: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+    /// The value of the latest number data point, and when it was recorded.
+    last: Option<(u64, f64)>,
+    /// The sum of delta sums, or of the count of histograms and summaries.
+    total: f64,
+    /// The sum of the values recorded by histograms and summaries.
+    sum: f64,
+}
+
+impl MetricSummary {
+    fn add_number(&mut self, time: u64, value: f64) {
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        if self.last.is_none_or(|(last, _)| time >= last) {
+            self.last = Some((time, value));
+        }
+    }
+}
+
+impl std::fmt::Display for MetricSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if !self.unit.is_empty() {
+            write!(f, " {}", self.unit)?;
+        }
+        write!(f, ", {} points", self.points)?;
+        match self.kind {
+            "gauge" | "cumulative sum" => {
+                if let (Some(min), Some(max), Some((_, last))) = (self.min, self.max, self.last) {
+                    write!(f, ", min {}, max {}, last {}", min, max, last)?;
+                }
+                Ok(())
+            }
+            "delta sum" => write!(f, ", total {}", self.total),
+            _ => write!(f, ", count {}, sum {}", self.total, self.sum),
+        }
+    }
+}
+
+fn print_metrics(out: &mut impl Write, requests: &[ExportRequest]) -> io::Result<()> {
+    let mut summaries: BTreeMap<(&str, &str), MetricSummary> = BTreeMap::new();
+    for request in requests {
+        let ExportRequest::Metrics(request) = request else {
+            continue;
+        };
+        for resource_metrics in &request.resource_metrics {
+            let service = service_name(&resource_metrics.resource);
+            for metric in resource_metrics
+                .scope_metrics
+                .iter()
+                .flat_map(|scope_metrics| &scope_metrics.metrics)
+            {
+                let summary = summaries.entry((service, &metric.name)).or_default();
+                summary.unit.clone_from(&metric.unit);
+                match &metric.data {
+                    Some(Data::Gauge(gauge)) => {
+                        summary.kind = "gauge";
+                        summary.points += gauge.data_points.len();
+                        for point in &gauge.data_points {
+                            summary.add_number(point.time_unix_nano, number(&point.value));
+                        }
+                    }
+                    Some(Data::Sum(sum)) => {
+                        let delta =
+                            sum.aggregation_temporality == AggregationTemporality::Delta as i32;
+                        summary.kind = if delta { "delta sum" } else { "cumulative sum" };
+                        summary.points += sum.data_points.len();
+                        for point in &sum.data_points {
+                            let value = number(&point.value);
+                            summary.total += value;
+                            summary.add_number(point.time_unix_nano, value);
+                        }
+                    }
+                    Some(Data::Histogram(histogram)) => {
+                        summary.kind = "histogram";
+                        summary.points += histogram.data_points.len();
+                        for point in &histogram.data_points {
+                            summary.total += point.count as f64;
+                            summary.sum += point.sum.unwrap_or_default();
+                        }
+                    }
+                    Some(Data::ExponentialHistogram(histogram)) => {
+                        summary.kind = "exponential histogram";
+                        summary.points += histogram.data_points.len();
+                        for point in &histogram.data_points {
+                            summary.total += point.count as f64;
+                            summary.sum += point.sum.unwrap_or_default();
+                        }
+                    }
+                    Some(Data::Summary(data)) => {
+                        summary.kind = "summary";
+                        summary.points += data.data_points.len();
+                        for point in &data.data_points {
+                            summary.total += point.count as f64;
+                            summary.sum += point.sum;
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    for ((service, name), summary) in summaries {
+        writeln!(out, "{} [{}] {}", name, service, summary)?;
+    }
+    Ok(())
+}
+
+async fn replay(config: &UpstreamConfig, requests: &[ExportRequest]) -> Result<()> {
+    let mut client = UpstreamClient::connect(config)?;
+    let mut failed = 0;
+    for request in requests {
+        if let Err(e) = client.send(request).await {
+            eprintln!("Failed to replay {} export: {}", request.signal(), e);
+            failed += 1;
+        }
+    }
+
+    println!(
+        "Replayed {} of {} exports to {}",
+        requests.len() - failed,
+        requests.len(),
+        config.endpoint
+    );
+    if failed > 0 {
+        bail!("{} exports failed", failed);
+    }
+    Ok(())
+}
+
+fn service_name(resource: &Option<Resource>) -> &str {
+    resource
+        .iter()
+        .flat_map(|resource| &resource.attributes)
+        .find(|attribute| attribute.key == "service.name")
+        .and_then(|attribute| match &attribute.value.as_ref()?.value {
+            Some(Value::StringValue(name)) => Some(name.as_str()),
+            _ => None,
+        })
+        .unwrap_or("unknown")
+}
+
+fn log_time(record: &LogRecord) -> u64 {
+    match record.time_unix_nano {
+        0 => record.observed_time_unix_nano,
+        time => time,
+    }
+}
+
+fn severity(record: &LogRecord) -> &str {
+    if !record.severity_text.is_empty() {
+        return &record.severity_text;
+    }
+    match record.severity_number {
+        1..=4 => "TRACE",
+        5..=8 => "DEBUG",
+        9..=12 => "INFO",
+        13..=16 => "WARN",
+        17..=20 => "ERROR",
+        21..=24 => "FATAL",
+        _ => "-",
+    }
+}
+
+fn display_value(value: &AnyValue) -> String {
+    match &value.value {
+        Some(Value::StringValue(value)) => value.clone(),
+        Some(Value::BoolValue(value)) => value.to_string(),
+        Some(Value::IntValue(value)) => value.to_string(),
+        Some(Value::DoubleValue(value)) => value.to_string(),
+        Some(_) => serde_json::to_string(value).unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+fn number(value: &Option<number_data_point::Value>) -> f64 {
+    match value {
+        Some(number_data_point::Value::AsDouble(value)) => *value,
+        Some(number_data_point::Value::AsInt(value)) => *value as f64,
+        None => 0.0,
+    }
+}
+
+fn duration(start: u64, end: u64) -> Duration {
+    Duration::from_nanos(end.saturating_sub(start))
+}
+
+fn timestamp(unix_nano: u64) -> String {
+    Utc.timestamp_nanos(unix_nano as i64)
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_trace_id(id: &str) -> Result<TraceId> {
+    if id.len() != 32 {
+        bail!("A trace ID has 32 hex digits");
+    }
+    (0..id.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(id.get(i..i + 2).unwrap_or_default(), 16)
+                .map_err(|_| anyhow!("Invalid trace ID: {}", id))
+        })
+        .collect::<Result<_>>()
+        .map(TraceId)
+}
+
+fn parse_time(time: &str) -> Result<u64> {
+    let time = DateTime::parse_from_rfc3339(time)?;
+    let seconds = u64::try_from(time.timestamp()).map_err(|_| anyhow!("Time before 1970"))?;
+    Ok(seconds * 1_000_000_000 + u64::from(time.timestamp_subsec_nanos()))
+}
+
+fn parse_severity(level: &str) -> Result<SeverityLevel> {
+    SeverityLevel::try_from(level.to_string())
+}
+
+fn parse_header(header: &str) -> Result<(String, String)> {
+    header
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| anyhow!("Expected KEY=VALUE, got {}", header))
+}
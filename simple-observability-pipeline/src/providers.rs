@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use opentelemetry::global;
+use opentelemetry_sdk::logs::LoggerProvider;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::TracerProvider;
+use tracing::{error, Subscriber};
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::{ExportTarget, ExporterConfig};
+use crate::connector::{uds_connection_state, ConnectionState};
+use crate::telemetry::{PipelineHealth, PipelineTelemetry};
+
+/// The subscriber assembled by [`crate::create_providers`].
+pub type BoxedSubscriber = Box<dyn Subscriber + Send + Sync + 'static>;
+
+/// Handle to the tracer, meter and logger providers created by [`crate::create_providers`].
+///
+/// Dropping the handle shuts the providers down, flushing any telemetry that is still queued.
+pub struct ObservabilityProviders {
+    tracer_provider: Option<TracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+    logger_provider: Option<LoggerProvider>,
+    subscriber: Option<BoxedSubscriber>,
+    exporter_config: ExporterConfig,
+    telemetry: Arc<PipelineTelemetry>,
+}
+
+impl ObservabilityProviders {
+    pub(crate) fn new(
+        tracer_provider: TracerProvider,
+        meter_provider: SdkMeterProvider,
+        logger_provider: LoggerProvider,
+        subscriber: BoxedSubscriber,
+        exporter_config: ExporterConfig,
+        telemetry: Arc<PipelineTelemetry>,
+    ) -> Self {
+        Self {
+            tracer_provider: Some(tracer_provider),
+            meter_provider: Some(meter_provider),
+            logger_provider: Some(logger_provider),
+            subscriber: Some(subscriber),
+            exporter_config,
+            telemetry,
+        }
+    }
+
+    pub fn tracer_provider(&self) -> Option<&TracerProvider> {
+        self.tracer_provider.as_ref()
+    }
+
+    pub fn meter_provider(&self) -> Option<&SdkMeterProvider> {
+        self.meter_provider.as_ref()
+    }
+
+    pub fn logger_provider(&self) -> Option<&LoggerProvider> {
+        self.logger_provider.as_ref()
+    }
+
+    /// Takes the subscriber out of the handle, eg. to use it with
+    /// `tracing::subscriber::with_default` instead of installing it globally.
+    pub fn take_subscriber(&mut self) -> Option<BoxedSubscriber> {
+        self.subscriber.take()
+    }
+
+    /// Sets the global tracer and meter providers, and installs the subscriber as the global
+    /// default.
+    ///
+    /// This also installs a global OpenTelemetry error handler, which is how the batch processors
+    /// report the items they drop.
+    pub fn install_globals(&mut self) -> Result<()> {
+        self.telemetry
+            .install_error_handler()
+            .map_err(|e| anyhow!("Failed to install global error handler: {}", e))?;
+        if let Some(tracer_provider) = &self.tracer_provider {
+            global::set_tracer_provider(tracer_provider.clone());
+        }
+        if let Some(meter_provider) = &self.meter_provider {
+            global::set_meter_provider(meter_provider.clone());
+        }
+        if let Some(subscriber) = self.subscriber.take() {
+            subscriber
+                .try_init()
+                .map_err(|e| anyhow!("Failed to install global subscriber: {}", e))?;
+        }
+
+        Ok(())
+    }
+
+    /// The state of the connection to the export target, if it is a Unix domain socket.
+    pub fn connection_state(&self) -> Option<ConnectionState> {
+        match &self.exporter_config.target {
+            ExportTarget::Uds(path) => uds_connection_state(path),
+            _ => None,
+        }
+    }
+
+    /// The counters of the exports of every signal.
+    pub fn telemetry(&self) -> &Arc<PipelineTelemetry> {
+        &self.telemetry
+    }
+
+    /// A snapshot of the exports of every signal and of the connection to the export target.
+    pub fn health(&self) -> PipelineHealth {
+        self.telemetry.health(self.connection_state())
+    }
+
+    /// Exports all telemetry that is still queued in the providers.
+    pub fn force_flush(&self) {
+        if let Some(tracer_provider) = &self.tracer_provider {
+            for result in tracer_provider.force_flush() {
+                if let Err(e) = result {
+                    error!("Failed to flush tracer provider: {:?}", e);
+                }
+            }
+        }
+        if let Some(meter_provider) = &self.meter_provider {
+            if let Err(e) = meter_provider.force_flush() {
+                error!("Failed to flush metrics provider: {:?}", e);
+            }
+        }
+        if let Some(logger_provider) = &self.logger_provider {
+            for result in logger_provider.force_flush() {
+                if let Err(e) = result {
+                    error!("Failed to flush logger provider: {:?}", e);
+                }
+            }
+        }
+    }
+
+    pub fn shutdown(mut self) {
+        self.shutdown_providers();
+    }
+
+    fn shutdown_providers(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take() {
+            if let Err(e) = tracer_provider.shutdown() {
+                error!("Failed to shutdown tracer provider: {:?}", e);
+            }
+        }
+        if let Some(meter_provider) = self.meter_provider.take() {
+            if let Err(e) = meter_provider.shutdown() {
+                error!("Failed to shutdown metrics provider: {:?}", e);
+            }
+        }
+        if let Some(logger_provider) = self.logger_provider.take() {
+            if let Err(e) = logger_provider.shutdown() {
+                error!("Failed to shutdown logger provider: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Drop for ObservabilityProviders {
+    fn drop(&mut self) {
+        self.shutdown_providers();
+    }
+}
@@ -0,0 +1,426 @@
+//! Building blocks for `proxy-server`, which receives OTLP exports and writes or forwards them.
+
+use anyhow::{anyhow, bail, Result};
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::common::v1::KeyValue;
+use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+use prost::bytes::Buf;
+use prost::encoding::{decode_key, decode_varint, encode_key, encode_varint, WireType};
+use prost::Message;
+
+use crate::telemetry::Signal;
+
+pub mod admin;
+pub mod admission;
+pub mod buffer;
+pub mod capture;
+pub mod config;
+pub mod listener;
+pub mod processor;
+pub mod routing;
+pub mod sink;
+pub mod span_metrics;
+pub mod tail_sampling;
+pub mod upstream;
+
+pub use admin::{AdminConfig, ProxyStats, ProxyStatus};
+pub use admission::{Admission, AdmissionConfig, Rejection};
+pub use buffer::DiskBuffer;
+pub use capture::{read_capture_file, CaptureFilter};
+pub use config::{
+    BufferConfig, ProxyConfig, RequestLabel, RequestMetricsConfig, RetryConfig, UpstreamConfig,
+    PROXY_CONFIG,
+};
+pub use listener::{ListenerConfig, SocketFile, TlsConfig};
+pub use processor::{ProcessorConfig, Processors};
+pub use routing::{RoutingConfig, RoutingRules, RuleSet};
+pub use sink::{
+    BatchConfig, Compression, FileFormat, FileSink, FileSinkConfig, QueueFull, RotatingFile,
+    RotationConfig, SinkStats,
+};
+pub use span_metrics::{SpanMetrics, SpanMetricsConfig};
+pub use tail_sampling::{TailSampler, TailSamplingConfig};
+pub use upstream::{Upstream, UpstreamClient};
+
+/// An export received by the proxy, of any signal.
+#[derive(Debug, Clone)]
+pub enum ExportRequest {
+    Traces(ExportTraceServiceRequest),
+    Metrics(ExportMetricsServiceRequest),
+    Logs(ExportLogsServiceRequest),
+}
+
+impl ExportRequest {
+    pub fn signal(&self) -> Signal {
+        match self {
+            ExportRequest::Traces(_) => Signal::Traces,
+            ExportRequest::Metrics(_) => Signal::Metrics,
+            ExportRequest::Logs(_) => Signal::Logs,
+        }
+    }
+
+    /// The number of spans, metric data points or log records in the request.
+    pub fn items(&self) -> u64 {
+        let items = match self {
+            ExportRequest::Traces(request) => request
+                .resource_spans
+                .iter()
+                .flat_map(|resource| &resource.scope_spans)
+                .map(|scope| scope.spans.len())
+                .sum(),
+            ExportRequest::Metrics(request) => request
+                .resource_metrics
+                .iter()
+                .flat_map(|resource| &resource.scope_metrics)
+                .flat_map(|scope| &scope.metrics)
+                .map(|metric| data_points(&metric.data))
+                .sum(),
+            ExportRequest::Logs(request) => request
+                .resource_logs
+                .iter()
+                .flat_map(|resource| &resource.scope_logs)
+                .map(|scope| scope.log_records.len())
+                .sum::<usize>(),
+        };
+        items as u64
+    }
+
+    /// The `service.name` of the first resource in the request.
+    pub fn service_name(&self) -> Option<String> {
+        let resource = match self {
+            ExportRequest::Traces(request) => request.resource_spans.first()?.resource.as_ref(),
+            ExportRequest::Metrics(request) => request.resource_metrics.first()?.resource.as_ref(),
+            ExportRequest::Logs(request) => request.resource_logs.first()?.resource.as_ref(),
+        }?;
+        attribute(&resource.attributes, "service.name")
+    }
+
+    /// The size of the protobuf encoding of the request.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            ExportRequest::Traces(request) => request.encoded_len(),
+            ExportRequest::Metrics(request) => request.encoded_len(),
+            ExportRequest::Logs(request) => request.encoded_len(),
+        }
+    }
+
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        match self {
+            ExportRequest::Traces(request) => request.encode_to_vec(),
+            ExportRequest::Metrics(request) => request.encode_to_vec(),
+            ExportRequest::Logs(request) => request.encode_to_vec(),
+        }
+    }
+
+    /// The request in the OTLP/JSON encoding, on a single line.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        match self {
+            ExportRequest::Traces(request) => serde_json::to_string(request),
+            ExportRequest::Metrics(request) => serde_json::to_string(request),
+            ExportRequest::Logs(request) => serde_json::to_string(request),
+        }
+    }
+
+    /// Parses a request encoded by [`ExportRequest::to_json`]. The signal is told from the
+    /// top-level field.
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_slice(json)?;
+        let signal = [
+            (Signal::Traces, "resourceSpans"),
+            (Signal::Metrics, "resourceMetrics"),
+            (Signal::Logs, "resourceLogs"),
+        ]
+        .into_iter()
+        .find(|(_, field)| value.get(field).is_some())
+        .map(|(signal, _)| signal)
+        .ok_or_else(|| anyhow!("Not an OTLP export"))?;
+
+        Ok(match signal {
+            Signal::Traces => ExportRequest::Traces(serde_json::from_value(value)?),
+            Signal::Metrics => ExportRequest::Metrics(serde_json::from_value(value)?),
+            Signal::Logs => ExportRequest::Logs(serde_json::from_value(value)?),
+        })
+    }
+
+    /// Encodes the request as one record of a capture file in the protobuf format.
+    ///
+    /// A record is the request as a length-delimited field, numbered 1 for traces, 2 for metrics
+    /// and 3 for logs. A file of records is therefore itself a valid protobuf message, with a
+    /// repeated field for each signal.
+    pub fn encode_record(&self) -> Vec<u8> {
+        let len = self.encoded_len();
+        let mut buf = Vec::with_capacity(len + 11);
+        encode_key(
+            record_field(self.signal()),
+            WireType::LengthDelimited,
+            &mut buf,
+        );
+        encode_varint(len as u64, &mut buf);
+        match self {
+            ExportRequest::Traces(request) => request.encode_raw(&mut buf),
+            ExportRequest::Metrics(request) => request.encode_raw(&mut buf),
+            ExportRequest::Logs(request) => request.encode_raw(&mut buf),
+        }
+        buf
+    }
+
+    /// Decodes the records written by [`ExportRequest::encode_record`].
+    pub fn decode_records(mut bytes: &[u8]) -> Result<Vec<Self>> {
+        let mut requests = Vec::new();
+        while bytes.has_remaining() {
+            let (field, wire_type) = decode_key(&mut bytes)?;
+            let signal = Signal::ALL
+                .into_iter()
+                .find(|signal| record_field(*signal) == field)
+                .filter(|_| wire_type == WireType::LengthDelimited)
+                .ok_or_else(|| anyhow!("Invalid record field {}", field))?;
+
+            let len = decode_varint(&mut bytes)? as usize;
+            if len > bytes.len() {
+                bail!("Truncated record");
+            }
+            let (record, rest) = bytes.split_at(len);
+            requests.push(Self::decode(signal, record)?);
+            bytes = rest;
+        }
+        Ok(requests)
+    }
+
+    /// Decodes a request of `signal` from its protobuf encoding.
+    pub fn decode(signal: Signal, bytes: &[u8]) -> Result<Self> {
+        Ok(match signal {
+            Signal::Traces => ExportRequest::Traces(ExportTraceServiceRequest::decode(bytes)?),
+            Signal::Metrics => ExportRequest::Metrics(ExportMetricsServiceRequest::decode(bytes)?),
+            Signal::Logs => ExportRequest::Logs(ExportLogsServiceRequest::decode(bytes)?),
+        })
+    }
+}
+
+fn record_field(signal: Signal) -> u32 {
+    match signal {
+        Signal::Traces => 1,
+        Signal::Metrics => 2,
+        Signal::Logs => 3,
+    }
+}
+
+fn data_points(data: &Option<Data>) -> usize {
+    match data {
+        Some(Data::Gauge(gauge)) => gauge.data_points.len(),
+        Some(Data::Sum(sum)) => sum.data_points.len(),
+        Some(Data::Histogram(histogram)) => histogram.data_points.len(),
+        Some(Data::ExponentialHistogram(histogram)) => histogram.data_points.len(),
+        Some(Data::Summary(summary)) => summary.data_points.len(),
+        None => 0,
+    }
+}
+
+/// The value of the attribute `key` as a string, if it is set to a string, boolean or number.
+pub(crate) fn attribute(attributes: &[KeyValue], key: &str) -> Option<String> {
+    let value = attributes
+        .iter()
+        .find(|attribute| attribute.key == key)?
+        .value
+        .as_ref()?
+        .value
+        .as_ref()?;
+
+    match value {
+        Value::StringValue(value) => Some(value.clone()),
+        Value::BoolValue(value) => Some(value.to_string()),
+        Value::IntValue(value) => Some(value.to_string()),
+        Value::DoubleValue(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use opentelemetry_proto::tonic::common::v1::AnyValue;
+    use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+    use opentelemetry_proto::tonic::metrics::v1::{
+        number_data_point, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+    };
+    use opentelemetry_proto::tonic::resource::v1::Resource;
+    use opentelemetry_proto::tonic::trace::v1::{ResourceSpans, ScopeSpans, Span};
+
+    use super::*;
+    use crate::proxy::processor::string_attribute;
+
+    pub(crate) fn resource(service: &str) -> Option<Resource> {
+        Some(Resource {
+            attributes: vec![string_attribute("service.name", service)],
+            ..Default::default()
+        })
+    }
+
+    /// A root span of the trace whose ID is `trace` repeated, that took a millisecond.
+    pub(crate) fn span(trace: u8, name: &str) -> Span {
+        Span {
+            trace_id: vec![trace; 16],
+            span_id: vec![trace; 8],
+            name: name.to_string(),
+            start_time_unix_nano: 1_000_000_000,
+            end_time_unix_nano: 1_001_000_000,
+            ..Default::default()
+        }
+    }
+
+    /// A log record of the trace whose ID is `trace` repeated, if any.
+    pub(crate) fn log(trace: Option<u8>, severity_number: i32, body: &str) -> LogRecord {
+        LogRecord {
+            time_unix_nano: 1_000_000_000,
+            severity_number,
+            body: Some(AnyValue {
+                value: Some(Value::StringValue(body.to_string())),
+            }),
+            trace_id: trace.map_or(Vec::new(), |trace| vec![trace; 16]),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn traces(service: &str, spans: Vec<Span>) -> ExportRequest {
+        ExportRequest::Traces(ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: resource(service),
+                scope_spans: vec![ScopeSpans {
+                    spans,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    pub(crate) fn logs(service: &str, log_records: Vec<LogRecord>) -> ExportRequest {
+        ExportRequest::Logs(ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: resource(service),
+                scope_logs: vec![ScopeLogs {
+                    log_records,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    /// Gauges with a single data point each.
+    pub(crate) fn metrics(service: &str, names: &[&str]) -> ExportRequest {
+        let metrics = names
+            .iter()
+            .map(|name| Metric {
+                name: name.to_string(),
+                data: Some(Data::Gauge(Gauge {
+                    data_points: vec![NumberDataPoint {
+                        time_unix_nano: 1_000_000_000,
+                        value: Some(number_data_point::Value::AsInt(1)),
+                        ..Default::default()
+                    }],
+                })),
+                ..Default::default()
+            })
+            .collect();
+        ExportRequest::Metrics(ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: resource(service),
+                scope_metrics: vec![ScopeMetrics {
+                    metrics,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    /// The names of the spans, metrics or the bodies of the log records in `request`, in order.
+    pub(crate) fn names(request: &ExportRequest) -> Vec<String> {
+        match request {
+            ExportRequest::Traces(request) => request
+                .resource_spans
+                .iter()
+                .flat_map(|resource| &resource.scope_spans)
+                .flat_map(|scope| &scope.spans)
+                .map(|span| span.name.clone())
+                .collect(),
+            ExportRequest::Metrics(request) => request
+                .resource_metrics
+                .iter()
+                .flat_map(|resource| &resource.scope_metrics)
+                .flat_map(|scope| &scope.metrics)
+                .map(|metric| metric.name.clone())
+                .collect(),
+            ExportRequest::Logs(request) => request
+                .resource_logs
+                .iter()
+                .flat_map(|resource| &resource.scope_logs)
+                .flat_map(|scope| &scope.log_records)
+                .filter_map(|record| match record.body.as_ref()?.value.as_ref()? {
+                    Value::StringValue(body) => Some(body.clone()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    fn sample() -> Vec<ExportRequest> {
+        vec![
+            traces("checkout", vec![span(1, "GET /cart"), span(1, "db")]),
+            metrics("checkout", &["cpu", "memory"]),
+            logs("payments", vec![log(Some(1), 9, "paid")]),
+        ]
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let requests = sample();
+        let bytes = requests
+            .iter()
+            .flat_map(ExportRequest::encode_record)
+            .collect::<Vec<_>>();
+
+        let decoded = ExportRequest::decode_records(&bytes).unwrap();
+        assert_eq!(decoded.len(), requests.len());
+        for (decoded, request) in decoded.iter().zip(&requests) {
+            assert_eq!(decoded.signal(), request.signal());
+            assert_eq!(names(decoded), names(request));
+            assert_eq!(decoded.encode_to_vec(), request.encode_to_vec());
+        }
+    }
+
+    #[test]
+    fn invalid_records_are_rejected() {
+        let record = sample()[0].encode_record();
+        assert!(ExportRequest::decode_records(&record[..record.len() - 1]).is_err());
+        // Field 4, length-delimited and empty.
+        assert!(ExportRequest::decode_records(&[0x22, 0]).is_err());
+        // Field 1, but as a varint.
+        assert!(ExportRequest::decode_records(&[0x08, 0]).is_err());
+        assert!(ExportRequest::decode_records(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for request in sample() {
+            let json = request.to_json().unwrap();
+            assert!(!json.contains('\n'));
+            let decoded = ExportRequest::from_json(json.as_bytes()).unwrap();
+            assert_eq!(decoded.signal(), request.signal());
+            assert_eq!(decoded.encode_to_vec(), request.encode_to_vec());
+        }
+        assert!(ExportRequest::from_json(b"{}").is_err());
+    }
+
+    #[test]
+    fn items_and_service_name() {
+        let [traces, metrics, logs] = <[_; 3]>::try_from(sample()).unwrap();
+        assert_eq!(traces.items(), 2);
+        assert_eq!(metrics.items(), 2);
+        assert_eq!(logs.items(), 1);
+        assert_eq!(traces.service_name().as_deref(), Some("checkout"));
+        assert_eq!(logs.service_name().as_deref(), Some("payments"));
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::proxy::sink::signal_index;
+use crate::proxy::{Admission, FileSink, Rejection, Upstream};
+use crate::telemetry::Signal;
+
+/// Where `proxy-server` serves its admin endpoints over HTTP.
+///
+/// ```json
+/// { "address": "127.0.0.1:8888" }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub address: SocketAddr,
+}
+
+/// Whether the proxy is accepting exports, and how many it has received.
+#[derive(Debug)]
+pub struct ProxyStatus {
+    started: Instant,
+    ready: AtomicBool,
+    received: [AtomicU64; 3],
+    received_items: [AtomicU64; 3],
+}
+
+impl Default for ProxyStatus {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            ready: AtomicBool::new(false),
+            received: Default::default(),
+            received_items: Default::default(),
+        }
+    }
+}
+
+impl ProxyStatus {
+    /// Counts an export of `signal` with `items` spans, data points or log records.
+    pub fn received(&self, signal: Signal, items: u64) {
+        self.received[signal_index(signal)].fetch_add(1, Ordering::Relaxed);
+        self.received_items[signal_index(signal)].fetch_add(items, Ordering::Relaxed);
+    }
+
+    /// Set once every listener is bound and being served, and cleared when the proxy starts
+    /// shutting down.
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
+/// What `/stats` reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyStats {
+    pub uptime_secs: f64,
+    pub ready: bool,
+    /// By signal.
+    pub signals: BTreeMap<&'static str, SignalStats>,
+    /// Absent if the file sink is disabled.
+    pub file_sink: Option<FileSinkStats>,
+    pub upstreams: Vec<UpstreamStats>,
+    pub admission: AdmissionStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SignalStats {
+    /// Exports received, whether or not they were admitted.
+    pub received: u64,
+    /// Spans, data points or log records in them.
+    pub received_items: u64,
+    /// Exports written to the capture file.
+    pub written: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileSinkStats {
+    /// Exports waiting to be written.
+    pub queue_depth: usize,
+    /// Batches that failed to be written.
+    pub errors: u64,
+    /// Exports turned away because the queue was full.
+    pub rejected: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamStats {
+    pub name: String,
+    pub queue_depth: usize,
+    pub queue_bytes: u64,
+    pub evicted: u64,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdmissionStats {
+    pub in_flight: usize,
+    /// By reason.
+    pub rejected: BTreeMap<&'static str, u64>,
+}
+
+impl ProxyStats {
+    pub fn collect(
+        status: &ProxyStatus,
+        file_sink: Option<&FileSink>,
+        upstreams: &[Upstream],
+        admission: &Admission,
+    ) -> Self {
+        let signals = Signal::ALL
+            .into_iter()
+            .map(|signal| {
+                let i = signal_index(signal);
+                let stats = SignalStats {
+                    received: status.received[i].load(Ordering::Relaxed),
+                    received_items: status.received_items[i].load(Ordering::Relaxed),
+                    written: file_sink.map_or(0, |sink| sink.stats().written(signal)),
+                };
+                (signal.as_str(), stats)
+            })
+            .collect();
+
+        Self {
+            uptime_secs: status.started.elapsed().as_secs_f64(),
+            ready: status.is_ready(),
+            signals,
+            file_sink: file_sink.map(|sink| FileSinkStats {
+                queue_depth: sink.stats().queued(),
+                errors: sink.stats().errors(),
+                rejected: sink.stats().rejected(),
+            }),
+            upstreams: upstreams
+                .iter()
+                .map(|upstream| UpstreamStats {
+                    name: upstream.name().to_string(),
+                    queue_depth: upstream.queue_depth(),
+                    queue_bytes: upstream.queue_bytes(),
+                    evicted: upstream.evicted(),
+                    dropped: upstream.dropped(),
+                })
+                .collect(),
+            admission: AdmissionStats {
+                in_flight: admission.in_flight(),
+                rejected: Rejection::ALL
+                    .into_iter()
+                    .map(|reason| (reason.as_str(), admission.rejected(reason)))
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// Serves the admin endpoints on `listener` until `shutdown` completes:
+///
+/// - `/healthz` answers 200 while the proxy is running, for liveness probes.
+/// - `/readyz` answers 200 while it is accepting exports, and 503 before the listeners are up
+///   and once it is shutting down.
+/// - `/stats` returns the [`ProxyStats`] from `stats` as JSON.
+pub async fn serve_admin<F>(
+    listener: TcpListener,
+    status: Arc<ProxyStatus>,
+    stats: F,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()>
+where
+    F: Fn() -> ProxyStats + Clone + Send + Sync + 'static,
+{
+    let router = Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .route(
+            "/readyz",
+            get(|State(status): State<Arc<ProxyStatus>>| async move {
+                match status.is_ready() {
+                    true => (StatusCode::OK, "ready"),
+                    false => (StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+                }
+            }),
+        )
+        .with_state(status)
+        .route(
+            "/stats",
+            get(move || async move { Json(stats()).into_response() }),
+        );
+
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// How long a client's limits are remembered after its last request.
+const CLIENT_IDLE: Duration = Duration::from_secs(60);
+
+/// Limits on what clients can send. Nothing is limited by default.
+///
+/// Clients are told apart by their `x-origin` header, or else by the `service.name` of the first
+/// resource in their exports. Clients name themselves, so a client can get fresh limits by changing
+/// its name. Only `max_in_flight` and `max_request_bytes` hold whatever a client says it is.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdmissionConfig {
+    /// Exports per second, per client.
+    pub requests_per_sec: Option<f64>,
+    /// Bytes per second, per client, of exports encoded as protobuf.
+    pub bytes_per_sec: Option<f64>,
+    /// How far a client can go above its rates, in seconds' worth of them.
+    pub burst_secs: f64,
+    /// Exports handled at the same time, from all clients, counted from before they are read.
+    pub max_in_flight: Option<usize>,
+    /// Larger exports are turned away by the listeners before they are decoded.
+    pub max_request_bytes: Option<usize>,
+    /// How many clients have their own rates at most. Clients beyond it share a single set.
+    pub max_clients: usize,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: None,
+            bytes_per_sec: None,
+            burst_secs: 1.0,
+            max_in_flight: None,
+            max_request_bytes: None,
+            max_clients: 10_000,
+        }
+    }
+}
+
+/// Why an export was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Larger than `max_request_bytes`. Retrying won't help.
+    TooLarge,
+    /// `max_in_flight` exports are already being handled.
+    InFlight,
+    /// The client is over `requests_per_sec`.
+    Rate,
+    /// The client is over `bytes_per_sec`.
+    Bytes,
+}
+
+impl Rejection {
+    pub const ALL: [Rejection; 4] = [
+        Rejection::TooLarge,
+        Rejection::InFlight,
+        Rejection::Rate,
+        Rejection::Bytes,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::TooLarge => "too_large",
+            Rejection::InFlight => "in_flight",
+            Rejection::Rate => "rate",
+            Rejection::Bytes => "bytes",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::TooLarge => "The export is larger than the proxy accepts",
+            Rejection::InFlight => "The proxy is handling too many exports",
+            Rejection::Rate => "Too many exports from this client",
+            Rejection::Bytes => "Too much data from this client",
+        })
+    }
+}
+
+/// Decides which exports the proxy handles, as configured by an [`AdmissionConfig`].
+#[derive(Debug)]
+pub struct Admission {
+    config: AdmissionConfig,
+    in_flight: AtomicUsize,
+    clients: Mutex<Clients>,
+    rejected: [AtomicU64; 4],
+}
+
+#[derive(Debug)]
+struct Clients {
+    /// `None` for the clients beyond `max_clients`.
+    buckets: HashMap<Option<String>, Buckets>,
+    last_sweep: Instant,
+}
+
+/// The token buckets of one client. Tokens can go negative, so that an export larger than a
+/// bucket is still let through once the bucket is full, and the client then waits longer.
+#[derive(Debug)]
+struct Buckets {
+    requests: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+/// Counts an export as in flight until it is dropped.
+#[derive(Debug)]
+pub struct Permit<'a> {
+    in_flight: &'a AtomicUsize,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Admission {
+    pub fn new(config: &AdmissionConfig) -> Self {
+        Self {
+            config: config.clone(),
+            in_flight: AtomicUsize::new(0),
+            clients: Mutex::new(Clients {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+            rejected: Default::default(),
+        }
+    }
+
+    /// Counts an export as in flight until the permit is dropped, unless `max_in_flight` exports
+    /// already are. Called before the export is read, so that the limit also bounds the memory
+    /// taken by exports that are still being received.
+    pub fn start(&self) -> Result<Permit<'_>, Rejection> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let permit = Permit {
+            in_flight: &self.in_flight,
+        };
+        if self
+            .config
+            .max_in_flight
+            .is_some_and(|max| in_flight >= max)
+        {
+            return Err(self.reject(Rejection::InFlight));
+        }
+        Ok(permit)
+    }
+
+    /// Decides whether to handle an export of `bytes` from `client`, going by its rates.
+    pub fn admit(&self, client: &str, bytes: usize) -> Result<(), Rejection> {
+        if self.config.requests_per_sec.is_none() && self.config.bytes_per_sec.is_none() {
+            return Ok(());
+        }
+        self.take_tokens(client, bytes as f64)
+            .map_err(|rejection| self.reject(rejection))
+    }
+
+    /// Counts an export that a listener turned away for being over `max_request_bytes`.
+    pub fn too_large(&self) {
+        self.reject(Rejection::TooLarge);
+    }
+
+    fn reject(&self, rejection: Rejection) -> Rejection {
+        self.rejected[rejection as usize].fetch_add(1, Ordering::Relaxed);
+        rejection
+    }
+
+    fn take_tokens(&self, client: &str, bytes: f64) -> Result<(), Rejection> {
+        let burst = self.config.burst_secs.max(0.0);
+        let requests_limit = self
+            .config
+            .requests_per_sec
+            .map(|rate| (rate, rate * burst));
+        let bytes_limit = self.config.bytes_per_sec.map(|rate| (rate, rate * burst));
+
+        let mut clients = self.clients.lock().expect("admission poisoned");
+        let now = Instant::now();
+        if now.duration_since(clients.last_sweep) >= CLIENT_IDLE {
+            clients
+                .buckets
+                .retain(|_, buckets| now.duration_since(buckets.updated) < CLIENT_IDLE);
+            clients.last_sweep = now;
+        }
+
+        let mut key = Some(client.to_string());
+        if !clients.buckets.contains_key(&key) && clients.buckets.len() >= self.config.max_clients {
+            key = None;
+        }
+        let buckets = clients.buckets.entry(key).or_insert_with(|| Buckets {
+            requests: requests_limit.map_or(0.0, |(_, capacity)| capacity),
+            bytes: bytes_limit.map_or(0.0, |(_, capacity)| capacity),
+            updated: now,
+        });
+        let elapsed = now.duration_since(buckets.updated).as_secs_f64();
+        buckets.updated = now;
+
+        let refill = |tokens: &mut f64, (rate, capacity): (f64, f64)| {
+            *tokens = (*tokens + rate * elapsed).min(capacity);
+        };
+        let has = |tokens: f64, cost: f64, (_, capacity): (f64, f64)| tokens >= cost.min(capacity);
+
+        if let Some(limit) = requests_limit {
+            refill(&mut buckets.requests, limit);
+            if !has(buckets.requests, 1.0, limit) {
+                return Err(Rejection::Rate);
+            }
+        }
+        if let Some(limit) = bytes_limit {
+            refill(&mut buckets.bytes, limit);
+            if !has(buckets.bytes, bytes, limit) {
+                return Err(Rejection::Bytes);
+            }
+        }
+
+        if requests_limit.is_some() {
+            buckets.requests -= 1.0;
+        }
+        if bytes_limit.is_some() {
+            buckets.bytes -= bytes;
+        }
+        Ok(())
+    }
+
+    /// How many exports were turned away for `reason`.
+    pub fn rejected(&self, reason: Rejection) -> u64 {
+        self.rejected[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// The number of exports being handled.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(config: AdmissionConfig) -> Admission {
+        Admission::new(&config)
+    }
+
+    /// Moves the client's buckets back in time, as if `secs` had passed since its last export.
+    fn wait(admission: &Admission, client: &str, secs: f64) {
+        let mut clients = admission.clients.lock().unwrap();
+        let buckets = clients.buckets.get_mut(&Some(client.to_string())).unwrap();
+        buckets.updated -= Duration::from_secs_f64(secs);
+    }
+
+    #[test]
+    fn requests_are_limited_after_the_burst() {
+        let admission = admission(AdmissionConfig {
+            requests_per_sec: Some(2.0),
+            burst_secs: 2.0,
+            ..Default::default()
+        });
+        for _ in 0..4 {
+            admission.admit("a", 10).unwrap();
+        }
+        assert_eq!(admission.admit("a", 10).unwrap_err(), Rejection::Rate);
+        // Other clients have their own rates.
+        admission.admit("b", 10).unwrap();
+
+        wait(&admission, "a", 0.5);
+        admission.admit("a", 10).unwrap();
+        assert_eq!(admission.admit("a", 10).unwrap_err(), Rejection::Rate);
+
+        // The bucket refills up to the burst, no further.
+        wait(&admission, "a", 60.0);
+        for _ in 0..4 {
+            admission.admit("a", 10).unwrap();
+        }
+        assert_eq!(admission.admit("a", 10).unwrap_err(), Rejection::Rate);
+        assert_eq!(admission.rejected(Rejection::Rate), 3);
+    }
+
+    #[test]
+    fn large_exports_go_through_a_full_bucket_then_wait() {
+        let admission = admission(AdmissionConfig {
+            bytes_per_sec: Some(100.0),
+            ..Default::default()
+        });
+        admission.admit("a", 250).unwrap();
+        assert_eq!(admission.admit("a", 1).unwrap_err(), Rejection::Bytes);
+        wait(&admission, "a", 1.5);
+        assert_eq!(admission.admit("a", 1).unwrap_err(), Rejection::Bytes);
+        wait(&admission, "a", 1.0);
+        admission.admit("a", 1).unwrap();
+    }
+
+    #[test]
+    fn exports_in_flight_are_limited() {
+        let admission = admission(AdmissionConfig {
+            max_in_flight: Some(2),
+            ..Default::default()
+        });
+        let first = admission.start().unwrap();
+        let _second = admission.start().unwrap();
+        assert_eq!(admission.start().unwrap_err(), Rejection::InFlight);
+        assert_eq!(admission.in_flight(), 2);
+        drop(first);
+        admission.start().unwrap();
+        assert_eq!(admission.in_flight(), 1);
+        assert_eq!(admission.rejected(Rejection::InFlight), 1);
+
+        admission.too_large();
+        assert_eq!(admission.rejected(Rejection::TooLarge), 1);
+    }
+
+    #[test]
+    fn clients_beyond_max_clients_share_their_rates() {
+        let admission = admission(AdmissionConfig {
+            requests_per_sec: Some(1.0),
+            max_clients: 2,
+            ..Default::default()
+        });
+        admission.admit("a", 1).unwrap();
+        admission.admit("b", 1).unwrap();
+        admission.admit("c", 1).unwrap();
+        assert_eq!(admission.admit("d", 1).unwrap_err(), Rejection::Rate);
+        assert_eq!(admission.admit("a", 1).unwrap_err(), Rejection::Rate);
+        assert_eq!(admission.clients.lock().unwrap().buckets.len(), 3);
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::Notify;
+
+use crate::proxy::ExportRequest;
+use crate::telemetry::Signal;
+
+/// A queue of exports persisted to a directory, so that they survive both an unreachable upstream
+/// and a restart of the proxy.
+///
+/// Every export is stored in its own file, named after its position in the queue and its signal,
+/// eg. `00000000000000000042.traces.pb`, and encoded as protobuf. Once the files take up more than
+/// `max_bytes`, the oldest ones are deleted to make room.
+#[derive(Debug)]
+pub struct DiskBuffer {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: VecDeque<Entry>,
+    next_seq: u64,
+    bytes: u64,
+    evicted: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    seq: u64,
+    signal: Signal,
+    size: u64,
+}
+
+impl Entry {
+    fn file_name(&self) -> String {
+        format!("{:020}.{}.pb", self.seq, self.signal)
+    }
+
+    fn parse(path: &Path, size: u64) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.strip_suffix(".pb")?;
+        let (seq, signal) = name.split_once('.')?;
+        Some(Self {
+            seq: seq.parse().ok()?,
+            signal: signal.parse().ok()?,
+            size,
+        })
+    }
+}
+
+impl DiskBuffer {
+    /// Opens the queue in `dir`, creating the directory if needed. Exports queued by a previous run
+    /// are kept, in their original order.
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow!("Failed to create {}: {}", dir.display(), e))?;
+
+        let mut entries = Vec::new();
+        for file in std::fs::read_dir(&dir)? {
+            let file = file?;
+            let path = file.path();
+            match Entry::parse(&path, file.metadata()?.len()) {
+                Some(entry) => entries.push(entry),
+                // Leftovers of a write that was interrupted.
+                None if path.extension().is_some_and(|ext| ext == "tmp") => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                None => {}
+            }
+        }
+        entries.sort_by_key(|entry| entry.seq);
+
+        let state = State {
+            next_seq: entries.last().map_or(0, |entry| entry.seq + 1),
+            bytes: entries.iter().map(|entry| entry.size).sum(),
+            entries: entries.into(),
+            evicted: 0,
+        };
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            state: Mutex::new(state),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Appends `request` to the queue, evicting the oldest exports if the queue grows beyond its
+    /// size limit or the disk is full. Does blocking I/O.
+    pub fn push(&self, request: &ExportRequest) -> Result<()> {
+        let bytes = request.encode_to_vec();
+        let entry = {
+            let mut state = self.state.lock().expect("disk buffer poisoned");
+            let entry = Entry {
+                seq: state.next_seq,
+                signal: request.signal(),
+                size: bytes.len() as u64,
+            };
+            state.next_seq += 1;
+            entry
+        };
+        let path = self.dir.join(entry.file_name());
+        let tmp = path.with_extension("tmp");
+
+        // Write to a temporary file first so that a crash never leaves a partial export behind.
+        // The queue isn't locked meanwhile, so that it can still be read from.
+        let write = || std::fs::write(&tmp, &bytes).and_then(|_| std::fs::rename(&tmp, &path));
+        while let Err(e) = write() {
+            // Only a full disk can be fixed by evicting. Anything else would delete the whole
+            // queue before failing anyway.
+            let full = matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded);
+            let evicted = match full {
+                true => self.evict_oldest(&mut self.state.lock().expect("disk buffer poisoned")),
+                false => None,
+            };
+            match evicted {
+                Some(evicted) => remove_file(&evicted),
+                None => {
+                    remove_file(&tmp);
+                    return Err(anyhow!("Failed to write {}: {}", path.display(), e));
+                }
+            }
+        }
+
+        let mut state = self.state.lock().expect("disk buffer poisoned");
+        state.bytes += entry.size;
+        let position = state
+            .entries
+            .partition_point(|queued| queued.seq < entry.seq);
+        state.entries.insert(position, entry);
+
+        // Always keep the newest export, even if it is larger than the limit on its own.
+        let mut evicted = Vec::new();
+        while state.bytes > self.max_bytes && state.entries.len() > 1 {
+            evicted.extend(self.evict_oldest(&mut state));
+        }
+        drop(state);
+
+        evicted.iter().for_each(|path| remove_file(path));
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Returns the oldest export and its position in the queue, without removing it. Does
+    /// blocking I/O.
+    pub fn peek(&self) -> Result<Option<(u64, ExportRequest)>> {
+        loop {
+            let Some(entry) = self.front() else {
+                return Ok(None);
+            };
+            let path = self.dir.join(entry.file_name());
+            let bytes = match std::fs::read(&path) {
+                Ok(bytes) => bytes,
+                // Evicted since, or deleted by someone else.
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    self.remove(entry.seq);
+                    continue;
+                }
+                Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+            };
+
+            match ExportRequest::decode(entry.signal, &bytes) {
+                Ok(request) => return Ok(Some((entry.seq, request))),
+                Err(e) => {
+                    eprintln!("Dropping corrupt export {}: {}", path.display(), e);
+                    self.remove(entry.seq);
+                }
+            }
+        }
+    }
+
+    /// Removes the export at `seq`, if it is still the oldest one and wasn't evicted in the
+    /// meantime. Does blocking I/O.
+    pub fn remove(&self, seq: u64) {
+        let mut state = self.state.lock().expect("disk buffer poisoned");
+        if state.entries.front().is_some_and(|entry| entry.seq == seq) {
+            let removed = self.pop_front(&mut state);
+            drop(state);
+            removed.iter().for_each(|path| remove_file(path));
+        }
+    }
+
+    fn front(&self) -> Option<Entry> {
+        let state = self.state.lock().expect("disk buffer poisoned");
+        state.entries.front().copied()
+    }
+
+    /// Waits until an export is pushed. Returns immediately if one was pushed since the last call.
+    pub async fn wait(&self) {
+        self.notify.notified().await
+    }
+
+    /// The number of queued exports.
+    pub fn depth(&self) -> usize {
+        self.state
+            .lock()
+            .expect("disk buffer poisoned")
+            .entries
+            .len()
+    }
+
+    /// The size of the queued exports on disk.
+    pub fn bytes(&self) -> u64 {
+        self.state.lock().expect("disk buffer poisoned").bytes
+    }
+
+    /// How many exports were deleted to make room for newer ones.
+    pub fn evicted(&self) -> u64 {
+        self.state.lock().expect("disk buffer poisoned").evicted
+    }
+
+    /// Removes the oldest export from the queue. Returns its file, for the caller to delete once
+    /// the queue is unlocked.
+    fn evict_oldest(&self, state: &mut State) -> Option<PathBuf> {
+        let evicted = self.pop_front(state);
+        if evicted.is_some() {
+            state.evicted += 1;
+        }
+        evicted
+    }
+
+    fn pop_front(&self, state: &mut State) -> Option<PathBuf> {
+        let entry = state.entries.pop_front()?;
+        state.bytes -= entry.size;
+        Some(self.dir.join(entry.file_name()))
+    }
+}
+
+fn remove_file(path: &Path) {
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::tests::{log, logs, metrics, names, span, traces};
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|file| file.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    fn peek_names(buffer: &DiskBuffer) -> Option<(u64, Vec<String>)> {
+        let (seq, request) = buffer.peek().unwrap()?;
+        Some((seq, names(&request)))
+    }
+
+    #[test]
+    fn reopening_keeps_the_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = DiskBuffer::open(dir.path(), u64::MAX).unwrap();
+        buffer.push(&traces("a", vec![span(1, "first")])).unwrap();
+        buffer.push(&metrics("a", &["second"])).unwrap();
+        buffer
+            .push(&logs("a", vec![log(None, 9, "third")]))
+            .unwrap();
+        let bytes = buffer.bytes();
+        drop(buffer);
+        // Left behind by an interrupted write.
+        std::fs::write(
+            dir.path().join("00000000000000000003.traces.tmp"),
+            b"partial",
+        )
+        .unwrap();
+
+        let buffer = DiskBuffer::open(dir.path(), u64::MAX).unwrap();
+        assert_eq!(buffer.depth(), 3);
+        assert_eq!(buffer.bytes(), bytes);
+        assert_eq!(files(dir.path()).len(), 3);
+
+        for expected in ["first", "second", "third"] {
+            let (seq, names) = peek_names(&buffer).unwrap();
+            assert_eq!(names, [expected]);
+            buffer.remove(seq);
+        }
+        assert!(buffer.peek().unwrap().is_none());
+        assert_eq!(buffer.bytes(), 0);
+
+        // New exports are queued after the ones read back.
+        buffer.push(&traces("a", vec![span(1, "fourth")])).unwrap();
+        assert_eq!(peek_names(&buffer).unwrap().0, 3);
+    }
+
+    #[test]
+    fn evicts_the_oldest_beyond_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let export = |name| traces("a", vec![span(1, name)]);
+        let size = export("0").encoded_len() as u64;
+
+        let buffer = DiskBuffer::open(dir.path(), size * 2).unwrap();
+        for name in ["0", "1", "2"] {
+            buffer.push(&export(name)).unwrap();
+        }
+        assert_eq!(buffer.depth(), 2);
+        assert_eq!(buffer.evicted(), 1);
+        assert_eq!(files(dir.path()).len(), 2);
+        assert_eq!(peek_names(&buffer).unwrap().1, ["1"]);
+
+        // The newest export is kept even if it is over the limit on its own.
+        let large = "x".repeat(size as usize * 4);
+        buffer.push(&export(&large)).unwrap();
+        assert_eq!(buffer.depth(), 1);
+        assert_eq!(buffer.evicted(), 3);
+        assert_eq!(peek_names(&buffer).unwrap().1, [large]);
+    }
+
+    #[test]
+    fn only_removes_the_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = DiskBuffer::open(dir.path(), u64::MAX).unwrap();
+        buffer.push(&traces("a", vec![span(1, "first")])).unwrap();
+        buffer.push(&traces("a", vec![span(1, "second")])).unwrap();
+
+        buffer.remove(1);
+        assert_eq!(buffer.depth(), 2);
+        buffer.remove(0);
+        assert_eq!(
+            peek_names(&buffer).unwrap(),
+            (1, vec!["second".to_string()])
+        );
+    }
+
+    #[test]
+    fn skips_missing_and_corrupt_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = DiskBuffer::open(dir.path(), u64::MAX).unwrap();
+        for name in ["missing", "corrupt", "intact"] {
+            buffer.push(&traces("a", vec![span(1, name)])).unwrap();
+        }
+        let files = files(dir.path());
+        std::fs::remove_file(&files[0]).unwrap();
+        std::fs::write(&files[1], [0xff; 16]).unwrap();
+
+        assert_eq!(
+            peek_names(&buffer).unwrap(),
+            (2, vec!["intact".to_string()])
+        );
+        assert_eq!(buffer.depth(), 1);
+        assert!(!files[1].exists());
+        assert_eq!(buffer.evicted(), 0);
+    }
+
+    #[test]
+    fn keeps_the_queue_when_a_write_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue");
+        let buffer = DiskBuffer::open(&path, u64::MAX).unwrap();
+        buffer.push(&traces("a", vec![span(1, "first")])).unwrap();
+        buffer.push(&traces("a", vec![span(1, "second")])).unwrap();
+
+        // Writes now fail with `NotFound`, which evicting can't fix.
+        let moved = dir.path().join("moved");
+        std::fs::rename(&path, &moved).unwrap();
+        assert!(buffer.push(&traces("a", vec![span(1, "third")])).is_err());
+        assert_eq!(buffer.depth(), 2);
+        assert_eq!(buffer.evicted(), 0);
+        assert_eq!(files(&moved).len(), 2);
+    }
+}
@@ -0,0 +1,197 @@
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use opentelemetry_proto::tonic::logs::v1::LogRecord;
+use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_proto::tonic::trace::v1::Span;
+
+use crate::proxy::routing::SeverityLevel;
+use crate::proxy::{attribute, ExportRequest};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Reads a capture file written by the file sink, in either format and compressed or not. The
+/// format is detected from the contents, so rotated files can be read too.
+pub fn read_capture_file(path: &Path) -> Result<Vec<ExportRequest>> {
+    let error = |e: anyhow::Error| anyhow!("Failed to read {}: {}", path.display(), e);
+    let raw = std::fs::read(path).map_err(|e| error(e.into()))?;
+    decompress(raw).and_then(|data| parse(&data)).map_err(error)
+}
+
+fn decompress(raw: Vec<u8>) -> Result<Vec<u8>> {
+    if raw.starts_with(GZIP_MAGIC) {
+        let mut data = Vec::new();
+        MultiGzDecoder::new(raw.as_slice()).read_to_end(&mut data)?;
+        Ok(data)
+    } else if raw.starts_with(ZSTD_MAGIC) {
+        Ok(zstd::decode_all(raw.as_slice())?)
+    } else {
+        Ok(raw)
+    }
+}
+
+/// JSON files have an export per line. Anything else is read as protobuf records.
+fn parse(data: &[u8]) -> Result<Vec<ExportRequest>> {
+    match data.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        None => Ok(Vec::new()),
+        Some(b'{') => data
+            .split(|byte| *byte == b'\n')
+            .enumerate()
+            .filter(|(_, line)| !line.trim_ascii().is_empty())
+            .map(|(i, line)| {
+                ExportRequest::from_json(line).map_err(|e| anyhow!("Line {}: {}", i + 1, e))
+            })
+            .collect(),
+        Some(_) => ExportRequest::decode_records(data),
+    }
+}
+
+/// Selects data from capture files. Everything that is set has to match.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureFilter {
+    /// The `service.name` of the resource.
+    pub service: Option<String>,
+    /// Spans and logs of this trace. Metrics never match.
+    pub trace_id: Option<Vec<u8>>,
+    /// In nanoseconds since the epoch. Spans match if they overlap the range, logs and metric data
+    /// points if their timestamp is within it.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Logs at this severity or above. Spans and metrics aren't affected.
+    pub min_severity: Option<SeverityLevel>,
+}
+
+impl CaptureFilter {
+    /// Removes what doesn't match from `request`, along with the resources, scopes and metrics
+    /// left empty. Returns whether anything is left.
+    pub fn apply(&self, request: &mut ExportRequest) -> bool {
+        match request {
+            ExportRequest::Traces(request) => {
+                request.resource_spans.retain_mut(|resource_spans| {
+                    if !self.matches_resource(&resource_spans.resource) {
+                        return false;
+                    }
+                    resource_spans.scope_spans.retain_mut(|scope_spans| {
+                        scope_spans.spans.retain(|span| self.matches_span(span));
+                        !scope_spans.spans.is_empty()
+                    });
+                    !resource_spans.scope_spans.is_empty()
+                });
+                !request.resource_spans.is_empty()
+            }
+            ExportRequest::Metrics(request) => {
+                if self.trace_id.is_some() {
+                    return false;
+                }
+                request.resource_metrics.retain_mut(|resource_metrics| {
+                    if !self.matches_resource(&resource_metrics.resource) {
+                        return false;
+                    }
+                    resource_metrics.scope_metrics.retain_mut(|scope_metrics| {
+                        scope_metrics
+                            .metrics
+                            .retain_mut(|metric| self.retain_data_points(&mut metric.data));
+                        !scope_metrics.metrics.is_empty()
+                    });
+                    !resource_metrics.scope_metrics.is_empty()
+                });
+                !request.resource_metrics.is_empty()
+            }
+            ExportRequest::Logs(request) => {
+                request.resource_logs.retain_mut(|resource_logs| {
+                    if !self.matches_resource(&resource_logs.resource) {
+                        return false;
+                    }
+                    resource_logs.scope_logs.retain_mut(|scope_logs| {
+                        scope_logs
+                            .log_records
+                            .retain(|record| self.matches_log(record));
+                        !scope_logs.log_records.is_empty()
+                    });
+                    !resource_logs.scope_logs.is_empty()
+                });
+                !request.resource_logs.is_empty()
+            }
+        }
+    }
+
+    fn matches_resource(&self, resource: &Option<Resource>) -> bool {
+        let Some(service) = &self.service else {
+            return true;
+        };
+        resource
+            .as_ref()
+            .and_then(|resource| attribute(&resource.attributes, "service.name"))
+            .is_some_and(|name| name == *service)
+    }
+
+    fn matches_trace(&self, trace_id: &[u8]) -> bool {
+        self.trace_id.as_ref().is_none_or(|id| id == trace_id)
+    }
+
+    fn matches_span(&self, span: &Span) -> bool {
+        self.matches_trace(&span.trace_id)
+            && self
+                .since
+                .is_none_or(|since| span.end_time_unix_nano >= since)
+            && self
+                .until
+                .is_none_or(|until| span.start_time_unix_nano <= until)
+    }
+
+    fn matches_log(&self, record: &LogRecord) -> bool {
+        let time = match record.time_unix_nano {
+            0 => record.observed_time_unix_nano,
+            time => time,
+        };
+        self.matches_trace(&record.trace_id)
+            && self.in_range(time)
+            && self
+                .min_severity
+                .is_none_or(|min| record.severity_number >= min.min())
+    }
+
+    fn in_range(&self, time: u64) -> bool {
+        self.since.is_none_or(|since| time >= since) && self.until.is_none_or(|until| time <= until)
+    }
+
+    /// Keeps the data points in the time range. Returns whether any are left.
+    fn retain_data_points(&self, data: &mut Option<Data>) -> bool {
+        match data {
+            Some(Data::Gauge(gauge)) => {
+                gauge
+                    .data_points
+                    .retain(|point| self.in_range(point.time_unix_nano));
+                !gauge.data_points.is_empty()
+            }
+            Some(Data::Sum(sum)) => {
+                sum.data_points
+                    .retain(|point| self.in_range(point.time_unix_nano));
+                !sum.data_points.is_empty()
+            }
+            Some(Data::Histogram(histogram)) => {
+                histogram
+                    .data_points
+                    .retain(|point| self.in_range(point.time_unix_nano));
+                !histogram.data_points.is_empty()
+            }
+            Some(Data::ExponentialHistogram(histogram)) => {
+                histogram
+                    .data_points
+                    .retain(|point| self.in_range(point.time_unix_nano));
+                !histogram.data_points.is_empty()
+            }
+            Some(Data::Summary(summary)) => {
+                summary
+                    .data_points
+                    .retain(|point| self.in_range(point.time_unix_nano));
+                !summary.data_points.is_empty()
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::config::{parse_target, BackoffConfig, ExporterConfig};
+use crate::proxy::admin::AdminConfig;
+use crate::proxy::admission::AdmissionConfig;
+use crate::proxy::listener::ListenerConfig;
+use crate::proxy::processor::ProcessorConfig;
+use crate::proxy::routing::RoutingConfig;
+use crate::proxy::sink::FileSinkConfig;
+use crate::proxy::span_metrics::SpanMetricsConfig;
+use crate::proxy::tail_sampling::TailSamplingConfig;
+
+/// Path of the JSON file that `proxy-server` reads its configuration from.
+pub const PROXY_CONFIG: &str = "PROXY_CONFIG";
+
+/// Configuration of `proxy-server`.
+///
+/// ```json
+/// {
+///     "listeners": [{ "uds": { "path": "/tmp/proxy-server.sock" } }, { "http": { "address": "0.0.0.0:4318" } }],
+///     "file_sink": { "directory": "/var/log/proxy-server", "rotation": { "max_bytes": 104857600 } },
+///     "buffer": { "directory": "/var/lib/proxy-server/buffer" },
+///     "processors": [{ "inject_resource": { "host_name": true } }],
+///     "routing": { "path": "/etc/proxy-server/rules.json" },
+///     "tail_sampling": { "policies": ["error", { "probabilistic": { "percentage": 10 } }] },
+///     "span_metrics": { "interval_ms": 15000 },
+///     "request_metrics": { "labels": ["method", "origin"] },
+///     "admission": { "requests_per_sec": 100, "bytes_per_sec": 10485760, "max_in_flight": 64 },
+///     "admin": { "address": "127.0.0.1:8888" },
+///     "upstreams": [
+///         { "name": "collector", "endpoint": "http://collector:4317" },
+///         { "name": "backup", "endpoint": "http://backup:4318", "protocol": "http/protobuf" }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Where exports are accepted. Defaults to gRPC on `DEFAULT_SOCK`.
+    pub listeners: Vec<ListenerConfig>,
+    /// Writes every export to the local capture files.
+    pub file_sink: FileSinkConfig,
+    /// Every export is forwarded to each of these.
+    pub upstreams: Vec<UpstreamConfig>,
+    /// Queue the exports for the upstreams on disk instead of in memory.
+    pub buffer: Option<BufferConfig>,
+    /// Applied to every export, in order, before it is routed, written or forwarded.
+    pub processors: Vec<ProcessorConfig>,
+    /// Filter and route the exports with the rules in this file.
+    pub routing: Option<RoutingConfig>,
+    /// Hold traces until they are complete, and only pass on the ones that match a policy.
+    pub tail_sampling: Option<TailSamplingConfig>,
+    /// Generate request rate, error and duration metrics from the spans received.
+    pub span_metrics: Option<SpanMetricsConfig>,
+    pub request_metrics: RequestMetricsConfig,
+    pub admission: AdmissionConfig,
+    /// Serve `/healthz`, `/readyz` and `/stats` over HTTP.
+    pub admin: Option<AdminConfig>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listeners: vec![ListenerConfig::default()],
+            file_sink: FileSinkConfig::default(),
+            upstreams: Vec::new(),
+            buffer: None,
+            processors: Vec::new(),
+            routing: None,
+            tail_sampling: None,
+            span_metrics: None,
+            request_metrics: RequestMetricsConfig::default(),
+            admission: AdmissionConfig::default(),
+            admin: None,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Reads the file named by `PROXY_CONFIG`, or returns the default configuration, which only
+    /// writes the capture files, if it isn't set.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(PROXY_CONFIG) {
+            Ok(path) if !path.trim().is_empty() => Self::from_file(path.trim()),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid proxy config {}: {}", path.display(), e))
+    }
+}
+
+/// The labels of the metrics that `proxy-server` records about the gRPC requests it receives.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestMetricsConfig {
+    pub labels: Vec<RequestLabel>,
+}
+
+impl Default for RequestMetricsConfig {
+    fn default() -> Self {
+        Self {
+            labels: vec![
+                RequestLabel::Method,
+                RequestLabel::StatusCode,
+                RequestLabel::Origin,
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestLabel {
+    /// `rpc.service` and `rpc.method`, eg. `opentelemetry.proto.collector.trace.v1.TraceService`
+    /// and `Export`.
+    Method,
+    /// `rpc.grpc.status_code`.
+    StatusCode,
+    /// `origin`, the service that sent the request: the `x-origin` header if it is set, or else
+    /// the `service.name` of the first resource in the request.
+    Origin,
+}
+
+/// An OTLP endpoint that the proxy forwards to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Identifies the upstream in logs.
+    pub name: String,
+    /// Accepts `unix://` URIs, like `OTEL_EXPORTER_OTLP_ENDPOINT`.
+    pub endpoint: String,
+    /// `grpc` (the default), `http/protobuf` or `http/json`.
+    #[serde(default)]
+    pub protocol: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How many exports are queued in memory while the upstream is slow or retrying, or while
+    /// they wait to be written to the disk buffer. Exports received while the queue is full are
+    /// dropped.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+impl UpstreamConfig {
+    pub fn exporter_config(&self) -> Result<ExporterConfig> {
+        let mut config = ExporterConfig::default();
+        config.target = parse_target(
+            self.protocol.as_deref(),
+            Some(&self.endpoint),
+            &config.target,
+        )?;
+        config.timeout = Duration::from_millis(self.timeout_ms);
+        config.headers = self.headers.clone();
+        Ok(config)
+    }
+}
+
+/// Where the exports for the upstreams are queued on disk.
+///
+/// Each upstream gets its own subdirectory, named after it. While an export keeps failing with a
+/// retryable error it stays at the front of the queue, and is retried until it succeeds or is
+/// evicted, so that the queue is replayed in order once the upstream recovers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BufferConfig {
+    pub directory: PathBuf,
+    /// The size limit of each upstream's queue. The oldest exports are evicted beyond it.
+    #[serde(default = "default_buffer_max_bytes")]
+    pub max_bytes: u64,
+}
+
+/// How often a failed export is retried, if the upstream reports a retryable error. Exports
+/// buffered on disk are retried until they are evicted, so `max_attempts` doesn't apply to them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Including the first attempt.
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn backoff(&self) -> BackoffConfig {
+        BackoffConfig {
+            initial: Duration::from_millis(self.initial_backoff_ms),
+            max: Duration::from_millis(self.max_backoff_ms),
+            multiplier: 2,
+        }
+    }
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_queue_size() -> usize {
+    1024
+}
+
+fn default_buffer_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
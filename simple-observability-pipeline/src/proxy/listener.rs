@@ -0,0 +1,330 @@
+use std::future::Future;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use flate2::read::GzDecoder;
+use opentelemetry_proto::tonic::collector::logs::v1::logs_service_server::LogsService;
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_server::MetricsService;
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::TraceService;
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, UnixListener};
+use tonic::metadata::MetadataMap;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::{Code, Extensions, Status};
+
+use crate::config::DEFAULT_SOCK;
+
+/// The largest OTLP/HTTP body that is accepted unless `max_request_bytes` is set, the same as the
+/// default gRPC message limit.
+pub const MAX_HTTP_BODY: usize = 4 * 1024 * 1024;
+
+/// Where `proxy-server` accepts exports. Every listener feeds the same handlers.
+///
+/// ```json
+/// [
+///     { "uds": { "path": "/tmp/proxy-server.sock", "mode": "660", "internal": true } },
+///     { "grpc": { "address": "0.0.0.0:4317", "tls": { "cert": "server.pem", "key": "server.key" } } },
+///     { "http": { "address": "0.0.0.0:4318" } }
+/// ]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ListenerConfig {
+    /// OTLP/gRPC on a Unix domain socket. The socket file is replaced if it already exists, and
+    /// removed when the proxy shuts down.
+    Uds {
+        path: PathBuf,
+        /// The permissions of the socket file, in octal, eg. `660`.
+        #[serde(default)]
+        mode: Option<String>,
+        /// Whether the proxy's own telemetry is accepted here, so that exports tagged
+        /// `x-origin: proxy-server` are neither limited by admission control nor counted in the
+        /// request metrics. Anyone who can write to the socket can set the header, so `mode`
+        /// should keep other clients out. The header is never trusted on other listeners.
+        #[serde(default)]
+        internal: bool,
+    },
+    /// OTLP/gRPC over TCP.
+    Grpc {
+        address: SocketAddr,
+        #[serde(default)]
+        tls: Option<TlsConfig>,
+    },
+    /// OTLP/HTTP, with protobuf or JSON bodies, optionally gzipped.
+    Http { address: SocketAddr },
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig::Uds {
+            path: PathBuf::from(DEFAULT_SOCK),
+            mode: None,
+            internal: true,
+        }
+    }
+}
+
+/// PEM files for serving gRPC over TLS.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Only accept clients with a certificate signed by this CA.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn server_tls_config(&self) -> Result<ServerTlsConfig> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))
+        };
+
+        let mut config = ServerTlsConfig::new()
+            .identity(Identity::from_pem(read(&self.cert)?, read(&self.key)?));
+        if let Some(client_ca) = &self.client_ca {
+            config = config.client_ca_root(Certificate::from_pem(read(client_ca)?));
+        }
+        Ok(config)
+    }
+}
+
+/// A socket file that is removed when this is dropped.
+#[derive(Debug)]
+pub struct SocketFile {
+    path: PathBuf,
+}
+
+impl SocketFile {
+    /// Binds a Unix domain socket at `path`, replacing any socket left behind by a previous run.
+    /// Must be called from a Tokio runtime.
+    pub fn bind(path: &Path, mode: Option<&str>) -> Result<(UnixListener, Self)> {
+        let mode = mode
+            .map(|mode| {
+                u32::from_str_radix(mode, 8).map_err(|_| anyhow!("Invalid socket mode: {}", mode))
+            })
+            .transpose()?;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!("{} exists and is not a socket", path.display()));
+            }
+            std::fs::remove_file(path)
+                .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("Failed to bind {}: {}", path.display(), e))?;
+        let socket = Self {
+            path: path.to_path_buf(),
+        };
+
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .map_err(|e| anyhow!("Failed to set the mode of {}: {}", path.display(), e))?;
+        }
+        Ok((listener, socket))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Serves `router`, usually an [`http_router`], on `listener` until `shutdown` completes.
+pub async fn serve_http(
+    listener: TcpListener,
+    router: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// The OTLP/HTTP endpoints, `/v1/traces`, `/v1/metrics` and `/v1/logs`, which pass every export
+/// to the gRPC handlers of `service`. Bodies over `max_body` bytes, before or after they are
+/// decompressed, are turned away with 413.
+///
+/// Every response that an endpoint makes carries the gRPC [`Code`] of the export in its
+/// extensions, along with the extensions of the handler's response, so that layers can measure
+/// the exports as they would gRPC requests.
+pub fn http_router<S>(service: S, max_body: usize) -> Router
+where
+    S: TraceService + MetricsService + LogsService + Clone,
+{
+    Router::new()
+        .route(
+            "/v1/traces",
+            post(
+                move |State(service): State<S>, headers: HeaderMap, body: Bytes| async move {
+                    export(headers, body, max_body, |request| async move {
+                        TraceService::export(&service, request).await
+                    })
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/v1/metrics",
+            post(
+                move |State(service): State<S>, headers: HeaderMap, body: Bytes| async move {
+                    export(headers, body, max_body, |request| async move {
+                        MetricsService::export(&service, request).await
+                    })
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/v1/logs",
+            post(
+                move |State(service): State<S>, headers: HeaderMap, body: Bytes| async move {
+                    export(headers, body, max_body, |request| async move {
+                        LogsService::export(&service, request).await
+                    })
+                    .await
+                },
+            ),
+        )
+        .layer(DefaultBodyLimit::max(max_body))
+        .with_state(service)
+}
+
+/// Decodes an OTLP/HTTP request, passes it to `handle` with the HTTP headers as its metadata,
+/// and encodes the response in the same format as the request.
+async fn export<Req, Resp, F, Fut>(
+    headers: HeaderMap,
+    body: Bytes,
+    max_body: usize,
+    handle: F,
+) -> Response
+where
+    Req: Message + Default + DeserializeOwned,
+    Resp: Message + Serialize,
+    F: FnOnce(tonic::Request<Req>) -> Fut,
+    Fut: Future<Output = Result<tonic::Response<Resp>, Status>>,
+{
+    let json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    let body = match decompress(&headers, body, max_body) {
+        Ok(body) => body,
+        Err(status) => return failure(status),
+    };
+    let request = match json {
+        true => serde_json::from_slice(&body).map_err(|e| e.to_string()),
+        false => Req::decode(body.as_ref()).map_err(|e| e.to_string()),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return failure(Status::invalid_argument(e)),
+    };
+
+    let metadata = MetadataMap::from_headers(headers);
+    let request = tonic::Request::from_parts(metadata, Extensions::default(), request);
+    let (response, extensions) = match handle(request).await {
+        Ok(response) => {
+            let (_, response, extensions) = response.into_parts();
+            (response, extensions)
+        }
+        Err(status) => return failure(status),
+    };
+
+    let mut response = encode_response(json, &response);
+    response.extensions_mut().extend(extensions);
+    response.extensions_mut().insert(Code::Ok);
+    response
+}
+
+/// The HTTP response to a failed export.
+fn failure(status: Status) -> Response {
+    let mut response = (http_status(status.code()), status.message().to_string()).into_response();
+    response.extensions_mut().insert(status.code());
+    response
+}
+
+/// Encodes the reply to an export in the same format as the request.
+fn encode_response<Resp: Message + Serialize>(json: bool, response: &Resp) -> Response {
+    match json {
+        true => match serde_json::to_vec(response) {
+            Ok(body) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                )],
+                body,
+            )
+                .into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        false => (
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/x-protobuf"),
+            )],
+            response.encode_to_vec(),
+        )
+            .into_response(),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn decompress(headers: &HeaderMap, body: Bytes, max_body: usize) -> Result<Bytes, Status> {
+    match headers
+        .get(header::CONTENT_ENCODING)
+        .map(HeaderValue::as_bytes)
+    {
+        None | Some(b"identity") => Ok(body),
+        Some(b"gzip") => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(body.as_ref())
+                .take(max_body as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            if decompressed.len() > max_body {
+                return Err(Status::out_of_range(format!(
+                    "Body is larger than {} bytes",
+                    max_body
+                )));
+            }
+            Ok(decompressed.into())
+        }
+        Some(encoding) => Err(Status::invalid_argument(format!(
+            "Unsupported content encoding: {}",
+            String::from_utf8_lossy(encoding)
+        ))),
+    }
+}
+
+/// The HTTP status for a gRPC status, as OTLP/HTTP clients expect them for retries.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::InvalidArgument => StatusCode::BAD_REQUEST,
+        Code::OutOfRange => StatusCode::PAYLOAD_TOO_LARGE,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::common::v1::{AnyValue, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_semantic_conventions::resource::HOST_NAME;
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::proxy::ExportRequest;
+
+/// A step of the attribute processing that the proxy applies to every export before writing or
+/// forwarding it. The steps run in the order they are configured.
+///
+/// ```json
+/// [
+///     { "inject_resource": { "host_name": true, "attributes": { "deployment.environment": "prod" } } },
+///     { "rename": { "from": "user_id", "to": "enduser.id" } },
+///     { "hash": { "keys": "enduser\\.id" } },
+///     { "redact": { "values": "[\\w.+-]+@[\\w-]+\\.[\\w.]+", "replacement": "<email>" } },
+///     { "delete": { "keys": "http\\.request\\.header\\..*", "target": "records" } },
+///     { "truncate": { "max_length": 1024 } }
+/// ]
+/// ```
+///
+/// Key and value patterns are regular expressions. Key patterns must match the whole key, value
+/// patterns can match any part of a value.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ProcessorConfig {
+    /// Sets an attribute to a string value.
+    Add {
+        key: String,
+        value: String,
+        /// Replace the attribute if it is already set.
+        #[serde(default)]
+        overwrite: bool,
+        #[serde(default)]
+        target: Target,
+    },
+    /// Renames an attribute, replacing any attribute that already has the new name.
+    Rename {
+        from: String,
+        to: String,
+        #[serde(default)]
+        target: Target,
+    },
+    Delete {
+        keys: String,
+        #[serde(default)]
+        target: Target,
+    },
+    /// Replaces string values with their SHA-256 hash, as hex, so that they can still be
+    /// correlated but not read.
+    Hash(SelectorConfig),
+    /// Replaces the parts of string values that match `values`, or the whole value if it isn't
+    /// set, with `replacement`.
+    Redact {
+        #[serde(default)]
+        keys: Option<String>,
+        #[serde(default)]
+        values: Option<String>,
+        #[serde(default)]
+        target: Target,
+        #[serde(default = "default_replacement")]
+        replacement: String,
+    },
+    /// Adds resource attributes, without replacing the ones that are set by the sender.
+    InjectResource {
+        /// Adds `host.name`, set to the host name of the proxy.
+        #[serde(default)]
+        host_name: bool,
+        #[serde(default)]
+        attributes: HashMap<String, String>,
+    },
+    /// Shortens string values to at most `max_length` characters.
+    Truncate {
+        max_length: usize,
+        #[serde(default)]
+        keys: Option<String>,
+        #[serde(default)]
+        target: Target,
+    },
+}
+
+/// Selects the string attributes that a processor changes, by key, value or both. At least one of
+/// the patterns must be set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SelectorConfig {
+    #[serde(default)]
+    pub keys: Option<String>,
+    #[serde(default)]
+    pub values: Option<String>,
+    #[serde(default)]
+    pub target: Target,
+}
+
+/// Which attributes a processor applies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// Resource attributes.
+    Resource,
+    /// Attributes of spans, span events and links, log records and metric data points.
+    Records,
+    #[default]
+    All,
+}
+
+/// The compiled processors.
+#[derive(Debug, Default)]
+pub struct Processors {
+    processors: Vec<Processor>,
+}
+
+#[derive(Debug)]
+enum Processor {
+    Add {
+        attribute: KeyValue,
+        overwrite: bool,
+        target: Target,
+    },
+    Rename {
+        from: String,
+        to: String,
+        target: Target,
+    },
+    Delete {
+        keys: Regex,
+        target: Target,
+    },
+    Hash(Selector),
+    Redact {
+        selector: Selector,
+        replacement: String,
+    },
+    InjectResource(Vec<KeyValue>),
+    Truncate {
+        max_length: usize,
+        keys: Option<Regex>,
+        target: Target,
+    },
+}
+
+#[derive(Debug)]
+struct Selector {
+    keys: Option<Regex>,
+    values: Option<Regex>,
+    target: Target,
+}
+
+impl Processors {
+    pub fn new(configs: &[ProcessorConfig]) -> Result<Self> {
+        let processors = configs
+            .iter()
+            .enumerate()
+            .map(|(i, config)| {
+                Processor::new(config).map_err(|e| anyhow!("Invalid processor #{}: {}", i + 1, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { processors })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    pub fn process(&self, request: &mut ExportRequest) {
+        for processor in &self.processors {
+            processor.process(request);
+        }
+    }
+}
+
+impl Processor {
+    fn new(config: &ProcessorConfig) -> Result<Self> {
+        Ok(match config {
+            ProcessorConfig::Add {
+                key,
+                value,
+                overwrite,
+                target,
+            } => Processor::Add {
+                attribute: string_attribute(key, value),
+                overwrite: *overwrite,
+                target: *target,
+            },
+            ProcessorConfig::Rename { from, to, .. } if from == to => {
+                bail!("Renaming {} to itself would delete it", from)
+            }
+            ProcessorConfig::Rename { from, to, target } => Processor::Rename {
+                from: from.clone(),
+                to: to.clone(),
+                target: *target,
+            },
+            ProcessorConfig::Delete { keys, target } => Processor::Delete {
+                keys: key_regex(keys)?,
+                target: *target,
+            },
+            ProcessorConfig::Hash(selector) => Processor::Hash(Selector::new(
+                selector.keys.as_deref(),
+                selector.values.as_deref(),
+                selector.target,
+            )?),
+            ProcessorConfig::Redact {
+                keys,
+                values,
+                target,
+                replacement,
+            } => Processor::Redact {
+                selector: Selector::new(keys.as_deref(), values.as_deref(), *target)?,
+                replacement: replacement.clone(),
+            },
+            ProcessorConfig::InjectResource {
+                host_name,
+                attributes,
+            } => {
+                let mut injected = attributes
+                    .iter()
+                    .map(|(key, value)| string_attribute(key, value))
+                    .collect::<Vec<_>>();
+                if *host_name {
+                    let name = hostname::get()
+                        .map_err(|e| anyhow!("Failed to get the host name: {}", e))?;
+                    injected.push(string_attribute(HOST_NAME, &name.to_string_lossy()));
+                }
+                Processor::InjectResource(injected)
+            }
+            ProcessorConfig::Truncate {
+                max_length,
+                keys,
+                target,
+            } => Processor::Truncate {
+                max_length: *max_length,
+                keys: keys.as_deref().map(key_regex).transpose()?,
+                target: *target,
+            },
+        })
+    }
+
+    fn process(&self, request: &mut ExportRequest) {
+        match self {
+            Processor::Add {
+                attribute,
+                overwrite,
+                target,
+            } => for_each_attributes(request, *target, |attributes| {
+                match attributes.iter_mut().find(|a| a.key == attribute.key) {
+                    Some(existing) if *overwrite => existing.value = attribute.value.clone(),
+                    Some(_) => {}
+                    None => attributes.push(attribute.clone()),
+                }
+            }),
+            Processor::Rename { from, to, target } => {
+                for_each_attributes(request, *target, |attributes| {
+                    if attributes.iter().any(|a| a.key == *from) {
+                        attributes.retain(|a| a.key != *to);
+                        for attribute in attributes.iter_mut().filter(|a| a.key == *from) {
+                            attribute.key = to.clone();
+                        }
+                    }
+                })
+            }
+            Processor::Delete { keys, target } => {
+                for_each_attributes(request, *target, |attributes| {
+                    attributes.retain(|a| !keys.is_match(&a.key))
+                })
+            }
+            Processor::Hash(selector) => selector.apply(request, |value| {
+                *value = format!("{:x}", Sha256::digest(value.as_bytes()));
+            }),
+            Processor::Redact {
+                selector,
+                replacement,
+            } => selector.apply(request, |value| {
+                *value = match &selector.values {
+                    Some(values) => values.replace_all(value, replacement.as_str()).into_owned(),
+                    None => replacement.clone(),
+                };
+            }),
+            Processor::InjectResource(injected) => {
+                for_each_attributes(request, Target::Resource, |attributes| {
+                    for attribute in injected {
+                        if !attributes.iter().any(|a| a.key == attribute.key) {
+                            attributes.push(attribute.clone());
+                        }
+                    }
+                })
+            }
+            Processor::Truncate {
+                max_length,
+                keys,
+                target,
+            } => for_each_attributes(request, *target, |attributes| {
+                for attribute in attributes.iter_mut() {
+                    if keys
+                        .as_ref()
+                        .is_some_and(|keys| !keys.is_match(&attribute.key))
+                    {
+                        continue;
+                    }
+                    if let Some(value) = string_value(attribute) {
+                        if let Some((end, _)) = value.char_indices().nth(*max_length) {
+                            value.truncate(end);
+                        }
+                    }
+                }
+            }),
+        }
+    }
+}
+
+impl Selector {
+    fn new(keys: Option<&str>, values: Option<&str>, target: Target) -> Result<Self> {
+        if keys.is_none() && values.is_none() {
+            return Err(anyhow!("Either keys or values must be set"));
+        }
+        Ok(Self {
+            keys: keys.map(key_regex).transpose()?,
+            values: values
+                .map(|values| {
+                    Regex::new(values).map_err(|e| anyhow!("Invalid pattern {}: {}", values, e))
+                })
+                .transpose()?,
+            target,
+        })
+    }
+
+    /// Calls `f` with every string value that is selected.
+    fn apply(&self, request: &mut ExportRequest, f: impl Fn(&mut String)) {
+        for_each_attributes(request, self.target, |attributes| {
+            for attribute in attributes.iter_mut() {
+                if self
+                    .keys
+                    .as_ref()
+                    .is_some_and(|keys| !keys.is_match(&attribute.key))
+                {
+                    continue;
+                }
+                let Some(value) = string_value(attribute) else {
+                    continue;
+                };
+                if self
+                    .values
+                    .as_ref()
+                    .is_some_and(|values| !values.is_match(value))
+                {
+                    continue;
+                }
+                f(value);
+            }
+        })
+    }
+}
+
+/// Calls `f` with every list of attributes in `request` that belongs to `target`. Resources that
+/// aren't set are created, so that attributes can be added to them.
+fn for_each_attributes(
+    request: &mut ExportRequest,
+    target: Target,
+    mut f: impl FnMut(&mut Vec<KeyValue>),
+) {
+    let resources = target != Target::Records;
+    let records = target != Target::Resource;
+
+    let mut resource = |resource: &mut Option<Resource>| {
+        if resources {
+            f(&mut resource.get_or_insert_with(Resource::default).attributes);
+        }
+    };
+
+    match request {
+        ExportRequest::Traces(request) => {
+            for resource_spans in &mut request.resource_spans {
+                resource(&mut resource_spans.resource);
+            }
+            if !records {
+                return;
+            }
+            let spans = request
+                .resource_spans
+                .iter_mut()
+                .flat_map(|r| &mut r.scope_spans)
+                .flat_map(|s| &mut s.spans);
+            for span in spans {
+                f(&mut span.attributes);
+                for event in &mut span.events {
+                    f(&mut event.attributes);
+                }
+                for link in &mut span.links {
+                    f(&mut link.attributes);
+                }
+            }
+        }
+        ExportRequest::Metrics(request) => {
+            for resource_metrics in &mut request.resource_metrics {
+                resource(&mut resource_metrics.resource);
+            }
+            if !records {
+                return;
+            }
+            let data = request
+                .resource_metrics
+                .iter_mut()
+                .flat_map(|r| &mut r.scope_metrics)
+                .flat_map(|s| &mut s.metrics)
+                .filter_map(|m| m.data.as_mut());
+            for data in data {
+                match data {
+                    Data::Gauge(gauge) => gauge
+                        .data_points
+                        .iter_mut()
+                        .for_each(|p| f(&mut p.attributes)),
+                    Data::Sum(sum) => sum
+                        .data_points
+                        .iter_mut()
+                        .for_each(|p| f(&mut p.attributes)),
+                    Data::Histogram(histogram) => histogram
+                        .data_points
+                        .iter_mut()
+                        .for_each(|p| f(&mut p.attributes)),
+                    Data::ExponentialHistogram(histogram) => histogram
+                        .data_points
+                        .iter_mut()
+                        .for_each(|p| f(&mut p.attributes)),
+                    Data::Summary(summary) => summary
+                        .data_points
+                        .iter_mut()
+                        .for_each(|p| f(&mut p.attributes)),
+                }
+            }
+        }
+        ExportRequest::Logs(request) => {
+            for resource_logs in &mut request.resource_logs {
+                resource(&mut resource_logs.resource);
+            }
+            if !records {
+                return;
+            }
+            let records = request
+                .resource_logs
+                .iter_mut()
+                .flat_map(|r| &mut r.scope_logs)
+                .flat_map(|s| &mut s.log_records);
+            for record in records {
+                f(&mut record.attributes);
+            }
+        }
+    }
+}
+
+fn string_value(attribute: &mut KeyValue) -> Option<&mut String> {
+    match attribute.value.as_mut()?.value.as_mut()? {
+        Value::StringValue(value) => Some(value),
+        _ => None,
+    }
+}
+
+pub(crate) fn string_attribute(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn key_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| anyhow!("Invalid pattern {}: {}", pattern, e))
+}
+
+fn default_replacement() -> String {
+    "<redacted>".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::proxy::attribute;
+    use crate::proxy::tests::{span, traces};
+
+    fn processors(configs: serde_json::Value) -> Result<Processors> {
+        Processors::new(&serde_json::from_value::<Vec<ProcessorConfig>>(configs)?)
+    }
+
+    #[test]
+    fn rename_replaces_the_new_name() {
+        let processors = processors(json!([
+            { "add": { "key": "team", "value": "payments" } },
+            { "rename": { "from": "service.name", "to": "team", "target": "resource" } },
+        ]))
+        .unwrap();
+        let mut request = traces("checkout", vec![span(1, "a")]);
+        processors.process(&mut request);
+
+        let ExportRequest::Traces(request) = request else {
+            unreachable!()
+        };
+        let resource = request.resource_spans[0].resource.as_ref().unwrap();
+        assert_eq!(resource.attributes.len(), 1);
+        assert_eq!(attribute(&resource.attributes, "team").unwrap(), "checkout");
+        let span = &request.resource_spans[0].scope_spans[0].spans[0];
+        assert_eq!(attribute(&span.attributes, "team").unwrap(), "payments");
+    }
+
+    #[test]
+    fn renaming_to_the_same_key_is_rejected() {
+        let renamed = processors(json!([{ "rename": { "from": "user_id", "to": "user_id" } }]));
+        assert!(renamed.is_err());
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use opentelemetry_proto::tonic::common::v1::KeyValue;
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::proxy::{attribute, ExportRequest};
+
+/// Where the routing rules are read from, and how often the file is checked for changes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+/// The contents of the routing rules file.
+///
+/// ```json
+/// {
+///     "rules": [
+///         { "name": "no-debug-logs", "match": { "max_severity": "DEBUG" }, "action": "drop" },
+///         { "match": { "span_name": "GET /health" }, "action": "drop" },
+///         {
+///             "match": { "resource": { "service.name": "checkout|payments" }, "metric_name": "http\\..*" },
+///             "action": { "route": { "upstreams": ["collector"] } }
+///         }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RulesFile {
+    pub rules: Vec<RuleConfig>,
+}
+
+/// Spans, metrics and log records are matched against the rules in order, and the first rule that
+/// matches decides what happens to them. Anything that no rule matches goes to the file sink and
+/// every upstream, as if there were no rules.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    /// Identifies the rule in logs.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(rename = "match", default)]
+    pub matcher: MatchConfig,
+    pub action: Action,
+}
+
+/// Every condition that is set must hold for a rule to match. Patterns are regular expressions
+/// that must match the whole value.
+///
+/// `span_name`, `metric_name` and the severities only match spans, metrics and log records
+/// respectively, so at most one kind of them can be set on a rule. A rule with only `resource`
+/// matches every signal.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchConfig {
+    /// Resource attributes, by key. Attributes that aren't set never match.
+    pub resource: HashMap<String, String>,
+    pub span_name: Option<String>,
+    pub metric_name: Option<String>,
+    /// Log records at this severity or above, eg. `WARN`.
+    pub min_severity: Option<SeverityLevel>,
+    /// Log records at this severity or below. Records without a severity never match.
+    pub max_severity: Option<SeverityLevel>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Drop,
+    Route(Route),
+}
+
+/// Sends the matching data only to these destinations, instead of to all of them.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Route {
+    pub file_sink: bool,
+    /// Names of upstreams from the proxy config.
+    pub upstreams: Vec<String>,
+}
+
+impl Route {
+    pub fn includes_upstream(&self, name: &str) -> bool {
+        self.upstreams.iter().any(|upstream| upstream == name)
+    }
+}
+
+/// One of the OTel log severities, without the trailing number, eg. `INFO` covers `INFO` to
+/// `INFO4`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SeverityLevel(i32);
+
+impl SeverityLevel {
+    /// The lowest severity number of the level.
+    pub(crate) fn min(self) -> i32 {
+        self.0
+    }
+
+    /// The highest severity number of the level.
+    pub(crate) fn max(self) -> i32 {
+        self.0 + 3
+    }
+}
+
+impl TryFrom<String> for SeverityLevel {
+    type Error = anyhow::Error;
+
+    fn try_from(level: String) -> Result<Self> {
+        let number = match level.to_ascii_uppercase().as_str() {
+            "TRACE" => 1,
+            "DEBUG" => 5,
+            "INFO" => 9,
+            "WARN" => 13,
+            "ERROR" => 17,
+            "FATAL" => 21,
+            _ => bail!("Unknown severity: {}", level),
+        };
+        Ok(Self(number))
+    }
+}
+
+/// A compiled set of rules.
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    resource: Vec<(String, Regex)>,
+    item: ItemMatcher,
+    action: Action,
+}
+
+#[derive(Debug)]
+enum ItemMatcher {
+    Any,
+    SpanName(Regex),
+    MetricName(Regex),
+    Severity { min: i32, max: i32 },
+}
+
+/// A span, metric or log record.
+enum Item<'a> {
+    Span(&'a str),
+    Metric(&'a str),
+    Log(i32),
+}
+
+/// What happens to an item: the index of the rule that matched it, if any.
+type Decision = Option<usize>;
+
+impl RuleSet {
+    /// Compiles the rules, checking that every upstream they route to is one of `upstreams`.
+    pub fn new(file: &RulesFile, upstreams: &[&str]) -> Result<Self> {
+        let rules = file
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let name = rule.name.clone().unwrap_or_else(|| format!("#{}", i + 1));
+                Rule::new(rule, upstreams).map_err(|e| anyhow!("Invalid rule {}: {}", name, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn from_file(path: &Path, upstreams: &[&str]) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let file: RulesFile = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid routing rules {}: {}", path.display(), e))?;
+        Self::new(&file, upstreams)
+    }
+
+    /// Splits `request` by where its spans, metrics or log records should go, leaving out the
+    /// dropped ones. A `None` route means the default destinations, ie. everywhere.
+    pub fn route(&self, request: ExportRequest) -> Vec<(Option<&Route>, ExportRequest)> {
+        if self.rules.is_empty() {
+            return vec![(None, request)];
+        }
+
+        let decisions = self.decide(&request);
+        let mut groups: Vec<Decision> = Vec::new();
+        for decision in &decisions {
+            if !groups.contains(decision) {
+                groups.push(*decision);
+            }
+        }
+
+        match groups[..] {
+            [] => vec![(None, request)],
+            // Only copy the request if it has to be split.
+            [group] => self
+                .destination(group)
+                .map(|route| (route, request))
+                .into_iter()
+                .collect(),
+            _ => groups
+                .iter()
+                .filter_map(|group| {
+                    let route = self.destination(*group)?;
+                    let request = retain(request.clone(), &decisions, |decision| decision == group);
+                    Some((route, request))
+                })
+                .collect(),
+        }
+    }
+
+    /// Where the items with `decision` go, or `None` if they are dropped.
+    fn destination(&self, decision: Decision) -> Option<Option<&Route>> {
+        match decision {
+            None => Some(None),
+            Some(rule) => match &self.rules[rule].action {
+                Action::Drop => None,
+                Action::Route(route) => Some(Some(route)),
+            },
+        }
+    }
+
+    /// Decides every item in `request`, in the order they appear in it.
+    fn decide(&self, request: &ExportRequest) -> Vec<Decision> {
+        let mut decisions = Vec::new();
+        match request {
+            ExportRequest::Traces(request) => {
+                for resource_spans in &request.resource_spans {
+                    let resource = attributes(&resource_spans.resource);
+                    for scope_spans in &resource_spans.scope_spans {
+                        decisions.extend(
+                            scope_spans
+                                .spans
+                                .iter()
+                                .map(|span| self.decide_item(resource, &Item::Span(&span.name))),
+                        );
+                    }
+                }
+            }
+            ExportRequest::Metrics(request) => {
+                for resource_metrics in &request.resource_metrics {
+                    let resource = attributes(&resource_metrics.resource);
+                    for scope_metrics in &resource_metrics.scope_metrics {
+                        decisions.extend(
+                            scope_metrics.metrics.iter().map(|metric| {
+                                self.decide_item(resource, &Item::Metric(&metric.name))
+                            }),
+                        );
+                    }
+                }
+            }
+            ExportRequest::Logs(request) => {
+                for resource_logs in &request.resource_logs {
+                    let resource = attributes(&resource_logs.resource);
+                    for scope_logs in &resource_logs.scope_logs {
+                        decisions.extend(scope_logs.log_records.iter().map(|record| {
+                            self.decide_item(resource, &Item::Log(record.severity_number))
+                        }));
+                    }
+                }
+            }
+        }
+        decisions
+    }
+
+    fn decide_item(&self, resource: &[KeyValue], item: &Item) -> Decision {
+        self.rules
+            .iter()
+            .position(|rule| rule.matches(resource, item))
+    }
+}
+
+impl Rule {
+    fn new(config: &RuleConfig, upstreams: &[&str]) -> Result<Self> {
+        let matcher = &config.matcher;
+        let resource = matcher
+            .resource
+            .iter()
+            .map(|(key, pattern)| Ok((key.clone(), pattern_regex(pattern)?)))
+            .collect::<Result<_>>()?;
+
+        let has_severity = matcher.min_severity.is_some() || matcher.max_severity.is_some();
+        let item = match (&matcher.span_name, &matcher.metric_name, has_severity) {
+            (None, None, false) => ItemMatcher::Any,
+            (Some(name), None, false) => ItemMatcher::SpanName(pattern_regex(name)?),
+            (None, Some(name), false) => ItemMatcher::MetricName(pattern_regex(name)?),
+            (None, None, true) => ItemMatcher::Severity {
+                min: matcher.min_severity.map_or(1, SeverityLevel::min),
+                max: matcher.max_severity.map_or(i32::MAX, SeverityLevel::max),
+            },
+            _ => bail!("span_name, metric_name and the severities can't be combined"),
+        };
+
+        if let Action::Route(route) = &config.action {
+            if let Some(unknown) = route
+                .upstreams
+                .iter()
+                .find(|u| !upstreams.contains(&u.as_str()))
+            {
+                bail!("Unknown upstream: {}", unknown);
+            }
+        }
+
+        Ok(Self {
+            resource,
+            item,
+            action: config.action.clone(),
+        })
+    }
+
+    fn matches(&self, resource: &[KeyValue], item: &Item) -> bool {
+        let item_matches = match (&self.item, item) {
+            (ItemMatcher::Any, _) => true,
+            (ItemMatcher::SpanName(regex), Item::Span(name)) => regex.is_match(name),
+            (ItemMatcher::MetricName(regex), Item::Metric(name)) => regex.is_match(name),
+            (ItemMatcher::Severity { min, max }, Item::Log(severity)) => {
+                (*min..=*max).contains(severity)
+            }
+            _ => false,
+        };
+
+        item_matches
+            && self.resource.iter().all(|(key, regex)| {
+                attribute(resource, key).is_some_and(|value| regex.is_match(&value))
+            })
+    }
+}
+
+/// Routing rules that are reloaded whenever their file changes.
+#[derive(Debug)]
+pub struct RoutingRules {
+    current: RwLock<Arc<RuleSet>>,
+}
+
+impl RoutingRules {
+    /// Loads the rules, and starts watching the file for changes. Must be called from a Tokio
+    /// runtime.
+    ///
+    /// If the rules can't be loaded on a reload, eg. because the file is invalid, the previous
+    /// rules stay in place.
+    pub fn watch(config: &RoutingConfig, upstreams: Vec<String>) -> Result<Arc<Self>> {
+        let names = upstreams.iter().map(String::as_str).collect::<Vec<_>>();
+        let rules = Arc::new(Self {
+            current: RwLock::new(Arc::new(RuleSet::from_file(&config.path, &names)?)),
+        });
+
+        let path = config.path.clone();
+        let interval = Duration::from_secs(config.reload_interval_secs.max(1));
+        let watched = Arc::downgrade(&rules);
+        tokio::spawn(async move {
+            let mut last_modified = modified(&path);
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(rules) = watched.upgrade() else {
+                    return;
+                };
+
+                let modified = modified(&path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                let names = upstreams.iter().map(String::as_str).collect::<Vec<_>>();
+                match RuleSet::from_file(&path, &names) {
+                    Ok(set) => {
+                        *rules.current.write().expect("routing rules poisoned") = Arc::new(set);
+                        eprintln!("Reloaded routing rules from {}", path.display());
+                    }
+                    Err(e) => eprintln!("Keeping the previous routing rules: {}", e),
+                }
+            }
+        });
+
+        Ok(rules)
+    }
+
+    /// The rules as of now. Requests that are being routed keep the rules they started with.
+    pub fn current(&self) -> Arc<RuleSet> {
+        self.current.read().expect("routing rules poisoned").clone()
+    }
+}
+
+/// Keeps the items of `request` whose decision satisfies `keep`, and removes the resources and
+/// scopes left empty. `decisions` must be in the order returned by [`RuleSet::decide`].
+fn retain(
+    mut request: ExportRequest,
+    decisions: &[Decision],
+    keep: impl Fn(&Decision) -> bool,
+) -> ExportRequest {
+    let mut decisions = decisions.iter();
+    let mut next = || keep(decisions.next().expect("a decision per item"));
+
+    match &mut request {
+        ExportRequest::Traces(request) => {
+            for resource_spans in &mut request.resource_spans {
+                for scope_spans in &mut resource_spans.scope_spans {
+                    scope_spans.spans.retain(|_| next());
+                }
+                resource_spans
+                    .scope_spans
+                    .retain(|scope| !scope.spans.is_empty());
+            }
+            request
+                .resource_spans
+                .retain(|resource| !resource.scope_spans.is_empty());
+        }
+        ExportRequest::Metrics(request) => {
+            for resource_metrics in &mut request.resource_metrics {
+                for scope_metrics in &mut resource_metrics.scope_metrics {
+                    scope_metrics.metrics.retain(|_| next());
+                }
+                resource_metrics
+                    .scope_metrics
+                    .retain(|scope| !scope.metrics.is_empty());
+            }
+            request
+                .resource_metrics
+                .retain(|resource| !resource.scope_metrics.is_empty());
+        }
+        ExportRequest::Logs(request) => {
+            for resource_logs in &mut request.resource_logs {
+                for scope_logs in &mut resource_logs.scope_logs {
+                    scope_logs.log_records.retain(|_| next());
+                }
+                resource_logs
+                    .scope_logs
+                    .retain(|scope| !scope.log_records.is_empty());
+            }
+            request
+                .resource_logs
+                .retain(|resource| !resource.scope_logs.is_empty());
+        }
+    }
+    request
+}
+
+fn attributes(resource: &Option<Resource>) -> &[KeyValue] {
+    resource
+        .as_ref()
+        .map_or(&[], |resource| resource.attributes.as_slice())
+}
+
+fn pattern_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| anyhow!("Invalid pattern {}: {}", pattern, e))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn default_reload_interval_secs() -> u64 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use serde_json::json;
+
+    use super::*;
+    use crate::proxy::tests::{log, logs, metrics, names, span, traces};
+
+    fn rules(rules: serde_json::Value) -> Result<RuleSet> {
+        let file: RulesFile = serde_json::from_value(json!({ "rules": rules }))?;
+        RuleSet::new(&file, &["collector", "backup"])
+    }
+
+    /// The names in each group, and whether it goes to the file sink and which upstreams.
+    fn groups(routed: &[(Option<&Route>, ExportRequest)]) -> Vec<(Vec<String>, String)> {
+        routed
+            .iter()
+            .map(|(route, request)| {
+                let route = match route {
+                    None => "default".to_string(),
+                    Some(route) => format!("file={} {:?}", route.file_sink, route.upstreams),
+                };
+                (names(request), route)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn severity_levels_cover_their_four_numbers() {
+        let info = SeverityLevel::try_from("info".to_string()).unwrap();
+        assert_eq!((info.min(), info.max()), (9, 12));
+        let fatal = SeverityLevel::try_from("FATAL".to_string()).unwrap();
+        assert_eq!((fatal.min(), fatal.max()), (21, 24));
+        assert!(SeverityLevel::try_from("VERBOSE".to_string()).is_err());
+
+        let rules = rules(json!([
+            { "match": { "min_severity": "WARN", "max_severity": "ERROR" }, "action": "drop" },
+        ]))
+        .unwrap();
+        let records = [12, 13, 20, 21]
+            .into_iter()
+            .map(|severity| log(None, severity, &severity.to_string()))
+            .collect();
+        let routed = rules.route(logs("a", records));
+        assert_eq!(
+            groups(&routed),
+            [(vec!["12".into(), "21".into()], "default".into())]
+        );
+    }
+
+    #[test]
+    fn splits_by_the_first_matching_rule() {
+        let rules = rules(json!([
+            { "match": { "span_name": "GET /health" }, "action": "drop" },
+            {
+                "match": { "resource": { "service.name": "checkout|payments" } },
+                "action": { "route": { "file_sink": true, "upstreams": ["collector"] } }
+            },
+            { "match": { "span_name": "db.*" }, "action": { "route": { "upstreams": ["backup"] } } },
+        ]))
+        .unwrap();
+
+        let routed = rules.route(traces(
+            "checkout",
+            vec![span(1, "GET /health"), span(1, "db"), span(1, "GET /cart")],
+        ));
+        assert_eq!(
+            groups(&routed),
+            [(
+                vec!["db".into(), "GET /cart".into()],
+                r#"file=true ["collector"]"#.into()
+            )]
+        );
+
+        let routed = rules.route(traces(
+            "checkout2",
+            vec![
+                span(1, "db.query"),
+                span(1, "GET /health"),
+                span(1, "GET /"),
+            ],
+        ));
+        assert_eq!(
+            groups(&routed),
+            [
+                (vec!["db.query".into()], r#"file=false ["backup"]"#.into()),
+                (vec!["GET /".into()], "default".into()),
+            ]
+        );
+
+        assert!(rules
+            .route(traces("a", vec![span(1, "GET /health")]))
+            .is_empty());
+    }
+
+    #[test]
+    fn metric_rules_only_match_metrics() {
+        let rules = rules(json!([
+            { "match": { "metric_name": "http\\..*" }, "action": "drop" },
+        ]))
+        .unwrap();
+        let routed = rules.route(metrics("a", &["http.duration", "cpu"]));
+        assert_eq!(groups(&routed), [(vec!["cpu".into()], "default".into())]);
+        let routed = rules.route(traces("a", vec![span(1, "http.duration")]));
+        assert_eq!(groups(&routed).len(), 1);
+    }
+
+    #[test]
+    fn splitting_removes_empty_resources() {
+        let rules = rules(json!([
+            { "match": { "resource": { "service.name": "noisy" } }, "action": "drop" },
+            { "match": { "span_name": "db" }, "action": { "route": { "upstreams": ["backup"] } } },
+        ]))
+        .unwrap();
+        let [ExportRequest::Traces(noisy), ExportRequest::Traces(quiet)] = [
+            traces("noisy", vec![span(1, "a")]),
+            traces("quiet", vec![span(1, "db"), span(1, "b")]),
+        ] else {
+            unreachable!()
+        };
+        let request = ExportRequest::Traces(ExportTraceServiceRequest {
+            resource_spans: [noisy.resource_spans, quiet.resource_spans].concat(),
+        });
+
+        for (_, request) in rules.route(request) {
+            let ExportRequest::Traces(request) = request else {
+                unreachable!()
+            };
+            assert_eq!(request.resource_spans.len(), 1);
+            assert_eq!(request.resource_spans[0].scope_spans[0].spans.len(), 1);
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let combined = json!([
+            { "match": { "span_name": "a", "metric_name": "b" }, "action": "drop" },
+        ]);
+        assert!(rules(combined).is_err());
+        let unknown = json!([
+            { "match": {}, "action": { "route": { "upstreams": ["nowhere"] } } },
+        ]);
+        assert!(rules(unknown).is_err());
+        let pattern = json!([{ "match": { "span_name": "(" }, "action": "drop" }]);
+        assert!(rules(pattern).is_err());
+    }
+}
@@ -0,0 +1,794 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Result};
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+
+use crate::proxy::ExportRequest;
+use crate::telemetry::Signal;
+
+/// File names, without their extensions.
+pub const TRACES_FILE: &str = "otel_traces";
+pub const METRICS_FILE: &str = "otel_metrics";
+pub const LOGS_FILE: &str = "otel_logs";
+/// Receives the exports of all three signals.
+pub const COMBINED_FILE: &str = "otel_combined";
+
+/// Where and how the proxy writes the exports it receives.
+///
+/// Exports are queued and written in batches by a background thread, so that requests don't wait
+/// for the disk. Once `batch.queue_size` exports are waiting, further requests are rejected with
+/// `RESOURCE_EXHAUSTED` until the writer catches up.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSinkConfig {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    /// Whether to also write every export to `otel_combined`.
+    pub combined: bool,
+    pub format: FileFormat,
+    /// Compresses every batch as it is written. Rotated files are then not compressed again.
+    pub compression: Compression,
+    pub batch: BatchConfig,
+    pub rotation: RotationConfig,
+}
+
+impl Default for FileSinkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            directory: PathBuf::from("."),
+            combined: true,
+            format: FileFormat::default(),
+            compression: Compression::default(),
+            batch: BatchConfig::default(),
+            rotation: RotationConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// One OTLP/JSON line per export, in `.log` files.
+    #[default]
+    Json,
+    /// Length-delimited protobuf records, in `.pb` files, see [`ExportRequest::encode_record`].
+    Protobuf,
+}
+
+impl FileFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Json => "log",
+            FileFormat::Protobuf => "pb",
+        }
+    }
+}
+
+/// Every batch is compressed on its own, as a gzip member or a zstd frame, which the usual tools
+/// read as a single stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The suffix added to file names, eg. `.gz`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(data, 0),
+        }
+    }
+}
+
+/// When the background writer flushes: once the queued exports take up `max_bytes`, or
+/// `max_delay_ms` after the oldest of them was queued, whichever comes first.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    pub max_bytes: usize,
+    pub max_delay_ms: u64,
+    /// How many exports can wait to be written.
+    pub queue_size: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1024 * 1024,
+            max_delay_ms: 200,
+            queue_size: 4096,
+        }
+    }
+}
+
+/// When to move a file aside and start a new one. Files are never rotated by default.
+///
+/// Rotated files keep their name with a timestamp appended, eg.
+/// `otel_traces.log.20240101T120000.000`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationConfig {
+    /// Rotate before a write would take the file beyond this size.
+    pub max_bytes: Option<u64>,
+    /// Rotate once the file is this old, going by when it was created, or else last modified if
+    /// the filesystem doesn't record that. Its age carries over restarts of the proxy.
+    pub max_age_secs: Option<u64>,
+    /// Gzip rotated files in the background.
+    pub compress: bool,
+}
+
+/// Returned by [`FileSink::write`] when the queue of the background writer is full.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The file sink queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Writes the exports to one file per signal, and optionally a combined file, from a background
+/// thread.
+#[derive(Debug)]
+pub struct FileSink {
+    format: FileFormat,
+    queue: SyncSender<Command>,
+    stats: Arc<SinkStats>,
+}
+
+/// Counters of a [`FileSink`].
+#[derive(Debug, Default)]
+pub struct SinkStats {
+    written: [AtomicU64; 3],
+    errors: AtomicU64,
+    rejected: AtomicU64,
+    queued: AtomicUsize,
+}
+
+impl SinkStats {
+    /// How many exports of `signal` were written to their file.
+    pub fn written(&self, signal: Signal) -> u64 {
+        self.written[signal_index(signal)].load(Ordering::Relaxed)
+    }
+
+    /// How many batches failed to be written.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// How many exports were turned away because the queue was full.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// How many exports are waiting to be written.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+enum Command {
+    Write(Signal, Vec<u8>),
+    Flush(SyncSender<()>),
+}
+
+impl FileSink {
+    /// Creates the directory if needed, and starts the background writer. The files are created
+    /// on the first write.
+    pub fn open(config: &FileSinkConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.directory)
+            .map_err(|e| anyhow!("Failed to create {}: {}", config.directory.display(), e))?;
+
+        let mut rotation = config.rotation.clone();
+        rotation.compress &= config.compression == Compression::None;
+        let file = |name: &str| {
+            let name = format!(
+                "{}.{}{}",
+                name,
+                config.format.extension(),
+                config.compression.extension()
+            );
+            RotatingFile::new(config.directory.join(name), &rotation)
+        };
+
+        let stats = Arc::new(SinkStats::default());
+        let writer = Writer {
+            files: [file(TRACES_FILE), file(METRICS_FILE), file(LOGS_FILE)],
+            combined: config.combined.then(|| file(COMBINED_FILE)),
+            compression: config.compression,
+            max_bytes: config.batch.max_bytes,
+            max_delay: Duration::from_millis(config.batch.max_delay_ms),
+            stats: stats.clone(),
+        };
+
+        let (queue, commands) = mpsc::sync_channel(config.batch.queue_size.max(1));
+        std::thread::Builder::new()
+            .name("file-sink".to_string())
+            .spawn(move || writer.run(commands))?;
+
+        Ok(Self {
+            format: config.format,
+            queue,
+            stats,
+        })
+    }
+
+    /// Queues the request to be written. Fails with [`QueueFull`] if the writer is behind.
+    pub fn write(&self, request: &ExportRequest) -> Result<()> {
+        let record = match self.format {
+            FileFormat::Json => {
+                let mut line = request.to_json()?.into_bytes();
+                line.push(b'\n');
+                line
+            }
+            FileFormat::Protobuf => request.encode_record(),
+        };
+
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        match self
+            .queue
+            .try_send(Command::Write(request.signal(), record))
+        {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                match e {
+                    TrySendError::Full(_) => {
+                        self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                        Err(QueueFull.into())
+                    }
+                    TrySendError::Disconnected(_) => Err(anyhow!("The file sink has stopped")),
+                }
+            }
+        }
+    }
+
+    /// Writes everything that is queued, and waits for it to be written.
+    pub fn flush(&self) -> Result<()> {
+        let (done, flushed) = mpsc::sync_channel(1);
+        self.queue
+            .send(Command::Flush(done))
+            .map_err(|_| anyhow!("The file sink has stopped"))?;
+        flushed
+            .recv()
+            .map_err(|_| anyhow!("The file sink has stopped"))
+    }
+
+    pub fn stats(&self) -> &SinkStats {
+        &self.stats
+    }
+}
+
+/// The background writer of a [`FileSink`].
+struct Writer {
+    files: [RotatingFile; 3],
+    combined: Option<RotatingFile>,
+    compression: Compression,
+    max_bytes: usize,
+    max_delay: Duration,
+    stats: Arc<SinkStats>,
+}
+
+/// The exports queued for one file.
+#[derive(Default)]
+struct Batch {
+    data: Vec<u8>,
+    exports: u64,
+}
+
+impl Writer {
+    fn run(self, commands: mpsc::Receiver<Command>) {
+        let mut batches: [Batch; 3] = Default::default();
+        let mut combined = Batch::default();
+        let mut oldest: Option<Instant> = None;
+
+        loop {
+            let command = match oldest {
+                Some(oldest) => {
+                    commands.recv_timeout(self.max_delay.saturating_sub(oldest.elapsed()))
+                }
+                None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match command {
+                Ok(Command::Write(signal, record)) => {
+                    self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    if self.combined.is_some() {
+                        combined.data.extend_from_slice(&record);
+                        combined.exports += 1;
+                    }
+                    let batch = &mut batches[signal_index(signal)];
+                    batch.data.extend_from_slice(&record);
+                    batch.exports += 1;
+                    oldest.get_or_insert_with(Instant::now);
+
+                    let size = batches.iter().map(|batch| batch.data.len()).sum::<usize>();
+                    if size >= self.max_bytes {
+                        self.flush(&mut batches, &mut combined);
+                        oldest = None;
+                    }
+                }
+                Ok(Command::Flush(done)) => {
+                    self.flush(&mut batches, &mut combined);
+                    oldest = None;
+                    let _ = done.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.flush(&mut batches, &mut combined);
+                    oldest = None;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush(&mut batches, &mut combined);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn flush(&self, batches: &mut [Batch; 3], combined: &mut Batch) {
+        for (i, batch) in batches.iter_mut().enumerate() {
+            // Only the per-signal files are counted, the combined file holds the same exports.
+            if let Some(exports) = self.write(&self.files[i], std::mem::take(batch)) {
+                self.stats.written[i].fetch_add(exports, Ordering::Relaxed);
+            }
+        }
+        if let Some(file) = &self.combined {
+            self.write(file, std::mem::take(combined));
+        }
+    }
+
+    /// Writes the batch, and returns how many exports it held if that succeeded.
+    fn write(&self, file: &RotatingFile, batch: Batch) -> Option<u64> {
+        if batch.exports == 0 {
+            return None;
+        }
+        let result = self
+            .compression
+            .compress(&batch.data)
+            .and_then(|data| file.write(&data));
+        match result {
+            Ok(()) => Some(batch.exports),
+            Err(e) => {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Failed to write to {}: {}", file.path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+pub(crate) fn signal_index(signal: Signal) -> usize {
+    match signal {
+        Signal::Traces => 0,
+        Signal::Metrics => 1,
+        Signal::Logs => 2,
+    }
+}
+
+/// A file that is kept open between writes, and that only one writer appends to at a time, so
+/// that concurrent exports can't interleave their lines.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    compress: bool,
+    current: Mutex<Option<OpenFile>>,
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl RotatingFile {
+    pub fn new(path: impl Into<PathBuf>, rotation: &RotationConfig) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: rotation.max_bytes,
+            max_age: rotation.max_age_secs.map(Duration::from_secs),
+            compress: rotation.compress,
+            current: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&self, buf: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().expect("file sink poisoned");
+
+        let open = match current.take() {
+            Some(open) => open,
+            None => self.open()?,
+        };
+        let mut open = match self.should_rotate(&open, buf.len() as u64) {
+            true => {
+                drop(open);
+                self.rotate()?;
+                self.open()?
+            }
+            false => open,
+        };
+
+        // If the write fails the file is reopened on the next one, in case it was removed or
+        // replaced.
+        open.file.write_all(buf)?;
+        open.size += buf.len() as u64;
+        *current = Some(open);
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<OpenFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let metadata = file.metadata()?;
+        Ok(OpenFile {
+            size: metadata.len(),
+            created: metadata
+                .created()
+                .or_else(|_| metadata.modified())
+                .unwrap_or_else(|_| SystemTime::now()),
+            file,
+        })
+    }
+
+    fn should_rotate(&self, open: &OpenFile, additional: u64) -> bool {
+        let too_large = self
+            .max_bytes
+            .is_some_and(|max| open.size > 0 && open.size + additional > max);
+        // A clock that went backwards makes the file new again.
+        let too_old = self
+            .max_age
+            .is_some_and(|max| open.created.elapsed().unwrap_or_default() >= max);
+        too_large || too_old
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f").to_string();
+        let rotated = self.rotated_path(&timestamp);
+        std::fs::rename(&self.path, &rotated)?;
+
+        if self.compress {
+            std::thread::spawn(move || {
+                if let Err(e) = gzip(&rotated) {
+                    eprintln!("Failed to compress {}: {}", rotated.display(), e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Where to move the file when it is rotated at `timestamp`. A `-N` suffix is added if
+    /// another file was rotated within the same millisecond, even if that one was compressed.
+    fn rotated_path(&self, timestamp: &str) -> PathBuf {
+        (0..)
+            .map(|n| match n {
+                0 => with_suffix(&self.path, &format!(".{}", timestamp)),
+                n => with_suffix(&self.path, &format!(".{}-{}", timestamp, n)),
+            })
+            .find(|path| !path.exists() && !with_suffix(path, ".gz").exists())
+            .expect("unbounded range")
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Replaces `path` with `path.gz`.
+fn gzip(path: &Path) -> io::Result<()> {
+    let compressed = with_suffix(path, ".gz");
+
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+
+    std::fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, MultiGzDecoder};
+
+    use super::*;
+    use crate::proxy::tests::{log, logs, metrics, names, span, traces};
+
+    fn sink(dir: &Path, batch: BatchConfig) -> FileSink {
+        FileSink::open(&FileSinkConfig {
+            directory: dir.to_path_buf(),
+            batch,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// Waits for `done`, for up to 10 seconds.
+    fn eventually(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// The names of the files in `dir`, sorted.
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn rotating_file(dir: &Path, rotation: RotationConfig) -> RotatingFile {
+        RotatingFile::new(dir.join("otel_traces.log"), &rotation)
+    }
+
+    #[test]
+    fn rotates_before_exceeding_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = rotating_file(
+            dir.path(),
+            RotationConfig {
+                max_bytes: Some(10),
+                ..Default::default()
+            },
+        );
+        // A write larger than the limit still goes into an empty file.
+        file.write(b"0123456789ab").unwrap();
+        file.write(b"cdef").unwrap();
+        file.write(b"ghijkl").unwrap();
+        file.write(b"m").unwrap();
+
+        let names = files(dir.path());
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "otel_traces.log");
+        let contents = names
+            .iter()
+            .map(|name| std::fs::read_to_string(dir.path().join(name)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["m", "0123456789ab", "cdefghijkl"]);
+    }
+
+    #[test]
+    fn age_carries_over_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |max_age| RotatingFile {
+            max_age: Some(max_age),
+            ..rotating_file(dir.path(), RotationConfig::default())
+        };
+
+        file(Duration::from_secs(3600)).write(b"old\n").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        // A new writer, as after a restart, sees that the file is already too old.
+        let reopened = file(Duration::from_millis(50));
+        reopened.write(b"new\n").unwrap();
+        assert_eq!(files(dir.path()).len(), 2);
+
+        // The new file is young.
+        reopened.write(b"newer\n").unwrap();
+        assert_eq!(files(dir.path()).len(), 2);
+        let current = std::fs::read_to_string(dir.path().join("otel_traces.log")).unwrap();
+        assert_eq!(current, "new\nnewer\n");
+    }
+
+    #[test]
+    fn rotated_names_never_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = rotating_file(dir.path(), RotationConfig::default());
+        let timestamp = "20240101T120000.000";
+        let rotated = |suffix: &str| dir.path().join(format!("otel_traces.log.{}", suffix));
+
+        assert_eq!(file.rotated_path(timestamp), rotated(timestamp));
+        std::fs::write(rotated(timestamp), "").unwrap();
+        assert_eq!(
+            file.rotated_path(timestamp),
+            rotated(&format!("{}-1", timestamp))
+        );
+        std::fs::write(rotated(&format!("{}-1.gz", timestamp)), "").unwrap();
+        assert_eq!(
+            file.rotated_path(timestamp),
+            rotated(&format!("{}-2", timestamp))
+        );
+    }
+
+    #[test]
+    fn compresses_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = rotating_file(
+            dir.path(),
+            RotationConfig {
+                max_bytes: Some(4),
+                compress: true,
+                ..Default::default()
+            },
+        );
+        file.write(b"first\n").unwrap();
+        file.write(b"second\n").unwrap();
+
+        // The rotated file is compressed in the background.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let compressed = loop {
+            let names = files(dir.path());
+            if let [_, rotated] = &names[..] {
+                if rotated.ends_with(".gz") {
+                    break dir.path().join(rotated);
+                }
+            }
+            assert!(Instant::now() < deadline, "not compressed: {:?}", names);
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        let mut contents = String::new();
+        GzDecoder::new(File::open(compressed).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "first\n");
+        let current = std::fs::read_to_string(dir.path().join("otel_traces.log")).unwrap();
+        assert_eq!(current, "second\n");
+    }
+
+    #[test]
+    fn writes_a_batch_once_it_is_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = sink(
+            dir.path(),
+            BatchConfig {
+                max_bytes: 1,
+                max_delay_ms: 3_600_000,
+                ..Default::default()
+            },
+        );
+        sink.write(&traces("a", vec![span(1, "a")])).unwrap();
+        eventually(|| sink.stats().written(Signal::Traces) == 1);
+        assert_eq!(sink.stats().queued(), 0);
+    }
+
+    #[test]
+    fn writes_a_batch_after_max_delay() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = sink(
+            dir.path(),
+            BatchConfig {
+                max_delay_ms: 300,
+                ..Default::default()
+            },
+        );
+        sink.write(&traces("a", vec![span(1, "a")])).unwrap();
+        assert_eq!(sink.stats().written(Signal::Traces), 0);
+        eventually(|| sink.stats().written(Signal::Traces) == 1);
+    }
+
+    #[test]
+    fn rejects_exports_once_the_queue_is_full() {
+        // Without a writer, nothing leaves the queue.
+        let (queue, _commands) = mpsc::sync_channel(1);
+        let sink = FileSink {
+            format: FileFormat::Json,
+            queue,
+            stats: Arc::default(),
+        };
+        let request = traces("a", vec![span(1, "a")]);
+        sink.write(&request).unwrap();
+        let error = sink.write(&request).unwrap_err();
+        assert!(error.downcast_ref::<QueueFull>().is_some());
+        assert_eq!(sink.stats().queued(), 1);
+        assert_eq!(sink.stats().rejected(), 1);
+    }
+
+    #[test]
+    fn flush_waits_for_everything_queued() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = sink(
+            dir.path(),
+            BatchConfig {
+                max_delay_ms: 3_600_000,
+                ..Default::default()
+            },
+        );
+        sink.write(&traces("a", vec![span(1, "a")])).unwrap();
+        sink.write(&metrics("a", &["b"])).unwrap();
+        sink.write(&logs("a", vec![log(None, 9, "c")])).unwrap();
+        sink.flush().unwrap();
+
+        for signal in Signal::ALL {
+            assert_eq!(sink.stats().written(signal), 1);
+        }
+        let combined = std::fs::read_to_string(dir.path().join("otel_combined.log")).unwrap();
+        let names = combined
+            .lines()
+            .flat_map(|line| names(&ExportRequest::from_json(line.as_bytes()).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn compressed_batches_read_back() {
+        for format in [FileFormat::Json, FileFormat::Protobuf] {
+            for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+                let dir = tempfile::tempdir().unwrap();
+                let sink = FileSink::open(&FileSinkConfig {
+                    directory: dir.path().to_path_buf(),
+                    format,
+                    compression,
+                    ..Default::default()
+                })
+                .unwrap();
+                // Two batches, so two gzip members or zstd frames.
+                sink.write(&traces("a", vec![span(1, "a")])).unwrap();
+                sink.flush().unwrap();
+                sink.write(&traces("a", vec![span(2, "b"), span(3, "c")]))
+                    .unwrap();
+                sink.flush().unwrap();
+
+                let name = format!(
+                    "otel_traces.{}{}",
+                    format.extension(),
+                    compression.extension()
+                );
+                let bytes = std::fs::read(dir.path().join(name)).unwrap();
+                let bytes = match compression {
+                    Compression::None => bytes,
+                    Compression::Gzip => {
+                        let mut decoded = Vec::new();
+                        MultiGzDecoder::new(&bytes[..])
+                            .read_to_end(&mut decoded)
+                            .unwrap();
+                        decoded
+                    }
+                    Compression::Zstd => zstd::decode_all(&bytes[..]).unwrap(),
+                };
+                let requests = match format {
+                    FileFormat::Json => bytes
+                        .split(|byte| *byte == b'\n')
+                        .filter(|line| !line.is_empty())
+                        .map(|line| ExportRequest::from_json(line).unwrap())
+                        .collect(),
+                    FileFormat::Protobuf => ExportRequest::decode_records(&bytes).unwrap(),
+                };
+                let names = requests.iter().flat_map(names).collect::<Vec<_>>();
+                assert_eq!(names, ["a", "b", "c"], "{:?} {:?}", format, compression);
+            }
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::common::v1::{AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+use opentelemetry_proto::tonic::metrics::v1::number_data_point;
+use opentelemetry_proto::tonic::metrics::v1::{
+    AggregationTemporality, Histogram, HistogramDataPoint, Metric, NumberDataPoint,
+    ResourceMetrics, ScopeMetrics, Sum,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_proto::tonic::trace::v1::span::SpanKind;
+use opentelemetry_proto::tonic::trace::v1::status::StatusCode;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::proxy::processor::string_attribute;
+use crate::proxy::{attribute, ExportRequest};
+
+/// The metrics generated from spans, named as by the collector's `spanmetrics` connector.
+pub const CALLS_METRIC: &str = "traces.span.metrics.calls";
+pub const DURATION_METRIC: &str = "traces.span.metrics.duration";
+
+/// The instrumentation scope of the generated metrics.
+const SCOPE: &str = "spanmetrics";
+
+/// Derives request rate, error and duration metrics from the spans received, for services that
+/// only emit traces. Every `interval_ms`, and when the proxy shuts down, the totals so far are
+/// emitted as cumulative `traces.span.metrics.calls` and `traces.span.metrics.duration` (ms)
+/// metrics, for each `service.name`, with `span.name`, `span.kind` and `status.code` attributes.
+/// Errors are the calls with a `status.code` of `STATUS_CODE_ERROR`.
+///
+/// ```json
+/// { "interval_ms": 15000, "buckets_ms": [10, 100, 1000, 10000], "max_series": 10000 }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpanMetricsConfig {
+    pub interval_ms: u64,
+    /// The upper bounds of the duration histogram buckets.
+    pub buckets_ms: Vec<f64>,
+    /// Spans that would add a series beyond this are counted in a single series per service, with
+    /// `otel.metric.overflow` set and no other attributes.
+    pub max_series: usize,
+}
+
+impl Default for SpanMetricsConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 15_000,
+            buckets_ms: vec![
+                2.0, 4.0, 6.0, 8.0, 10.0, 50.0, 100.0, 200.0, 400.0, 800.0, 1_000.0, 1_400.0,
+                2_000.0, 5_000.0, 10_000.0, 15_000.0,
+            ],
+            max_series: 10_000,
+        }
+    }
+}
+
+/// Aggregates spans into metrics, see [`SpanMetricsConfig`].
+#[derive(Debug)]
+pub struct SpanMetrics {
+    buckets_ms: Vec<f64>,
+    max_series: usize,
+    /// When the cumulative totals started, in nanoseconds since the epoch.
+    start_time: u64,
+    series: Mutex<HashMap<SeriesKey, Series>>,
+    generated: mpsc::UnboundedSender<ExportRequest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SeriesKey {
+    service: String,
+    /// `None` for the overflow series of the service.
+    span: Option<SpanKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SpanKey {
+    name: String,
+    kind: i32,
+    status: i32,
+}
+
+#[derive(Debug)]
+struct Series {
+    calls: u64,
+    sum_ms: f64,
+    min_ms: f64,
+    max_ms: f64,
+    /// One more than there are bucket bounds.
+    bucket_counts: Vec<u64>,
+}
+
+impl SpanMetrics {
+    /// Starts emitting the metrics, which are sent on the returned channel. Must be called from a
+    /// Tokio runtime.
+    pub fn spawn(
+        config: &SpanMetricsConfig,
+    ) -> Result<(Arc<Self>, mpsc::UnboundedReceiver<ExportRequest>)> {
+        if config.interval_ms == 0 {
+            bail!("Span metrics need an interval_ms above 0");
+        }
+        if config.buckets_ms.windows(2).any(|pair| pair[0] >= pair[1]) {
+            bail!("Span metrics buckets_ms must be increasing");
+        }
+
+        let (generated, receiver) = mpsc::unbounded_channel();
+        let span_metrics = Arc::new(Self {
+            buckets_ms: config.buckets_ms.clone(),
+            max_series: config.max_series,
+            start_time: unix_nanos(),
+            series: Mutex::new(HashMap::new()),
+            generated,
+        });
+
+        let period = Duration::from_millis(config.interval_ms);
+        let ticking = Arc::downgrade(&span_metrics);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                match ticking.upgrade() {
+                    Some(span_metrics) => span_metrics.emit(),
+                    None => return,
+                }
+            }
+        });
+
+        Ok((span_metrics, receiver))
+    }
+
+    /// Adds the spans of `request` to the totals. Other signals are ignored.
+    pub fn record(&self, request: &ExportRequest) {
+        let ExportRequest::Traces(request) = request else {
+            return;
+        };
+
+        let mut series = self.series.lock().expect("span metrics poisoned");
+        for resource_spans in &request.resource_spans {
+            let service = resource_spans
+                .resource
+                .as_ref()
+                .and_then(|resource| attribute(&resource.attributes, "service.name"))
+                .unwrap_or_else(|| "unknown_service".to_string());
+            for span in resource_spans
+                .scope_spans
+                .iter()
+                .flat_map(|scope_spans| &scope_spans.spans)
+            {
+                let mut key = SeriesKey {
+                    service: service.clone(),
+                    span: Some(SpanKey {
+                        name: span.name.clone(),
+                        kind: span.kind,
+                        status: span.status.as_ref().map_or(0, |status| status.code),
+                    }),
+                };
+                if !series.contains_key(&key) && series.len() >= self.max_series {
+                    key.span = None;
+                }
+
+                let duration_ms =
+                    span.end_time_unix_nano
+                        .saturating_sub(span.start_time_unix_nano) as f64
+                        / 1_000_000.0;
+                series
+                    .entry(key)
+                    .or_insert_with(|| Series {
+                        calls: 0,
+                        sum_ms: 0.0,
+                        min_ms: f64::INFINITY,
+                        max_ms: f64::NEG_INFINITY,
+                        bucket_counts: vec![0; self.buckets_ms.len() + 1],
+                    })
+                    .record(duration_ms, &self.buckets_ms);
+            }
+        }
+    }
+
+    /// Sends the totals so far, if any spans were recorded.
+    pub fn emit(&self) {
+        let series = self.series.lock().expect("span metrics poisoned");
+        if series.is_empty() {
+            return;
+        }
+
+        let now = unix_nanos();
+        let mut by_service: HashMap<&str, (Vec<NumberDataPoint>, Vec<HistogramDataPoint>)> =
+            HashMap::new();
+        for (key, series) in series.iter() {
+            let attributes = key.attributes();
+            let (calls, durations) = by_service.entry(&key.service).or_default();
+            calls.push(NumberDataPoint {
+                attributes: attributes.clone(),
+                start_time_unix_nano: self.start_time,
+                time_unix_nano: now,
+                value: Some(number_data_point::Value::AsInt(series.calls as i64)),
+                ..Default::default()
+            });
+            durations.push(HistogramDataPoint {
+                attributes,
+                start_time_unix_nano: self.start_time,
+                time_unix_nano: now,
+                count: series.calls,
+                sum: Some(series.sum_ms),
+                bucket_counts: series.bucket_counts.clone(),
+                explicit_bounds: self.buckets_ms.clone(),
+                min: Some(series.min_ms),
+                max: Some(series.max_ms),
+                ..Default::default()
+            });
+        }
+
+        let resource_metrics = by_service
+            .into_iter()
+            .map(|(service, (calls, durations))| ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![string_attribute("service.name", service)],
+                    ..Default::default()
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: SCOPE.to_string(),
+                        ..Default::default()
+                    }),
+                    metrics: vec![
+                        Metric {
+                            name: CALLS_METRIC.to_string(),
+                            description: "Spans received".to_string(),
+                            unit: "{call}".to_string(),
+                            data: Some(Data::Sum(Sum {
+                                data_points: calls,
+                                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                                is_monotonic: true,
+                            })),
+                            ..Default::default()
+                        },
+                        Metric {
+                            name: DURATION_METRIC.to_string(),
+                            description: "Duration of the spans received".to_string(),
+                            unit: "ms".to_string(),
+                            data: Some(Data::Histogram(Histogram {
+                                data_points: durations,
+                                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                            })),
+                            ..Default::default()
+                        },
+                    ],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            })
+            .collect();
+        drop(series);
+
+        // The receiver is only gone once the proxy is shutting down.
+        let _ = self
+            .generated
+            .send(ExportRequest::Metrics(ExportMetricsServiceRequest {
+                resource_metrics,
+            }));
+    }
+}
+
+impl SeriesKey {
+    fn attributes(&self) -> Vec<KeyValue> {
+        let Some(span) = &self.span else {
+            return vec![KeyValue {
+                key: "otel.metric.overflow".to_string(),
+                value: Some(AnyValue {
+                    value: Some(Value::BoolValue(true)),
+                }),
+            }];
+        };
+        let kind = SpanKind::try_from(span.kind).unwrap_or(SpanKind::Unspecified);
+        let status = StatusCode::try_from(span.status).unwrap_or(StatusCode::Unset);
+        vec![
+            string_attribute("span.name", &span.name),
+            string_attribute("span.kind", kind.as_str_name()),
+            string_attribute("status.code", status.as_str_name()),
+        ]
+    }
+}
+
+impl Series {
+    fn record(&mut self, duration_ms: f64, buckets_ms: &[f64]) {
+        self.calls += 1;
+        self.sum_ms += duration_ms;
+        self.min_ms = self.min_ms.min(duration_ms);
+        self.max_ms = self.max_ms.max(duration_ms);
+        // Buckets include their upper bound.
+        let bucket = buckets_ms.partition_point(|bound| *bound < duration_ms);
+        self.bucket_counts[bucket] += 1;
+    }
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_proto::tonic::trace::v1::{Span, Status};
+    use serde_json::json;
+
+    use super::*;
+    use crate::proxy::tests::{logs, metrics, span, traces};
+
+    /// Span metrics that are only emitted when asked to, with buckets up to 1, 10 and 100 ms.
+    fn span_metrics(
+        max_series: usize,
+    ) -> (Arc<SpanMetrics>, mpsc::UnboundedReceiver<ExportRequest>) {
+        let config = serde_json::from_value(json!({
+            "interval_ms": 3_600_000,
+            "buckets_ms": [1, 10, 100],
+            "max_series": max_series,
+        }))
+        .unwrap();
+        SpanMetrics::spawn(&config).unwrap()
+    }
+
+    fn timed_span(name: &str, duration_ms: u64) -> Span {
+        let span = span(1, name);
+        Span {
+            end_time_unix_nano: span.start_time_unix_nano + duration_ms * 1_000_000,
+            ..span
+        }
+    }
+
+    fn error_span(name: &str) -> Span {
+        Span {
+            status: Some(Status {
+                code: StatusCode::Error as i32,
+                ..Default::default()
+            }),
+            ..span(1, name)
+        }
+    }
+
+    /// The calls and durations of every series emitted, by service and by span name and status,
+    /// or `overflow` for the overflow series.
+    fn emitted(
+        receiver: &mut mpsc::UnboundedReceiver<ExportRequest>,
+    ) -> HashMap<(String, String), (i64, HistogramDataPoint)> {
+        let Ok(ExportRequest::Metrics(request)) = receiver.try_recv() else {
+            panic!("No span metrics were emitted");
+        };
+        let mut series = HashMap::new();
+        for resource_metrics in request.resource_metrics {
+            let service = attribute(
+                &resource_metrics.resource.unwrap().attributes,
+                "service.name",
+            )
+            .unwrap();
+            let [scope_metrics] = &resource_metrics.scope_metrics[..] else {
+                panic!("Expected a single scope");
+            };
+            let [calls, durations] = &scope_metrics.metrics[..] else {
+                panic!("Expected the calls and duration metrics");
+            };
+            assert_eq!(calls.name, CALLS_METRIC);
+            assert_eq!(durations.name, DURATION_METRIC);
+            let (Some(Data::Sum(calls)), Some(Data::Histogram(durations))) =
+                (&calls.data, &durations.data)
+            else {
+                panic!("Expected a sum and a histogram");
+            };
+            for (calls, durations) in calls.data_points.iter().zip(&durations.data_points) {
+                assert_eq!(calls.attributes, durations.attributes);
+                let series_name = match attribute(&calls.attributes, "span.name") {
+                    Some(name) => {
+                        let status = attribute(&calls.attributes, "status.code").unwrap();
+                        format!("{} {}", name, status)
+                    }
+                    None => {
+                        assert_eq!(
+                            attribute(&calls.attributes, "otel.metric.overflow").as_deref(),
+                            Some("true")
+                        );
+                        "overflow".to_string()
+                    }
+                };
+                let Some(number_data_point::Value::AsInt(count)) = calls.value else {
+                    panic!("Expected an integer count");
+                };
+                series.insert((service.clone(), series_name), (count, durations.clone()));
+            }
+        }
+        series
+    }
+
+    fn key(service: &str, series: &str) -> (String, String) {
+        (service.to_string(), series.to_string())
+    }
+
+    #[tokio::test]
+    async fn durations_fall_in_the_bucket_that_includes_them() {
+        let (span_metrics, mut receiver) = span_metrics(100);
+        let spans = [1, 2, 10, 100, 150]
+            .into_iter()
+            .map(|duration_ms| timed_span("GET", duration_ms))
+            .collect();
+        span_metrics.record(&traces("cart", spans));
+        span_metrics.emit();
+
+        let series = emitted(&mut receiver);
+        let (calls, durations) = &series[&key("cart", "GET STATUS_CODE_UNSET")];
+        assert_eq!(*calls, 5);
+        assert_eq!(durations.count, 5);
+        assert_eq!(durations.explicit_bounds, [1.0, 10.0, 100.0]);
+        assert_eq!(durations.bucket_counts, [1, 2, 1, 1]);
+        assert_eq!(durations.sum, Some(263.0));
+        assert_eq!(durations.min, Some(1.0));
+        assert_eq!(durations.max, Some(150.0));
+    }
+
+    #[tokio::test]
+    async fn series_are_kept_per_service_span_and_status() {
+        let (span_metrics, mut receiver) = span_metrics(100);
+        span_metrics.record(&traces("cart", vec![span(1, "GET"), span(2, "db")]));
+        span_metrics.record(&traces("cart", vec![span(3, "GET")]));
+        span_metrics.record(&traces("checkout", vec![span(4, "GET"), error_span("GET")]));
+        span_metrics.emit();
+
+        let series = emitted(&mut receiver);
+        let calls = |service, name| series[&key(service, name)].0;
+        assert_eq!(series.len(), 4);
+        assert_eq!(calls("cart", "GET STATUS_CODE_UNSET"), 2);
+        assert_eq!(calls("cart", "db STATUS_CODE_UNSET"), 1);
+        assert_eq!(calls("checkout", "GET STATUS_CODE_UNSET"), 1);
+        assert_eq!(calls("checkout", "GET STATUS_CODE_ERROR"), 1);
+    }
+
+    #[tokio::test]
+    async fn spans_beyond_max_series_are_counted_as_overflow() {
+        let (span_metrics, mut receiver) = span_metrics(2);
+        let spans = ["a", "b", "c", "a", "d"]
+            .into_iter()
+            .map(|name| span(1, name))
+            .collect();
+        span_metrics.record(&traces("cart", spans));
+        span_metrics.record(&traces("checkout", vec![span(2, "a")]));
+        span_metrics.emit();
+
+        let series = emitted(&mut receiver);
+        let calls = |service, name| series[&key(service, name)].0;
+        assert_eq!(series.len(), 4);
+        assert_eq!(calls("cart", "a STATUS_CODE_UNSET"), 2);
+        assert_eq!(calls("cart", "b STATUS_CODE_UNSET"), 1);
+        assert_eq!(calls("cart", "overflow"), 2);
+        assert_eq!(calls("checkout", "overflow"), 1);
+    }
+
+    #[tokio::test]
+    async fn emits_cumulative_totals_once_spans_are_recorded() {
+        let (span_metrics, mut receiver) = span_metrics(100);
+        span_metrics.emit();
+        span_metrics.record(&metrics("cart", &["cpu"]));
+        span_metrics.record(&logs("cart", Vec::new()));
+        span_metrics.emit();
+        assert!(receiver.try_recv().is_err());
+
+        span_metrics.record(&traces("cart", vec![span(1, "GET")]));
+        span_metrics.emit();
+        assert_eq!(
+            emitted(&mut receiver)[&key("cart", "GET STATUS_CODE_UNSET")].0,
+            1
+        );
+        span_metrics.record(&traces("cart", vec![span(2, "GET")]));
+        span_metrics.emit();
+        assert_eq!(
+            emitted(&mut receiver)[&key("cart", "GET STATUS_CODE_UNSET")].0,
+            2
+        );
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_proto::tonic::trace::v1::status::StatusCode;
+use opentelemetry_proto::tonic::trace::v1::{ResourceSpans, ScopeSpans, Span};
+use regex::Regex;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::proxy::{attribute, ExportRequest};
+
+/// Holds the spans of every trace for `decision_wait_ms` after its first span arrives, then keeps
+/// or drops the whole trace at once. A trace is kept if any of the policies match it.
+///
+/// Log records that belong to a trace are held and kept or dropped with it. Spans and log records
+/// that arrive after their trace was decided follow the decision, for `decision_cache_secs`.
+///
+/// ```json
+/// {
+///     "decision_wait_ms": 10000,
+///     "policies": [
+///         "error",
+///         { "latency": { "threshold_ms": 500 } },
+///         { "attribute": { "key": "sampling.keep", "value": "true" } },
+///         { "probabilistic": { "percentage": 5 } }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TailSamplingConfig {
+    #[serde(default = "default_decision_wait_ms")]
+    pub decision_wait_ms: u64,
+    /// How many traces are held at most. Beyond it the oldest trace is decided early.
+    #[serde(default = "default_max_traces")]
+    pub max_traces: usize,
+    #[serde(default = "default_decision_cache_secs")]
+    pub decision_cache_secs: u64,
+    pub policies: Vec<PolicyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PolicyConfig {
+    /// Any span has an error status.
+    Error,
+    /// The root span took longer than this.
+    Latency { threshold_ms: u64 },
+    /// Any span, or the resource of any span, has this attribute. If `value` is set, it is a
+    /// regular expression that the whole value must match.
+    Attribute {
+        key: String,
+        #[serde(default)]
+        value: Option<String>,
+    },
+    /// A share of all traces, chosen by trace ID so that every proxy makes the same choice.
+    Probabilistic { percentage: f64 },
+}
+
+#[derive(Debug)]
+enum Policy {
+    Error,
+    Latency(u64),
+    Attribute { key: String, value: Option<Regex> },
+    Probabilistic(u64),
+}
+
+/// Buffers traces and releases the kept ones, see [`TailSamplingConfig`].
+#[derive(Debug)]
+pub struct TailSampler {
+    policies: Vec<Policy>,
+    decision_wait: Duration,
+    max_traces: usize,
+    decision_cache: Duration,
+    state: Mutex<State>,
+    released: mpsc::UnboundedSender<ExportRequest>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: HashMap<Vec<u8>, PendingTrace>,
+    /// Pending trace IDs, oldest first.
+    order: VecDeque<Vec<u8>>,
+    decided: HashMap<Vec<u8>, bool>,
+    /// Decided trace IDs, oldest first.
+    decided_order: VecDeque<(Vec<u8>, Instant)>,
+}
+
+#[derive(Debug)]
+struct PendingTrace {
+    first_seen: Instant,
+    spans: Vec<ResourceSpans>,
+    logs: Vec<ResourceLogs>,
+}
+
+impl TailSampler {
+    /// Starts releasing decided traces. Kept spans and log records are sent on the returned
+    /// channel. Must be called from a Tokio runtime.
+    pub fn spawn(
+        config: &TailSamplingConfig,
+    ) -> Result<(Arc<Self>, mpsc::UnboundedReceiver<ExportRequest>)> {
+        if config.policies.is_empty() {
+            bail!("Tail sampling needs at least one policy");
+        }
+        let policies = config
+            .policies
+            .iter()
+            .map(Policy::new)
+            .collect::<Result<_>>()?;
+
+        let (released, receiver) = mpsc::unbounded_channel();
+        let sampler = Arc::new(Self {
+            policies,
+            decision_wait: Duration::from_millis(config.decision_wait_ms),
+            max_traces: config.max_traces.max(1),
+            decision_cache: Duration::from_secs(config.decision_cache_secs),
+            state: Mutex::new(State::default()),
+            released,
+        });
+
+        let period = sampler
+            .decision_wait
+            .clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let ticking = Arc::downgrade(&sampler);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                match ticking.upgrade() {
+                    Some(sampler) => sampler.tick(),
+                    None => return,
+                }
+            }
+        });
+
+        Ok((sampler, receiver))
+    }
+
+    /// Holds back the spans, and the log records with a trace ID, of traces that are yet to be
+    /// decided, and removes those of dropped traces. Returns what is left to pass on now, if
+    /// anything.
+    pub fn sample(&self, request: ExportRequest) -> Option<ExportRequest> {
+        let mut state = self.state.lock().expect("tail sampler poisoned");
+        let now = Instant::now();
+
+        let request = match request {
+            ExportRequest::Traces(mut request) => {
+                for resource_spans in &mut request.resource_spans {
+                    for scope_spans in &mut resource_spans.scope_spans {
+                        let mut held: HashMap<Vec<u8>, Vec<Span>> = HashMap::new();
+                        for span in std::mem::take(&mut scope_spans.spans) {
+                            match state.decided.get(&span.trace_id) {
+                                Some(true) => scope_spans.spans.push(span),
+                                Some(false) => {}
+                                None => held.entry(span.trace_id.clone()).or_default().push(span),
+                            }
+                        }
+                        for (trace_id, spans) in held {
+                            let fragment = ResourceSpans {
+                                resource: resource_spans.resource.clone(),
+                                scope_spans: vec![ScopeSpans {
+                                    scope: scope_spans.scope.clone(),
+                                    spans,
+                                    schema_url: scope_spans.schema_url.clone(),
+                                }],
+                                schema_url: resource_spans.schema_url.clone(),
+                            };
+                            self.pending(&mut state, trace_id, now).spans.push(fragment);
+                        }
+                    }
+                    resource_spans
+                        .scope_spans
+                        .retain(|scope| !scope.spans.is_empty());
+                }
+                request
+                    .resource_spans
+                    .retain(|resource| !resource.scope_spans.is_empty());
+                (!request.resource_spans.is_empty()).then_some(ExportRequest::Traces(request))
+            }
+            ExportRequest::Logs(mut request) => {
+                for resource_logs in &mut request.resource_logs {
+                    for scope_logs in &mut resource_logs.scope_logs {
+                        let mut held: HashMap<Vec<u8>, Vec<LogRecord>> = HashMap::new();
+                        for record in std::mem::take(&mut scope_logs.log_records) {
+                            if record.trace_id.is_empty() {
+                                scope_logs.log_records.push(record);
+                                continue;
+                            }
+                            match state.decided.get(&record.trace_id) {
+                                Some(true) => scope_logs.log_records.push(record),
+                                Some(false) => {}
+                                None => held
+                                    .entry(record.trace_id.clone())
+                                    .or_default()
+                                    .push(record),
+                            }
+                        }
+                        for (trace_id, log_records) in held {
+                            let fragment = ResourceLogs {
+                                resource: resource_logs.resource.clone(),
+                                scope_logs: vec![ScopeLogs {
+                                    scope: scope_logs.scope.clone(),
+                                    log_records,
+                                    schema_url: scope_logs.schema_url.clone(),
+                                }],
+                                schema_url: resource_logs.schema_url.clone(),
+                            };
+                            self.pending(&mut state, trace_id, now).logs.push(fragment);
+                        }
+                    }
+                    resource_logs
+                        .scope_logs
+                        .retain(|scope| !scope.log_records.is_empty());
+                }
+                request
+                    .resource_logs
+                    .retain(|resource| !resource.scope_logs.is_empty());
+                (!request.resource_logs.is_empty()).then_some(ExportRequest::Logs(request))
+            }
+            request @ ExportRequest::Metrics(_) => Some(request),
+        };
+
+        // Make room for the traces that were added.
+        let mut released = Released::default();
+        while state.pending.len() > self.max_traces {
+            self.decide_oldest(&mut state, now, &mut released);
+        }
+        drop(state);
+        self.release(released);
+
+        request
+    }
+
+    /// Decides the traces whose wait is over, and forgets old decisions.
+    fn tick(&self) {
+        let mut state = self.state.lock().expect("tail sampler poisoned");
+        let now = Instant::now();
+
+        let mut released = Released::default();
+        while state
+            .order
+            .front()
+            .is_some_and(|trace_id| state.pending[trace_id].first_seen + self.decision_wait <= now)
+        {
+            self.decide_oldest(&mut state, now, &mut released);
+        }
+
+        while let Some((trace_id, decided_at)) = state.decided_order.front() {
+            if *decided_at + self.decision_cache > now {
+                break;
+            }
+            let trace_id = trace_id.clone();
+            state.decided.remove(&trace_id);
+            state.decided_order.pop_front();
+        }
+
+        drop(state);
+        self.release(released);
+    }
+
+    /// Decides every trace that is still held, without waiting, for when the proxy shuts down.
+    pub fn flush(&self) {
+        let mut state = self.state.lock().expect("tail sampler poisoned");
+        let now = Instant::now();
+
+        let mut released = Released::default();
+        while !state.order.is_empty() {
+            self.decide_oldest(&mut state, now, &mut released);
+        }
+
+        drop(state);
+        self.release(released);
+    }
+
+    fn pending<'a>(
+        &self,
+        state: &'a mut State,
+        trace_id: Vec<u8>,
+        now: Instant,
+    ) -> &'a mut PendingTrace {
+        let State { pending, order, .. } = state;
+        pending.entry(trace_id).or_insert_with_key(|trace_id| {
+            order.push_back(trace_id.clone());
+            PendingTrace {
+                first_seen: now,
+                spans: Vec::new(),
+                logs: Vec::new(),
+            }
+        })
+    }
+
+    fn decide_oldest(&self, state: &mut State, now: Instant, released: &mut Released) {
+        let Some(trace_id) = state.order.pop_front() else {
+            return;
+        };
+        let Some(trace) = state.pending.remove(&trace_id) else {
+            return;
+        };
+
+        let keep = self
+            .policies
+            .iter()
+            .any(|policy| policy.matches(&trace_id, &trace));
+        if keep {
+            released.spans.extend(trace.spans);
+            released.logs.extend(trace.logs);
+        }
+        state.decided.insert(trace_id.clone(), keep);
+        state.decided_order.push_back((trace_id, now));
+    }
+
+    fn release(&self, released: Released) {
+        // The receiver is only gone once the proxy is shutting down.
+        if !released.spans.is_empty() {
+            let _ = self
+                .released
+                .send(ExportRequest::Traces(ExportTraceServiceRequest {
+                    resource_spans: released.spans,
+                }));
+        }
+        if !released.logs.is_empty() {
+            let _ = self
+                .released
+                .send(ExportRequest::Logs(ExportLogsServiceRequest {
+                    resource_logs: released.logs,
+                }));
+        }
+    }
+}
+
+/// The spans and log records of the traces kept by one decision round.
+#[derive(Default)]
+struct Released {
+    spans: Vec<ResourceSpans>,
+    logs: Vec<ResourceLogs>,
+}
+
+impl Policy {
+    fn new(config: &PolicyConfig) -> Result<Self> {
+        Ok(match config {
+            PolicyConfig::Error => Policy::Error,
+            PolicyConfig::Latency { threshold_ms } => Policy::Latency(threshold_ms * 1_000_000),
+            PolicyConfig::Attribute { key, value } => Policy::Attribute {
+                key: key.clone(),
+                value: value
+                    .as_deref()
+                    .map(|value| Regex::new(&format!("^(?:{})$", value)))
+                    .transpose()?,
+            },
+            PolicyConfig::Probabilistic { percentage } => {
+                if !(0.0..=100.0).contains(percentage) {
+                    bail!("percentage must be between 0 and 100: {}", percentage);
+                }
+                Policy::Probabilistic((percentage / 100.0 * u64::MAX as f64) as u64)
+            }
+        })
+    }
+
+    fn matches(&self, trace_id: &[u8], trace: &PendingTrace) -> bool {
+        let mut spans = trace.spans.iter().flat_map(|resource_spans| {
+            let resource = &resource_spans.resource;
+            resource_spans
+                .scope_spans
+                .iter()
+                .flat_map(move |scope| scope.spans.iter().map(move |span| (resource, span)))
+        });
+
+        match self {
+            Policy::Error => spans.any(|(_, span)| {
+                span.status
+                    .as_ref()
+                    .is_some_and(|status| status.code == StatusCode::Error as i32)
+            }),
+            Policy::Latency(threshold) => spans.any(|(_, span)| {
+                span.parent_span_id.is_empty()
+                    && span
+                        .end_time_unix_nano
+                        .saturating_sub(span.start_time_unix_nano)
+                        > *threshold
+            }),
+            Policy::Attribute { key, value } => spans.any(|(resource, span)| {
+                let resource = resource
+                    .as_ref()
+                    .map_or(&[][..], |r: &Resource| &r.attributes);
+                [&span.attributes[..], resource].iter().any(|attributes| {
+                    attribute(attributes, key).is_some_and(|found| {
+                        value.as_ref().is_none_or(|value| value.is_match(&found))
+                    })
+                })
+            }),
+            // The last 8 bytes of the trace ID are random in W3C trace contexts.
+            Policy::Probabilistic(threshold) => {
+                let tail = trace_id.len().saturating_sub(8);
+                let random = trace_id[tail..]
+                    .iter()
+                    .fold(0u64, |acc, byte| acc << 8 | *byte as u64);
+                random < *threshold
+            }
+        }
+    }
+}
+
+fn default_decision_wait_ms() -> u64 {
+    10_000
+}
+
+fn default_max_traces() -> usize {
+    50_000
+}
+
+fn default_decision_cache_secs() -> u64 {
+    60
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_proto::tonic::trace::v1::Status;
+    use serde_json::json;
+
+    use super::*;
+    use crate::proxy::tests::{log, logs, metrics, names, span, traces};
+
+    /// A sampler that only decides when it is flushed, or when it holds more than `max_traces`.
+    fn sampler(max_traces: usize) -> (Arc<TailSampler>, mpsc::UnboundedReceiver<ExportRequest>) {
+        let config = serde_json::from_value(json!({
+            "decision_wait_ms": 3_600_000,
+            "max_traces": max_traces,
+            "policies": ["error", { "attribute": { "key": "service.name", "value": "checkout" } }],
+        }))
+        .unwrap();
+        TailSampler::spawn(&config).unwrap()
+    }
+
+    fn error_span(trace: u8, name: &str) -> Span {
+        Span {
+            status: Some(Status {
+                code: StatusCode::Error as i32,
+                ..Default::default()
+            }),
+            ..span(trace, name)
+        }
+    }
+
+    fn released(receiver: &mut mpsc::UnboundedReceiver<ExportRequest>) -> Vec<String> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .flat_map(|request| names(&request))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_whole_traces_that_match_a_policy() {
+        let (sampler, mut receiver) = sampler(100);
+        let held = sampler.sample(traces("cart", vec![span(1, "a"), span(2, "b")]));
+        assert!(held.is_none());
+        assert!(sampler
+            .sample(traces("cart", vec![error_span(1, "c")]))
+            .is_none());
+        assert!(sampler
+            .sample(traces("checkout", vec![span(3, "d")]))
+            .is_none());
+
+        sampler.flush();
+        let mut kept = released(&mut receiver);
+        kept.sort();
+        assert_eq!(kept, ["a", "c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn late_spans_and_logs_follow_the_decision() {
+        let (sampler, mut receiver) = sampler(100);
+        sampler.sample(traces("cart", vec![error_span(1, "kept")]));
+        sampler.sample(traces("cart", vec![span(2, "dropped")]));
+        sampler.flush();
+        assert_eq!(released(&mut receiver), ["kept"]);
+
+        let late = traces("cart", vec![span(1, "late kept"), span(2, "late dropped")]);
+        assert_eq!(names(&sampler.sample(late).unwrap()), ["late kept"]);
+        let late = logs(
+            "cart",
+            vec![log(Some(1), 9, "kept"), log(Some(2), 9, "dropped")],
+        );
+        assert_eq!(names(&sampler.sample(late).unwrap()), ["kept"]);
+        assert!(released(&mut receiver).is_empty());
+    }
+
+    #[tokio::test]
+    async fn logs_are_held_with_their_trace() {
+        let (sampler, mut receiver) = sampler(100);
+        let request = logs(
+            "cart",
+            vec![log(Some(1), 9, "traced"), log(None, 9, "untraced")],
+        );
+        assert_eq!(names(&sampler.sample(request).unwrap()), ["untraced"]);
+        sampler.sample(traces("cart", vec![error_span(1, "failed")]));
+
+        let request = metrics("cart", &["cpu"]);
+        assert_eq!(names(&sampler.sample(request).unwrap()), ["cpu"]);
+
+        sampler.flush();
+        let mut kept = released(&mut receiver);
+        kept.sort();
+        assert_eq!(kept, ["failed", "traced"]);
+    }
+
+    #[tokio::test]
+    async fn decides_the_oldest_traces_beyond_max_traces() {
+        let (sampler, mut receiver) = sampler(2);
+        for trace in 1..=3 {
+            sampler.sample(traces("cart", vec![error_span(trace, &trace.to_string())]));
+        }
+        assert_eq!(released(&mut receiver), ["1"]);
+        sampler.flush();
+        assert_eq!(released(&mut receiver), ["2", "3"]);
+    }
+}
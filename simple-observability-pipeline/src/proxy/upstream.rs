@@ -0,0 +1,486 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use opentelemetry_proto::tonic::collector::logs::v1::logs_service_client::LogsServiceClient;
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_client::TraceServiceClient;
+use prost::Message;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::StatusCode;
+use tokio::sync::mpsc;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Channel;
+use tonic::Code;
+
+use crate::config::{BackoffConfig, ExportTarget, HttpEncoding};
+use crate::exporter::{init_channel, metadata_from_headers};
+use crate::proxy::buffer::DiskBuffer;
+use crate::proxy::config::{BufferConfig, UpstreamConfig};
+use crate::proxy::ExportRequest;
+
+/// Forwards exports to one upstream OTLP endpoint from a background task.
+///
+/// Exports are sent one at a time, in the order they were queued, and retried with backoff while
+/// the upstream returns retryable errors. They are queued in memory, or on disk if a
+/// [`BufferConfig`] is given, in which case they are written to it from a background thread.
+///
+/// Failures are printed to stderr rather than logged with `tracing`, since the proxy forwards its
+/// own logs too and a failing upstream would otherwise keep producing more of them.
+#[derive(Debug)]
+pub struct Upstream {
+    name: String,
+    queue: Queue,
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+enum Queue {
+    Memory {
+        sender: mpsc::Sender<ExportRequest>,
+        bytes: Arc<AtomicU64>,
+    },
+    Disk {
+        /// Exports waiting to be written to the buffer.
+        sender: SyncSender<ExportRequest>,
+        disk: Arc<DiskBuffer>,
+    },
+}
+
+impl Upstream {
+    /// Starts the task that forwards to the upstream. Must be called from a Tokio runtime.
+    pub fn spawn(config: &UpstreamConfig, buffer: Option<&BufferConfig>) -> Result<Self> {
+        let client = Client::new(config)?;
+        let dropped = Arc::new(AtomicU64::new(0));
+        let backoff = config.retry.backoff();
+
+        let queue = match buffer {
+            Some(buffer) => {
+                // The name becomes a directory, so it mustn't be able to point anywhere else.
+                if config.name.is_empty()
+                    || config.name.starts_with('.')
+                    || config.name.contains(['/', '\\'])
+                {
+                    bail!(
+                        "Upstream name {:?} can't be used as a directory name",
+                        config.name
+                    );
+                }
+
+                let disk = Arc::new(DiskBuffer::open(
+                    buffer.directory.join(&config.name),
+                    buffer.max_bytes,
+                )?);
+                let (sender, receiver) = sync_channel::<ExportRequest>(config.queue_size.max(1));
+                let writing = disk.clone();
+                let write_dropped = dropped.clone();
+                let name = config.name.clone();
+                std::thread::Builder::new()
+                    .name(format!("buffer-{}", config.name))
+                    .spawn(move || {
+                        // Ends once the upstream is dropped.
+                        for request in receiver {
+                            if let Err(e) = writing.push(&request) {
+                                write_dropped.fetch_add(1, Ordering::Relaxed);
+                                eprintln!(
+                                    "Failed to buffer {} export for {}, dropping it: {}",
+                                    request.signal(),
+                                    name,
+                                    e
+                                );
+                            }
+                        }
+                    })
+                    .map_err(|e| anyhow!("Failed to start the buffer writer: {}", e))?;
+                tokio::spawn(forward_buffered(
+                    config.name.clone(),
+                    client,
+                    disk.clone(),
+                    backoff,
+                    dropped.clone(),
+                ));
+                Queue::Disk { sender, disk }
+            }
+            None => {
+                let (sender, receiver) = mpsc::channel(config.queue_size.max(1));
+                let bytes = Arc::new(AtomicU64::new(0));
+                tokio::spawn(forward_queued(
+                    config.name.clone(),
+                    client,
+                    receiver,
+                    bytes.clone(),
+                    config.retry.max_attempts.max(1),
+                    backoff,
+                    dropped.clone(),
+                ));
+                Queue::Memory { sender, bytes }
+            }
+        };
+
+        Ok(Self {
+            name: config.name.clone(),
+            queue,
+            dropped,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queues `request` to be forwarded. Returns false, and drops the request, if it couldn't be
+    /// queued. Never blocks, exports for the disk buffer are written by a background thread.
+    pub fn forward(&self, request: ExportRequest) -> bool {
+        let signal = request.signal();
+        let result = match &self.queue {
+            Queue::Memory { sender, bytes } => {
+                let size = request.encoded_len() as u64;
+                match sender.try_send(request) {
+                    Ok(()) => {
+                        bytes.fetch_add(size, Ordering::Relaxed);
+                        Ok(())
+                    }
+                    Err(_) => Err("the queue is full".to_string()),
+                }
+            }
+            Queue::Disk { sender, .. } => sender
+                .try_send(request)
+                .map_err(|_| "the buffer can't keep up".to_string()),
+        };
+
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "Failed to queue {} export for {}, dropping it: {}",
+                    signal, self.name, e
+                );
+                false
+            }
+        }
+    }
+
+    /// How many exports were dropped, either because they couldn't be queued or because the
+    /// upstream rejected them. Exports evicted from the disk buffer are counted separately.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of exports waiting to be sent.
+    pub fn queue_depth(&self) -> usize {
+        match &self.queue {
+            Queue::Memory { sender, .. } => sender.max_capacity() - sender.capacity(),
+            Queue::Disk { disk, .. } => disk.depth(),
+        }
+    }
+
+    /// The size of the exports waiting to be sent, encoded as protobuf.
+    pub fn queue_bytes(&self) -> u64 {
+        match &self.queue {
+            Queue::Memory { bytes, .. } => bytes.load(Ordering::Relaxed),
+            Queue::Disk { disk, .. } => disk.bytes(),
+        }
+    }
+
+    /// How many exports were evicted from the disk buffer to make room for newer ones.
+    pub fn evicted(&self) -> u64 {
+        match &self.queue {
+            Queue::Memory { .. } => 0,
+            Queue::Disk { disk, .. } => disk.evicted(),
+        }
+    }
+}
+
+/// Sends exports to an upstream one at a time, waiting for each to be accepted, for tools that
+/// replay exports rather than forward them.
+pub struct UpstreamClient {
+    client: Client,
+    max_attempts: u32,
+    backoff: BackoffConfig,
+}
+
+impl UpstreamClient {
+    /// Must be called from a Tokio runtime.
+    pub fn connect(config: &UpstreamConfig) -> Result<Self> {
+        Ok(Self {
+            client: Client::new(config)?,
+            max_attempts: config.retry.max_attempts.max(1),
+            backoff: config.retry.backoff(),
+        })
+    }
+
+    /// Sends `request`, retrying while the upstream returns retryable errors.
+    pub async fn send(&mut self, request: &ExportRequest) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match self.client.send(request).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.retryable && attempt + 1 < self.max_attempts => {
+                    tokio::time::sleep(self.backoff.delay(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => bail!("{} (after {} attempts)", e.message, attempt + 1),
+            }
+        }
+    }
+}
+
+async fn forward_queued(
+    name: String,
+    mut client: Client,
+    mut receiver: mpsc::Receiver<ExportRequest>,
+    bytes: Arc<AtomicU64>,
+    max_attempts: u32,
+    backoff: BackoffConfig,
+    dropped: Arc<AtomicU64>,
+) {
+    while let Some(request) = receiver.recv().await {
+        bytes.fetch_sub(request.encoded_len() as u64, Ordering::Relaxed);
+
+        let mut attempt = 0;
+        loop {
+            match client.send(&request).await {
+                Ok(()) => break,
+                Err(e) if e.retryable && attempt + 1 < max_attempts => {
+                    tokio::time::sleep(backoff.delay(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    eprintln!(
+                        "Failed to forward {} export to {} after {} attempts, dropping it: {}",
+                        request.signal(),
+                        name,
+                        attempt + 1,
+                        e.message
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sends the oldest buffered export until it succeeds, is rejected, or is evicted, then moves on
+/// to the next one.
+async fn forward_buffered(
+    name: String,
+    mut client: Client,
+    disk: Arc<DiskBuffer>,
+    backoff: BackoffConfig,
+    dropped: Arc<AtomicU64>,
+) {
+    // The buffer does blocking I/O.
+    let peek = |disk: Arc<DiskBuffer>| async move {
+        tokio::task::spawn_blocking(move || disk.peek())
+            .await
+            .map_err(anyhow::Error::from)?
+    };
+    let remove = |disk: Arc<DiskBuffer>, seq| async move {
+        let _ = tokio::task::spawn_blocking(move || disk.remove(seq)).await;
+    };
+
+    let mut attempt = 0;
+    loop {
+        let (seq, request) = match peek(disk.clone()).await {
+            Ok(Some(next)) => next,
+            Ok(None) => {
+                disk.wait().await;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Failed to read the buffer of {}: {}", name, e);
+                tokio::time::sleep(backoff.max).await;
+                continue;
+            }
+        };
+
+        match client.send(&request).await {
+            Ok(()) => {
+                remove(disk.clone(), seq).await;
+                attempt = 0;
+            }
+            Err(e) if e.retryable => {
+                tokio::time::sleep(backoff.delay(attempt)).await;
+                attempt = attempt.saturating_add(1);
+            }
+            Err(e) => {
+                remove(disk.clone(), seq).await;
+                attempt = 0;
+                dropped.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "{} rejected a buffered {} export, dropping it: {}",
+                    name,
+                    request.signal(),
+                    e.message
+                );
+            }
+        }
+    }
+}
+
+struct SendError {
+    message: String,
+    retryable: bool,
+}
+
+struct GrpcClients {
+    traces: TraceServiceClient<Channel>,
+    metrics: MetricsServiceClient<Channel>,
+    logs: LogsServiceClient<Channel>,
+    metadata: MetadataMap,
+}
+
+enum Client {
+    Grpc(Box<GrpcClients>),
+    Http {
+        client: reqwest::Client,
+        endpoint: String,
+        encoding: HttpEncoding,
+        headers: HeaderMap,
+    },
+}
+
+impl Client {
+    fn new(config: &UpstreamConfig) -> Result<Self> {
+        let exporter_config = config.exporter_config()?;
+
+        match &exporter_config.target {
+            ExportTarget::Http { endpoint, encoding } => {
+                let mut headers = HeaderMap::new();
+                for (key, value) in &exporter_config.headers {
+                    headers.insert(
+                        HeaderName::from_bytes(key.to_lowercase().as_bytes())?,
+                        HeaderValue::from_str(value)?,
+                    );
+                }
+
+                Ok(Client::Http {
+                    client: reqwest::Client::builder()
+                        .timeout(exporter_config.timeout)
+                        .build()
+                        .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?,
+                    endpoint: endpoint.trim_end_matches('/').to_string(),
+                    encoding: *encoding,
+                    headers,
+                })
+            }
+            _ => {
+                let channel = init_channel(&exporter_config)?;
+                Ok(Client::Grpc(Box::new(GrpcClients {
+                    traces: TraceServiceClient::new(channel.clone()),
+                    metrics: MetricsServiceClient::new(channel.clone()),
+                    logs: LogsServiceClient::new(channel),
+                    metadata: metadata_from_headers(&exporter_config.headers)?,
+                })))
+            }
+        }
+    }
+
+    async fn send(&mut self, request: &ExportRequest) -> Result<(), SendError> {
+        match self {
+            Client::Grpc(clients) => {
+                let GrpcClients {
+                    traces,
+                    metrics,
+                    logs,
+                    metadata,
+                } = clients.as_mut();
+                let result = match request {
+                    ExportRequest::Traces(request) => traces
+                        .export(grpc_request(request.clone(), metadata))
+                        .await
+                        .map(drop),
+                    ExportRequest::Metrics(request) => metrics
+                        .export(grpc_request(request.clone(), metadata))
+                        .await
+                        .map(drop),
+                    ExportRequest::Logs(request) => logs
+                        .export(grpc_request(request.clone(), metadata))
+                        .await
+                        .map(drop),
+                };
+
+                // See https://opentelemetry.io/docs/specs/otlp/#failures
+                result.map_err(|status| SendError {
+                    retryable: matches!(
+                        status.code(),
+                        Code::Cancelled
+                            | Code::DeadlineExceeded
+                            | Code::ResourceExhausted
+                            | Code::Aborted
+                            | Code::OutOfRange
+                            | Code::Unavailable
+                            | Code::DataLoss
+                    ),
+                    message: status.to_string(),
+                })
+            }
+            Client::Http {
+                client,
+                endpoint,
+                encoding,
+                headers,
+            } => {
+                let (path, body) = match request {
+                    ExportRequest::Traces(request) => ("/v1/traces", encode(request, *encoding)),
+                    ExportRequest::Metrics(request) => ("/v1/metrics", encode(request, *encoding)),
+                    ExportRequest::Logs(request) => ("/v1/logs", encode(request, *encoding)),
+                };
+                let body = body.map_err(|e| SendError {
+                    message: format!("Failed to encode export: {}", e),
+                    retryable: false,
+                })?;
+                let content_type = match encoding {
+                    HttpEncoding::Protobuf => "application/x-protobuf",
+                    HttpEncoding::Json => "application/json",
+                };
+
+                let response = client
+                    .post(format!("{}{}", endpoint, path))
+                    .headers(headers.clone())
+                    .header(CONTENT_TYPE, content_type)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(|e| SendError {
+                        message: e.to_string(),
+                        retryable: true,
+                    })?;
+
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(());
+                }
+                Err(SendError {
+                    message: format!("Upstream responded with {}", status),
+                    retryable: matches!(
+                        status,
+                        StatusCode::TOO_MANY_REQUESTS
+                            | StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    ),
+                })
+            }
+        }
+    }
+}
+
+fn grpc_request<T>(message: T, metadata: &MetadataMap) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    *request.metadata_mut() = metadata.clone();
+    request
+}
+
+fn encode<T>(message: &T, encoding: HttpEncoding) -> Result<Vec<u8>>
+where
+    T: Message + serde::Serialize,
+{
+    match encoding {
+        HttpEncoding::Protobuf => Ok(message.encode_to_vec()),
+        HttpEncoding::Json => Ok(serde_json::to_vec(message)?),
+    }
+}
@@ -1,8 +1,15 @@
-use std::io::Write;
-use std::time::Instant;
-
-use anyhow::Result;
-use once_cell::sync::Lazy;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use opentelemetry::metrics::{
+    AsyncInstrument, Counter, Histogram, ObservableCounter, ObservableGauge,
+};
 use opentelemetry::{global, KeyValue};
 use opentelemetry_proto::tonic::collector::logs::v1::logs_service_server::{
     LogsService, LogsServiceServer,
@@ -22,62 +29,345 @@ use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
 use opentelemetry_proto::tonic::collector::trace::v1::{
     ExportTraceServiceRequest, ExportTraceServiceResponse,
 };
-use opentelemetry_sdk::logs::LoggerProvider;
-use opentelemetry_sdk::metrics::SdkMeterProvider;
 use opentelemetry_sdk::Resource;
-use opentelemetry_semantic_conventions::resource::URL_PATH;
-use simple_observability_pipeline::{create_providers, DEFAULT_SOCK};
-use tokio::net::UnixListener;
-use tokio_stream::wrappers::UnixListenerStream;
+use opentelemetry_semantic_conventions::trace::{RPC_GRPC_STATUS_CODE, RPC_METHOD, RPC_SERVICE};
+use simple_observability_pipeline::interceptor::{ORIGIN_HEADER, PROXY_SERVER_ORIGIN};
+use simple_observability_pipeline::proxy::admin::serve_admin;
+use simple_observability_pipeline::proxy::listener::{http_router, serve_http, MAX_HTTP_BODY};
+use simple_observability_pipeline::proxy::routing::Route;
+use simple_observability_pipeline::proxy::{
+    Admission, ExportRequest, FileSink, ListenerConfig, Processors, ProxyConfig, ProxyStats,
+    ProxyStatus, QueueFull, Rejection, RequestLabel, RequestMetricsConfig, RoutingRules,
+    SocketFile, SpanMetrics, TailSampler, TlsConfig, Upstream,
+};
+use simple_observability_pipeline::{
+    create_providers, ExporterConfig, InterceptorChain, MetricView, MetricsConfig,
+    ObservabilityProviders, ProvidersConfig, ResourceBuilder, SamplerConfig,
+};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
 use tonic::async_trait;
 use tonic::body::BoxBody;
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::metadata::MetadataMap;
+use tonic::server::NamedService;
+use tonic::transport::server::Router;
+use tonic::transport::ServerTlsConfig;
+use tonic::{transport::Server, Code, Request, Response, Status};
+use tonic_health::pb::health_server::{Health, HealthServer};
+use tonic_health::server::{health_reporter, HealthReporter};
+use tonic_health::ServingStatus;
 use tonic_middleware::{Middleware, MiddlewareLayer, ServiceBound};
-use tracing::error;
-use tracing_subscriber::util::SubscriberInitExt;
-
-const LOCAL_COMBINED_OUT: &str = "./otel_combined.log";
-const LOCAL_LOG_FILE_OUT: &str = "./otel_logs.log";
-const LOCAL_METRICS_FILE_OUT: &str = "./otel_metrics.log";
-const LOCAL_TRACES_FILE_OUT: &str = "./otel_traces.log";
-
-static RESOURCE: Lazy<Resource> = Lazy::new(|| {
-    Resource::new(vec![
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-            "basic-otlp-server",
-        ),
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
-            "localhost",
-        ),
-    ])
-});
-
-#[derive(Debug, Default, Clone)]
-pub struct OTELProxyServer {}
+use tower::layer::util::{Identity, Stack};
+
+#[derive(Debug, Clone)]
+pub struct OTELProxyServer {
+    file_sink: Option<Arc<FileSink>>,
+    upstreams: Arc<Vec<Upstream>>,
+    processors: Arc<Processors>,
+    routing: Option<Arc<RoutingRules>>,
+    tail_sampler: Option<Arc<TailSampler>>,
+    span_metrics: Option<Arc<SpanMetrics>>,
+    admission: Arc<Admission>,
+    status: Arc<ProxyStatus>,
+    /// Whether this copy serves an internal listener, see [`ListenerConfig::Uds`].
+    internal: bool,
+}
 
 impl OTELProxyServer {
-    fn write_otel_request_to_file(&self, file_path: &str, request: &str) -> Result<(), Status> {
-        // Write the request as JSON appended to a log file
-        let mut file = std::fs::OpenOptions::new()
-            .append(true)
-            .open(file_path)
-            .map_err(|e| Status::internal(format!("Failed to open log file: {}", e)))?;
-        writeln!(file, "{}", request)
-            .map_err(|e| Status::internal(format!("Failed to write to log file: {}", e)))?;
-
-        // Write the request as JSON to a combined log file
-        let mut file = std::fs::OpenOptions::new()
-            .append(true)
-            .open(LOCAL_COMBINED_OUT)
-            .map_err(|e| Status::internal(format!("Failed to open combined log file: {}", e)))?;
-        writeln!(file, "{}", request).map_err(|e| {
-            Status::internal(format!("Failed to write to combined log file: {}", e))
-        })?;
+    /// Starts forwarding to the configured upstreams. What the proxy generates itself is returned,
+    /// to be passed to [`Self::route_generated`]. Must be called from a Tokio runtime.
+    fn new(config: &ProxyConfig) -> Result<(Self, Generated)> {
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|upstream| Upstream::spawn(upstream, config.buffer.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        let file_sink = match config.file_sink.enabled {
+            true => Some(Arc::new(FileSink::open(&config.file_sink)?)),
+            false => None,
+        };
+        let processors = Processors::new(&config.processors)?;
+        let routing = match &config.routing {
+            Some(routing) => {
+                let names = upstreams.iter().map(|u| u.name().to_string()).collect();
+                Some(RoutingRules::watch(routing, names)?)
+            }
+            None => None,
+        };
+        let (tail_sampler, released) = match &config.tail_sampling {
+            Some(tail_sampling) => {
+                let (sampler, released) = TailSampler::spawn(tail_sampling)?;
+                (Some(sampler), Some(released))
+            }
+            None => (None, None),
+        };
+        let (span_metrics, generated) = match &config.span_metrics {
+            Some(span_metrics) => {
+                let (span_metrics, generated) = SpanMetrics::spawn(span_metrics)?;
+                (Some(span_metrics), Some(generated))
+            }
+            None => (None, None),
+        };
+
+        let server = Self {
+            file_sink,
+            upstreams: Arc::new(upstreams),
+            processors: Arc::new(processors),
+            routing,
+            tail_sampler,
+            span_metrics,
+            admission: Arc::new(Admission::new(&config.admission)),
+            status: Arc::default(),
+            internal: false,
+        };
+        let generated = Generated {
+            released,
+            span_metrics: generated,
+        };
+
+        Ok((server, generated))
+    }
+
+    /// Routes the traces kept by the tail sampler and the span metrics as they are generated,
+    /// until `stop` completes. Then every trace still held is decided and the span metrics are
+    /// emitted once more, and those are routed too, so that none are lost when the proxy shuts
+    /// down.
+    async fn route_generated(self, mut generated: Generated, stop: impl Future<Output = ()>) {
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                Some(request) = recv(&mut generated.released) => self.route_released(request),
+                Some(request) = recv(&mut generated.span_metrics) => {
+                    self.route_span_metrics(request)
+                }
+                _ = &mut stop => break,
+            }
+        }
+
+        if let Some(tail_sampler) = &self.tail_sampler {
+            tail_sampler.flush();
+        }
+        if let Some(span_metrics) = &self.span_metrics {
+            span_metrics.emit();
+        }
+        if let Some(released) = &mut generated.released {
+            while let Ok(request) = released.try_recv() {
+                self.route_released(request);
+            }
+        }
+        if let Some(span_metrics) = &mut generated.span_metrics {
+            while let Ok(request) = span_metrics.try_recv() {
+                self.route_span_metrics(request);
+            }
+        }
+    }
+
+    fn route_released(&self, request: ExportRequest) {
+        if let Err(e) = self.route(request) {
+            eprintln!("Failed to write sampled traces: {}", e);
+        }
+    }
+
+    /// Span metrics skip the processors, since they only carry what the spans already did.
+    fn route_span_metrics(&self, request: ExportRequest) {
+        if let Err(e) = self.route(request) {
+            eprintln!("Failed to write span metrics: {}", e);
+        }
+    }
+
+    /// Queues the request for every upstream on the route. Doesn't wait for it to be sent.
+    fn forward(&self, route: Option<&Route>, request: ExportRequest) {
+        let upstreams = self
+            .upstreams
+            .iter()
+            .filter(|upstream| route.is_none_or(|route| route.includes_upstream(upstream.name())))
+            .collect::<Vec<_>>();
+        let Some((last, others)) = upstreams.split_last() else {
+            return;
+        };
+        for upstream in others {
+            upstream.forward(request.clone());
+        }
+        last.forward(request);
+    }
+
+    /// Processes and samples the request, then routes what is left of it. Span metrics count
+    /// every span, whether or not its trace is sampled.
+    fn handle(&self, mut request: ExportRequest) -> Result<()> {
+        self.processors.process(&mut request);
+
+        if let Some(span_metrics) = &self.span_metrics {
+            span_metrics.record(&request);
+        }
+
+        if let Some(tail_sampler) = &self.tail_sampler {
+            match tail_sampler.sample(request) {
+                Some(rest) => request = rest,
+                None => return Ok(()),
+            }
+        }
+        self.route(request)
+    }
 
+    /// Writes the request to the capture files and forwards it, as the routing rules say. Only
+    /// fails if none of it has been written or forwarded yet.
+    fn route(&self, request: ExportRequest) -> Result<()> {
+        let Some(routing) = &self.routing else {
+            return self.dispatch(None, request);
+        };
+        let rules = routing.current();
+        let mut dispatched = false;
+        for (route, request) in rules.route(request) {
+            match self.write(route, &request) {
+                Ok(()) => {}
+                Err(e) if !dispatched => return Err(e),
+                // Failing the export now would make the client send the parts that were already
+                // forwarded again.
+                Err(e) => eprintln!("Failed to write part of an export, dropping it: {}", e),
+            }
+            self.forward(route, request);
+            dispatched = true;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, route: Option<&Route>, request: ExportRequest) -> Result<()> {
+        self.write(route, &request)?;
+        self.forward(route, request);
         Ok(())
     }
+
+    /// Writes the request to the capture files, if they are on the route.
+    fn write(&self, route: Option<&Route>, request: &ExportRequest) -> Result<()> {
+        if let Some(file_sink) = &self.file_sink {
+            if route.is_none_or(|route| route.file_sink) {
+                file_sink.write(request)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves the exports of an internal listener, which the proxy's own telemetry is accepted
+    /// on.
+    fn with_internal(mut self, internal: bool) -> Self {
+        self.internal = internal;
+        self
+    }
+
+    /// Admits the export, see [`Admission::admit`]. The proxy's own telemetry is always admitted,
+    /// so that it keeps reporting while clients are being turned away.
+    fn admit(&self, info: &ExportInfo) -> Result<(), Rejection> {
+        if info.own_telemetry {
+            return Ok(());
+        }
+        self.admission
+            .admit(info.client(), info.request_bytes as usize)
+    }
+
+    /// Handles an export received on any listener, and answers it with `reply` once it is
+    /// routed. Clients over their rates are told to retry later with `RESOURCE_EXHAUSTED`.
+    #[allow(clippy::result_large_err)]
+    fn export<T: prost::Message>(
+        &self,
+        metadata: &MetadataMap,
+        request: ExportRequest,
+        reply: T,
+    ) -> Result<Response<T>, Status> {
+        let info = ExportInfo::new(metadata, &request, self.internal);
+        self.status.received(request.signal(), info.items);
+        self.admit(&info)
+            .map_err(|rejection| Status::resource_exhausted(rejection.to_string()))?;
+        self.handle(request).map_err(status)?;
+        Ok(info.response(reply))
+    }
+
+    fn stats(&self) -> ProxyStats {
+        ProxyStats::collect(
+            &self.status,
+            self.file_sink.as_deref(),
+            &self.upstreams,
+            &self.admission,
+        )
+    }
+
+    /// Waits for everything queued for the capture files to be written.
+    fn flush(&self) {
+        if let Some(file_sink) = &self.file_sink {
+            if let Err(e) = file_sink.flush() {
+                eprintln!("Failed to flush the capture files: {}", e);
+            }
+        }
+    }
+}
+
+/// The exports that the proxy generates itself, waiting to be routed.
+#[derive(Debug)]
+struct Generated {
+    /// Kept traces, once they are decided.
+    released: Option<mpsc::UnboundedReceiver<ExportRequest>>,
+    span_metrics: Option<mpsc::UnboundedReceiver<ExportRequest>>,
+}
+
+/// Receives from `receiver`, or waits forever if there is none.
+async fn recv(
+    receiver: &mut Option<mpsc::UnboundedReceiver<ExportRequest>>,
+) -> Option<ExportRequest> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// What [`MetricsMiddleware`] records about an export, passed to it in the extensions of the
+/// response.
+#[derive(Debug, Clone)]
+struct ExportInfo {
+    /// The `x-origin` header, or else the `service.name` of the first resource.
+    origin: Option<String>,
+    /// Whether this is the proxy's own telemetry, received on an internal listener.
+    own_telemetry: bool,
+    items: u64,
+    request_bytes: u64,
+    response_bytes: u64,
+}
+
+impl ExportInfo {
+    fn new(metadata: &MetadataMap, request: &ExportRequest, internal: bool) -> Self {
+        let origin = metadata
+            .get(ORIGIN_HEADER)
+            .and_then(|origin| origin.to_str().ok())
+            .map(str::to_string);
+        Self {
+            own_telemetry: internal && origin.as_deref() == Some(PROXY_SERVER_ORIGIN),
+            origin: origin.or_else(|| request.service_name()),
+            items: request.items(),
+            request_bytes: request.encoded_len() as u64,
+            response_bytes: 0,
+        }
+    }
+
+    /// Who sent the export, for admission control.
+    fn client(&self) -> &str {
+        self.origin.as_deref().unwrap_or("unknown")
+    }
+
+    fn response<T: prost::Message>(mut self, reply: T) -> Response<T> {
+        self.response_bytes = reply.encoded_len() as u64;
+        let mut response = Response::new(reply);
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Turns away the export with `RESOURCE_EXHAUSTED` if the capture files can't keep up, so that
+/// the client retries later.
+fn status(error: anyhow::Error) -> Status {
+    match error.downcast_ref::<QueueFull>() {
+        Some(_) => Status::resource_exhausted(error.to_string()),
+        None => Status::internal(error.to_string()),
+    }
 }
 
 #[tonic::async_trait]
@@ -86,15 +376,11 @@ impl TraceService for OTELProxyServer {
         &self,
         request: Request<ExportTraceServiceRequest>,
     ) -> Result<Response<ExportTraceServiceResponse>, Status> {
-        let request = serde_json::to_string(&request.into_inner())
-            .map_err(|e| Status::internal(format!("Failed to serialize request: {}", e)))?;
-        self.write_otel_request_to_file(LOCAL_TRACES_FILE_OUT, &request)?;
-
+        let (metadata, _, request) = request.into_parts();
         let reply = ExportTraceServiceResponse {
             partial_success: None,
         };
-
-        Ok(Response::new(reply))
+        OTELProxyServer::export(self, &metadata, ExportRequest::Traces(request), reply)
     }
 }
 
@@ -104,15 +390,11 @@ impl LogsService for OTELProxyServer {
         &self,
         request: Request<ExportLogsServiceRequest>,
     ) -> Result<Response<ExportLogsServiceResponse>, Status> {
-        let request = serde_json::to_string(&request.into_inner())
-            .map_err(|e| Status::internal(format!("Failed to serialize request: {}", e)))?;
-        self.write_otel_request_to_file(LOCAL_LOG_FILE_OUT, &request)?;
-
+        let (metadata, _, request) = request.into_parts();
         let reply = ExportLogsServiceResponse {
             partial_success: None,
         };
-
-        Ok(Response::new(reply))
+        OTELProxyServer::export(self, &metadata, ExportRequest::Logs(request), reply)
     }
 }
 
@@ -122,49 +404,22 @@ impl MetricsService for OTELProxyServer {
         &self,
         request: Request<ExportMetricsServiceRequest>,
     ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
-        let request = serde_json::to_string(&request.into_inner())
-            .map_err(|e| Status::internal(format!("Failed to serialize request: {}", e)))?;
-        self.write_otel_request_to_file(LOCAL_METRICS_FILE_OUT, &request)?;
-
+        let (metadata, _, request) = request.into_parts();
         let reply = ExportMetricsServiceResponse {
             partial_success: None,
         };
-
-        Ok(Response::new(reply))
+        OTELProxyServer::export(self, &metadata, ExportRequest::Metrics(request), reply)
     }
 }
 
-struct Origin {
-    service_name: String,
-}
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let server = OTELProxyServer::default();
-
-    // If the socket already exists, remove it
-    if std::fs::metadata(DEFAULT_SOCK).is_ok() {
-        std::fs::remove_file(DEFAULT_SOCK)?;
+    let proxy_config = ProxyConfig::from_env()?;
+    if proxy_config.listeners.is_empty() {
+        return Err("No listeners configured".into());
     }
 
-    let otel_service_rt =
-        tokio::runtime::Runtime::new().expect("failed to create otel service runtime");
-
-    let jh = otel_service_rt.spawn(async move {
-        let uds = UnixListener::bind(DEFAULT_SOCK).expect("failed to bind to UDS socket");
-        let uds_stream = UnixListenerStream::new(uds);
-        println!("Listening on {:?}", uds_stream);
-
-        Server::builder()
-            .layer(MiddlewareLayer::new(MetricsMiddleware))
-            .add_service(TraceServiceServer::new(server.clone()))
-            .add_service(MetricsServiceServer::new(server.clone()))
-            .add_service(LogsServiceServer::new(server))
-            .serve_with_incoming(uds_stream)
-            .await
-            .expect("failed to serve")
-    });
-
-    // Init observability - this MUST occur after we have spawned the tasks to bring up the GRPC server.
+    // Init observability. The exporters connect to our own socket lazily, so this doesn't have to
+    // wait for the GRPC server to come up.
     let observability_rt =
         tokio::runtime::Runtime::new().expect("failed to create Observability Runtime");
     let observability_providers = observability_rt.block_on(async {
@@ -172,20 +427,414 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Ok::<ObservabilityProviders, anyhow::Error>(observability_providers)
     })?;
 
+    let otel_service_rt =
+        tokio::runtime::Runtime::new().expect("failed to create otel service runtime");
+    let (server, generated) = {
+        // The upstreams forward from tasks on the service runtime.
+        let _guard = otel_service_rt.enter();
+        OTELProxyServer::new(&proxy_config)?
+    };
+    let (stop_generated, generated_stopped) = oneshot::channel::<()>();
+    let generated = otel_service_rt.spawn(server.clone().route_generated(generated, async {
+        let _ = generated_stopped.await;
+    }));
+    let middleware = MetricsMiddleware::new(&server, &proxy_config.request_metrics);
+
+    // Health checks are answered on every gRPC listener, and report serving once all of them are up.
+    let (mut health, health_service) = health_reporter();
+    otel_service_rt.block_on(set_health(&mut health, ServingStatus::NotServing));
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let shutdown = move || {
+        let mut shutdown_rx = shutdown_rx.clone();
+        async move {
+            let _ = shutdown_rx.changed().await;
+        }
+    };
+
+    // Bind every listener before serving any of them, so that a bad config fails straight away.
+    let mut sockets = Vec::new();
+    let mut listeners = Vec::new();
+    otel_service_rt.block_on(async {
+        for config in &proxy_config.listeners {
+            let listener = match config {
+                ListenerConfig::Uds {
+                    path,
+                    mode,
+                    internal,
+                } => {
+                    let (listener, socket) = SocketFile::bind(path, mode.as_deref())?;
+                    sockets.push(socket);
+                    Listener::Uds(listener, *internal)
+                }
+                ListenerConfig::Grpc { address, tls } => {
+                    let tls = tls.as_ref().map(TlsConfig::server_tls_config).transpose()?;
+                    let listener = TcpListener::bind(address)
+                        .await
+                        .map_err(|e| anyhow!("Failed to bind {}: {}", address, e))?;
+                    Listener::Grpc(listener, tls)
+                }
+                ListenerConfig::Http { address } => Listener::Http(
+                    TcpListener::bind(address)
+                        .await
+                        .map_err(|e| anyhow!("Failed to bind {}: {}", address, e))?,
+                ),
+            };
+            listeners.push(listener);
+        }
+        if let Some(admin) = &proxy_config.admin {
+            listeners.push(Listener::Admin(
+                TcpListener::bind(admin.address)
+                    .await
+                    .map_err(|e| anyhow!("Failed to bind {}: {}", admin.address, e))?,
+            ));
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    let max_request_bytes = proxy_config.admission.max_request_bytes;
+    let mut tasks = Vec::new();
+    for listener in listeners {
+        let server = server.clone();
+        let shutdown = shutdown();
+        let task = match listener {
+            Listener::Uds(listener, internal) => {
+                println!("Listening on {:?}", listener.local_addr()?);
+                let middleware = middleware.clone().with_internal(internal);
+                let builder = Server::builder().layer(MiddlewareLayer::new(middleware));
+                let health_service = health_service.clone();
+                let server = server.with_internal(internal);
+                // The proxy's own telemetry is exported here, and mustn't be turned away.
+                let max_request_bytes = max_request_bytes.filter(|_| !internal);
+                otel_service_rt.spawn(async move {
+                    add_services(builder, server, health_service, max_request_bytes)
+                        .serve_with_incoming_shutdown(UnixListenerStream::new(listener), shutdown)
+                        .await
+                        .map_err(anyhow::Error::from)
+                })
+            }
+            Listener::Grpc(listener, tls) => {
+                println!("Listening for gRPC on {}", listener.local_addr()?);
+                let mut builder = Server::builder();
+                if let Some(tls) = tls {
+                    builder = builder.tls_config(tls)?;
+                }
+                let builder = builder.layer(MiddlewareLayer::new(middleware.clone()));
+                let health_service = health_service.clone();
+                otel_service_rt.spawn(async move {
+                    add_services(builder, server, health_service, max_request_bytes)
+                        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown)
+                        .await
+                        .map_err(anyhow::Error::from)
+                })
+            }
+            Listener::Http(listener) => {
+                println!("Listening for HTTP on {}", listener.local_addr()?);
+                let max_body = max_request_bytes.unwrap_or(MAX_HTTP_BODY);
+                let router = http_router(server, max_body).layer(
+                    axum::middleware::from_fn_with_state(middleware.clone(), measure_http),
+                );
+                otel_service_rt.spawn(async move {
+                    serve_http(listener, router, shutdown)
+                        .await
+                        .map_err(anyhow::Error::from)
+                })
+            }
+            Listener::Admin(listener) => {
+                println!("Serving admin endpoints on {}", listener.local_addr()?);
+                let status = server.status.clone();
+                otel_service_rt.spawn(async move {
+                    serve_admin(listener, status, move || server.stats(), shutdown)
+                        .await
+                        .map_err(anyhow::Error::from)
+                })
+            }
+        };
+        tasks.push(task);
+    }
+    // Every listener was bound above, so connections are already queued for the tasks to accept.
+    server.status.set_ready(true);
+    otel_service_rt.block_on(set_health(&mut health, ServingStatus::Serving));
+
     otel_service_rt.block_on(async {
-        jh.await.expect("failed to join otel service runtime");
+        tokio::select! {
+            _ = shutdown_signal() => println!("Shutting down"),
+            (result, _, _) = futures::future::select_all(tasks.iter_mut()) => match result {
+                Ok(Ok(())) => eprintln!("A listener stopped"),
+                Ok(Err(e)) => eprintln!("A listener failed: {}", e),
+                Err(e) => eprintln!("A listener panicked: {}", e),
+            },
+        }
     });
 
+    // Probes see the proxy going away before it stops accepting exports.
+    server.status.set_ready(false);
+    otel_service_rt.block_on(set_health(&mut health, ServingStatus::NotServing));
+
+    // Flush our own telemetry while the listeners are still up, since it is exported to them.
     observability_providers.shutdown();
 
+    let _ = shutdown_tx.send(());
+    otel_service_rt.block_on(futures::future::join_all(tasks));
+    // Nothing more is received, so the traces still held by the tail sampler can be decided.
+    let _ = stop_generated.send(());
+    if let Err(e) = otel_service_rt.block_on(generated) {
+        eprintln!("Routing sampled traces and span metrics panicked: {}", e);
+    }
+    server.flush();
+    drop(sockets);
+
     Ok(())
 }
 
+/// A bound listener, waiting to be served.
+enum Listener {
+    /// And whether it is internal.
+    Uds(UnixListener, bool),
+    Grpc(TcpListener, Option<ServerTlsConfig>),
+    Http(TcpListener),
+    Admin(TcpListener),
+}
+
+/// Exports over `max_request_bytes` are turned away with `OUT_OF_RANGE` before they are decoded.
+fn add_services(
+    mut builder: Server<Stack<MiddlewareLayer<MetricsMiddleware>, Identity>>,
+    server: OTELProxyServer,
+    health_service: HealthServer<impl Health>,
+    max_request_bytes: Option<usize>,
+) -> Router<Stack<MiddlewareLayer<MetricsMiddleware>, Identity>> {
+    let mut traces = TraceServiceServer::new(server.clone());
+    let mut metrics = MetricsServiceServer::new(server.clone());
+    let mut logs = LogsServiceServer::new(server);
+    if let Some(limit) = max_request_bytes {
+        traces = traces.max_decoding_message_size(limit);
+        metrics = metrics.max_decoding_message_size(limit);
+        logs = logs.max_decoding_message_size(limit);
+    }
+    builder
+        .add_service(health_service)
+        .add_service(traces)
+        .add_service(metrics)
+        .add_service(logs)
+}
+
+/// Sets the health of the whole server, and of each export service.
+async fn set_health(health: &mut HealthReporter, status: ServingStatus) {
+    for service in [
+        "",
+        <TraceServiceServer<OTELProxyServer> as NamedService>::NAME,
+        <MetricsServiceServer<OTELProxyServer> as NamedService>::NAME,
+        <LogsServiceServer<OTELProxyServer> as NamedService>::NAME,
+    ] {
+        health.set_service_status(service, status).await;
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
 use tonic::codegen::http::Request as HttpRequest; // Use this instead of tonic::Request in Middleware!
 use tonic::codegen::http::Response as HttpResponse; // Use this instead of tonic::Response in Middleware!
 
-#[derive(Default, Clone)]
-pub struct MetricsMiddleware;
+/// Measures the exports received, and counts them as in flight for admission control from before
+/// they are read.
+#[derive(Clone)]
+pub struct MetricsMiddleware {
+    admission: Arc<Admission>,
+    request_instruments: Arc<RequestInstruments>,
+    labels: Arc<[RequestLabel]>,
+    _queue_instruments: Arc<QueueInstruments>,
+    _admission_instruments: Arc<AdmissionInstruments>,
+    /// Whether this copy measures an internal listener, see [`ListenerConfig::Uds`].
+    internal: bool,
+}
+
+/// Records the exports received from other services, over gRPC and OTLP/HTTP.
+struct RequestInstruments {
+    requests: Counter<u64>,
+    duration: Histogram<f64>,
+    request_size: Histogram<u64>,
+    response_size: Histogram<u64>,
+    items: Histogram<u64>,
+}
+
+/// Reports the queue of every upstream.
+struct QueueInstruments {
+    _depth: ObservableGauge<u64>,
+    _bytes: ObservableGauge<u64>,
+    _evicted: ObservableCounter<u64>,
+    _dropped: ObservableCounter<u64>,
+}
+
+/// Reports the exports turned away by admission control.
+struct AdmissionInstruments {
+    _rejected: ObservableCounter<u64>,
+    _in_flight: ObservableGauge<u64>,
+}
+
+impl MetricsMiddleware {
+    fn new(server: &OTELProxyServer, config: &RequestMetricsConfig) -> Self {
+        let meter = global::meter("proxy-server");
+        let upstreams = server.upstreams.clone();
+
+        let request_instruments = RequestInstruments {
+            requests: meter
+                .u64_counter("proxy.requests")
+                .with_description("Export requests received")
+                .init(),
+            duration: meter
+                .f64_histogram("proxy.request.duration")
+                .with_description("Time taken to handle export requests")
+                .with_unit("ms")
+                .init(),
+            request_size: meter
+                .u64_histogram("proxy.request.size")
+                .with_description("Size of the exports received, encoded as protobuf")
+                .with_unit("By")
+                .init(),
+            response_size: meter
+                .u64_histogram("proxy.response.size")
+                .with_description("Size of the responses to exports, encoded as protobuf")
+                .with_unit("By")
+                .init(),
+            items: meter
+                .u64_histogram("proxy.request.items")
+                .with_description("Spans, metric data points or log records per export")
+                .with_unit("{item}")
+                .init(),
+        };
+
+        // Observes `read` for every upstream, labelled with its name.
+        let per_upstream = |read: fn(&Upstream) -> u64| {
+            let upstreams = upstreams.clone();
+            move |observer: &dyn AsyncInstrument<u64>| {
+                for upstream in upstreams.iter() {
+                    observer.observe(
+                        read(upstream),
+                        &[KeyValue::new("upstream", upstream.name().to_string())],
+                    );
+                }
+            }
+        };
+        let queue_instruments = QueueInstruments {
+            _depth: meter
+                .u64_observable_gauge("proxy.queue.depth")
+                .with_description("Exports waiting to be forwarded")
+                .with_callback(per_upstream(|upstream| upstream.queue_depth() as u64))
+                .init(),
+            _bytes: meter
+                .u64_observable_gauge("proxy.queue.bytes")
+                .with_description("Size of the exports waiting to be forwarded")
+                .with_callback(per_upstream(Upstream::queue_bytes))
+                .init(),
+            _evicted: meter
+                .u64_observable_counter("proxy.queue.evicted")
+                .with_description(
+                    "Exports evicted from the disk buffer to make room for newer ones",
+                )
+                .with_callback(per_upstream(Upstream::evicted))
+                .init(),
+            _dropped: meter
+                .u64_observable_counter("proxy.queue.dropped")
+                .with_description(
+                    "Exports that couldn't be queued or were rejected by the upstream",
+                )
+                .with_callback(per_upstream(Upstream::dropped))
+                .init(),
+        };
+
+        let admission = server.admission.clone();
+        let in_flight = server.admission.clone();
+        let admission_instruments = AdmissionInstruments {
+            _rejected: meter
+                .u64_observable_counter("proxy.admission.rejected")
+                .with_description("Exports turned away by admission control")
+                .with_callback(move |observer| {
+                    for reason in Rejection::ALL {
+                        observer.observe(
+                            admission.rejected(reason),
+                            &[KeyValue::new("reason", reason.as_str())],
+                        );
+                    }
+                })
+                .init(),
+            _in_flight: meter
+                .u64_observable_gauge("proxy.admission.in_flight")
+                .with_description("Exports being handled")
+                .with_callback(move |observer| observer.observe(in_flight.in_flight() as u64, &[]))
+                .init(),
+        };
+
+        Self {
+            admission: server.admission.clone(),
+            request_instruments: Arc::new(request_instruments),
+            labels: config.labels.clone().into(),
+            _queue_instruments: Arc::new(queue_instruments),
+            _admission_instruments: Arc::new(admission_instruments),
+            internal: false,
+        }
+    }
+
+    /// Measures an internal listener, where the proxy's own telemetry isn't recorded.
+    fn with_internal(mut self, internal: bool) -> Self {
+        self.internal = internal;
+        self
+    }
+
+    /// Records a request to the gRPC `path`. `origin` is the `x-origin` header, if any.
+    fn record(
+        &self,
+        path: &str,
+        status_code: i64,
+        origin: Option<String>,
+        elapsed_time: Duration,
+        info: Option<&ExportInfo>,
+    ) {
+        let origin = origin
+            .or_else(|| info.and_then(|info| info.origin.clone()))
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let labels = self.labels(path, status_code, &origin);
+        let instruments = &self.request_instruments;
+        instruments.requests.add(1, &labels);
+        instruments
+            .duration
+            .record(elapsed_time.as_secs_f64() * 1000.0, &labels);
+        if let Some(info) = info {
+            instruments.request_size.record(info.request_bytes, &labels);
+            instruments
+                .response_size
+                .record(info.response_bytes, &labels);
+            instruments.items.record(info.items, &labels);
+        }
+    }
+
+    fn labels(&self, path: &str, status_code: i64, origin: &str) -> Vec<KeyValue> {
+        let mut labels = Vec::new();
+        for label in self.labels.iter() {
+            match label {
+                RequestLabel::Method => {
+                    let (service, method) = path
+                        .trim_start_matches('/')
+                        .split_once('/')
+                        .unwrap_or((path, ""));
+                    labels.push(KeyValue::new(RPC_SERVICE, service.to_string()));
+                    labels.push(KeyValue::new(RPC_METHOD, method.to_string()));
+                }
+                RequestLabel::StatusCode => {
+                    labels.push(KeyValue::new(RPC_GRPC_STATUS_CODE, status_code))
+                }
+                RequestLabel::Origin => labels.push(KeyValue::new("origin", origin.to_string())),
+            }
+        }
+        labels
+    }
+}
 
 #[async_trait]
 impl<S> Middleware<S> for MetricsMiddleware
@@ -198,77 +847,146 @@ where
         req: HttpRequest<BoxBody>,
         mut service: S,
     ) -> Result<HttpResponse<BoxBody>, S::Error> {
-        // If the request originated from this service itself, we don't want to track it.
-        let req_headers = req.headers();
-        let should_skip_metrics = match req_headers.get("x-origin") {
-            Some(origin) => origin != "proxy-server",
-            None => true,
-        };
-        if should_skip_metrics {
+        // The proxy's own telemetry is exported to itself, and measuring it would produce more.
+        // It is only told apart on internal listeners, since any client can set the header.
+        // Health checks aren't exports.
+        let origin = req
+            .headers()
+            .get(ORIGIN_HEADER)
+            .and_then(|origin| origin.to_str().ok())
+            .map(str::to_string);
+        let health_check = req
+            .uri()
+            .path()
+            .starts_with(&format!("/{}/", <HealthServer<()> as NamedService>::NAME));
+        let own_telemetry = self.internal && origin.as_deref() == Some(PROXY_SERVER_ORIGIN);
+        if own_telemetry || health_check {
             return service.call(req).await;
         }
 
-        // We should measure the duration of this request.
-        let req_path = req.uri().path().to_owned();
+        let path = req.uri().path().to_owned();
         let start_time = Instant::now();
-        let result = service.call(req).await;
+        let result = match self.admission.start() {
+            Ok(_permit) => service.call(req).await,
+            Err(rejection) => Ok(Status::resource_exhausted(rejection.to_string()).into_http()),
+        };
         let elapsed_time = start_time.elapsed();
 
-        // Send metrics events
-        let meter = global::meter(format!("request"));
-        meter
-            .u64_counter("latency")
-            .with_description("latency of requests")
-            .with_unit("microseconds")
-            .init()
-            .add(
-                elapsed_time.as_micros() as u64,
-                &[KeyValue::new(URL_PATH, req_path)],
-            );
+        let Ok(response) = &result else {
+            return result;
+        };
+        // Errors are sent as trailers-only responses, so their status is in the headers. A
+        // successful response has its status in the trailers, which haven't been sent yet.
+        let status_code = response
+            .headers()
+            .get("grpc-status")
+            .and_then(|code| code.to_str().ok()?.parse().ok())
+            .unwrap_or(Code::Ok as i64);
+        // Exports over `max_decoding_message_size` are turned away by tonic.
+        if status_code == Code::OutOfRange as i64 {
+            self.admission.too_large();
+        }
+        self.record(
+            &path,
+            status_code,
+            origin,
+            elapsed_time,
+            response.extensions().get::<ExportInfo>(),
+        );
 
         result
     }
 }
 
-async fn init_observability() -> Result<ObservabilityProviders> {
-    let (tracing_provider, metrics_provider, subscriber, logger_provider) =
-        create_providers(RESOURCE.clone(), true, true).await?;
+/// Records the OTLP/HTTP exports and counts them as in flight as [`MetricsMiddleware`] does the
+/// gRPC ones, under the path of the gRPC method that handles them.
+async fn measure_http(
+    State(middleware): State<MetricsMiddleware>,
+    request: axum::extract::Request,
+    next: Next,
+) -> axum::response::Response {
+    let path = match request.uri().path() {
+        "/v1/traces" => <TraceServiceServer<OTELProxyServer> as NamedService>::NAME,
+        "/v1/metrics" => <MetricsServiceServer<OTELProxyServer> as NamedService>::NAME,
+        "/v1/logs" => <LogsServiceServer<OTELProxyServer> as NamedService>::NAME,
+        _ => return next.run(request).await,
+    };
+    let path = format!("/{}/Export", path);
+    let origin = request
+        .headers()
+        .get(ORIGIN_HEADER)
+        .and_then(|origin| origin.to_str().ok())
+        .map(str::to_string);
+
+    let start_time = Instant::now();
+    let response = match middleware.admission.start() {
+        Ok(_permit) => next.run(request).await,
+        Err(rejection) => {
+            let mut response =
+                (StatusCode::TOO_MANY_REQUESTS, rejection.to_string()).into_response();
+            response.extensions_mut().insert(Code::ResourceExhausted);
+            response
+        }
+    };
+    let elapsed_time = start_time.elapsed();
 
-    // Set globals
-    global::set_tracer_provider(tracing_provider);
-    global::set_meter_provider(metrics_provider.clone());
-    subscriber.init();
-
-    Ok(ObservabilityProviders::new(
-        metrics_provider,
-        logger_provider,
-    ))
+    // Bodies over the limit are turned away by axum, before reaching the export.
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        middleware.admission.too_large();
+    }
+    // Other requests that were turned away before reaching the export carry no code.
+    let status_code = match response.extensions().get::<Code>() {
+        Some(code) => *code,
+        None if response.status() == StatusCode::PAYLOAD_TOO_LARGE => Code::OutOfRange,
+        None => Code::Unknown,
+    };
+    middleware.record(
+        &path,
+        status_code as i64,
+        origin,
+        elapsed_time,
+        response.extensions().get::<ExportInfo>(),
+    );
+    response
 }
 
-#[derive(Default)]
-struct ObservabilityProviders {
-    meter_provider: Option<SdkMeterProvider>,
-    logger_provider: Option<LoggerProvider>,
+fn resource() -> Resource {
+    ResourceBuilder::new("basic-otlp-server")
+        .with_service_version(env!("CARGO_PKG_VERSION"))
+        .with_attribute(KeyValue::new(
+            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
+            "localhost",
+        ))
+        .build()
 }
 
-impl ObservabilityProviders {
-    fn new(meter_provider: SdkMeterProvider, logger_provider: LoggerProvider) -> Self {
-        Self {
-            meter_provider: Some(meter_provider),
-            logger_provider: Some(logger_provider),
-        }
-    }
+/// From 256 bytes to 4 MiB, by factors of 4.
+fn size_buckets() -> Vec<f64> {
+    (0..8).map(|i| 256.0 * 4f64.powi(i)).collect()
+}
 
-    fn shutdown(self) {
-        if let Some(meter_provider) = self.meter_provider {
-            if let Err(e) = meter_provider.shutdown() {
-                error!("Failed to shutdown metrics provider: {:?}", e);
-            }
-        }
-        if let Some(logger_provider) = self.logger_provider {
-            if let Err(e) = logger_provider.shutdown() {
-                error!("Failed to shutdown logger provider: {:?}", e);
-            }
-        }
-    }
+async fn init_observability() -> Result<ObservabilityProviders> {
+    let config = ProvidersConfig::new(
+        ExporterConfig::default().with_interceptors(InterceptorChain::logging_service()),
+    )
+    .with_sampler(SamplerConfig::from_env()?)
+    .with_metrics(
+        MetricsConfig::from_env()?
+            .with_view(
+                // Requests usually take well under a second, and exports are at most a few MiB.
+                MetricView::new("proxy.request.duration").with_histogram_buckets(vec![
+                    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1_000.0,
+                ]),
+            )
+            .with_view(MetricView::new("proxy.request.size").with_histogram_buckets(size_buckets()))
+            .with_view(
+                MetricView::new("proxy.response.size").with_histogram_buckets(size_buckets()),
+            ),
+    );
+    let mut observability_providers = create_providers(resource(), &config).await?;
+
+    // Set globals
+    observability_providers.install_globals()?;
+
+    Ok(observability_providers)
 }
@@ -1,29 +1,15 @@
 use std::time::Duration;
 
 use anyhow::Result;
-use once_cell::sync::Lazy;
 use opentelemetry::trace::{TraceContextExt, Tracer, TracerProvider};
 use opentelemetry::Key;
 use opentelemetry::{global, KeyValue};
-use opentelemetry_sdk::logs::LoggerProvider;
-use opentelemetry_sdk::metrics::SdkMeterProvider;
 use opentelemetry_sdk::Resource;
-use simple_observability_pipeline::create_providers;
-use tracing::{error, info, instrument};
-use tracing_subscriber::prelude::*;
-
-static RESOURCE: Lazy<Resource> = Lazy::new(|| {
-    Resource::new(vec![
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-            "basic-otlp-client",
-        ),
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
-            "localhost",
-        ),
-    ])
-});
+use simple_observability_pipeline::{
+    create_providers, ExporterConfig, ObservabilityProviders, ProvidersConfig, ResourceBuilder,
+    SamplerConfig,
+};
+use tracing::{info, instrument};
 
 fn main() -> Result<()> {
     let logging_rt = tokio::runtime::Runtime::new().expect("Failed to create logging runtime");
@@ -113,45 +99,23 @@ async fn slow_process() {
     tokio::time::sleep(Duration::from_secs(2)).await;
 }
 
-async fn init_observability() -> Result<ObservabilityProviders> {
-    let (tracing_provider, metrics_provider, subscriber, logger_provider) =
-        create_providers(RESOURCE.clone(), true, false).await?;
-
-    // Set globals
-    global::set_tracer_provider(tracing_provider);
-    global::set_meter_provider(metrics_provider.clone());
-    subscriber.init();
-
-    Ok(ObservabilityProviders::new(
-        metrics_provider,
-        logger_provider,
-    ))
+fn resource() -> Resource {
+    ResourceBuilder::new("basic-otlp-client")
+        .with_service_version(env!("CARGO_PKG_VERSION"))
+        .with_attribute(KeyValue::new(
+            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
+            "localhost",
+        ))
+        .build()
 }
 
-#[derive(Default)]
-struct ObservabilityProviders {
-    meter_provider: Option<SdkMeterProvider>,
-    logger_provider: Option<LoggerProvider>,
-}
+async fn init_observability() -> Result<ObservabilityProviders> {
+    let config =
+        ProvidersConfig::new(ExporterConfig::from_env()?).with_sampler(SamplerConfig::from_env()?);
+    let mut observability_providers = create_providers(resource(), &config).await?;
 
-impl ObservabilityProviders {
-    fn new(meter_provider: SdkMeterProvider, logger_provider: LoggerProvider) -> Self {
-        Self {
-            meter_provider: Some(meter_provider),
-            logger_provider: Some(logger_provider),
-        }
-    }
+    // Set globals
+    observability_providers.install_globals()?;
 
-    fn shutdown(self) {
-        if let Some(meter_provider) = self.meter_provider {
-            if let Err(e) = meter_provider.shutdown() {
-                error!("Failed to shutdown metrics provider: {:?}", e);
-            }
-        }
-        if let Some(logger_provider) = self.logger_provider {
-            if let Err(e) = logger_provider.shutdown() {
-                error!("Failed to shutdown logger provider: {:?}", e);
-            }
-        }
-    }
+    Ok(observability_providers)
 }
@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use opentelemetry::{Array, KeyValue, StringValue, Value};
+use opentelemetry_sdk::resource::{EnvResourceDetector, ResourceDetector};
+use opentelemetry_sdk::Resource;
+use opentelemetry_semantic_conventions::resource::{
+    HOST_NAME, PROCESS_COMMAND, PROCESS_COMMAND_ARGS, PROCESS_EXECUTABLE_NAME,
+    PROCESS_EXECUTABLE_PATH, PROCESS_PID, SERVICE_INSTANCE_ID, SERVICE_NAME, SERVICE_VERSION,
+};
+
+pub const OTEL_SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+
+/// Builds the [`Resource`] describing the process that emits telemetry.
+///
+/// Attributes are merged in this order, later sources overriding earlier ones:
+/// 1. detected host and process attributes, and a generated `service.instance.id`
+/// 2. the service name and version passed to the builder
+/// 3. `OTEL_RESOURCE_ATTRIBUTES`
+/// 4. `OTEL_SERVICE_NAME`
+/// 5. attributes added with [`ResourceBuilder::with_attribute`]
+///
+/// ```ignore
+/// let resource = ResourceBuilder::new("my-service")
+///     .with_service_version(env!("CARGO_PKG_VERSION"))
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ResourceBuilder {
+    service_name: String,
+    service_version: Option<String>,
+    detect_host: bool,
+    detect_process: bool,
+    read_env: bool,
+    overrides: Vec<KeyValue>,
+}
+
+impl ResourceBuilder {
+    /// `service_name` is used unless `OTEL_SERVICE_NAME` or `OTEL_RESOURCE_ATTRIBUTES` set one.
+    pub fn new(service_name: impl Into<String>) -> Self {
+        Self {
+            service_name: service_name.into(),
+            service_version: None,
+            detect_host: true,
+            detect_process: true,
+            read_env: true,
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Sets `service.version`. Pass `env!("CARGO_PKG_VERSION")` to use the version of the
+    /// calling crate.
+    pub fn with_service_version(mut self, version: impl Into<String>) -> Self {
+        self.service_version = Some(version.into());
+        self
+    }
+
+    /// Whether to add `host.name`.
+    pub fn with_host_detection(mut self, enabled: bool) -> Self {
+        self.detect_host = enabled;
+        self
+    }
+
+    /// Whether to add the `process.*` attributes.
+    pub fn with_process_detection(mut self, enabled: bool) -> Self {
+        self.detect_process = enabled;
+        self
+    }
+
+    /// Whether to read `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES`.
+    pub fn with_env(mut self, enabled: bool) -> Self {
+        self.read_env = enabled;
+        self
+    }
+
+    /// Adds an attribute that takes precedence over every detected one.
+    pub fn with_attribute(mut self, attribute: KeyValue) -> Self {
+        self.overrides.push(attribute);
+        self
+    }
+
+    pub fn with_attributes(mut self, attributes: impl IntoIterator<Item = KeyValue>) -> Self {
+        self.overrides.extend(attributes);
+        self
+    }
+
+    pub fn build(self) -> Resource {
+        let mut detected = vec![KeyValue::new(
+            SERVICE_INSTANCE_ID,
+            uuid::Uuid::new_v4().to_string(),
+        )];
+        if self.detect_host {
+            detected.extend(host_attributes());
+        }
+        if self.detect_process {
+            detected.extend(process_attributes());
+        }
+
+        let mut service = vec![KeyValue::new(SERVICE_NAME, self.service_name)];
+        if let Some(version) = self.service_version {
+            service.push(KeyValue::new(SERVICE_VERSION, version));
+        }
+
+        let mut resource = Resource::new(detected).merge(&Resource::new(service));
+
+        if self.read_env {
+            resource = resource.merge(&EnvResourceDetector::new().detect(Duration::from_secs(0)));
+            if let Some(name) = std::env::var(OTEL_SERVICE_NAME)
+                .ok()
+                .filter(|name| !name.is_empty())
+            {
+                resource = resource.merge(&Resource::new([KeyValue::new(SERVICE_NAME, name)]));
+            }
+        }
+
+        resource.merge(&Resource::new(self.overrides))
+    }
+}
+
+fn host_attributes() -> Vec<KeyValue> {
+    match hostname::get() {
+        Ok(name) => vec![KeyValue::new(
+            HOST_NAME,
+            name.to_string_lossy().into_owned(),
+        )],
+        Err(_) => Vec::new(),
+    }
+}
+
+fn process_attributes() -> Vec<KeyValue> {
+    let mut attributes = vec![KeyValue::new(PROCESS_PID, std::process::id() as i64)];
+
+    if let Ok(exe) = std::env::current_exe() {
+        if let Some(name) = exe.file_name() {
+            attributes.push(KeyValue::new(
+                PROCESS_EXECUTABLE_NAME,
+                name.to_string_lossy().into_owned(),
+            ));
+        }
+        attributes.push(KeyValue::new(
+            PROCESS_EXECUTABLE_PATH,
+            exe.to_string_lossy().into_owned(),
+        ));
+    }
+
+    let mut args = std::env::args_os().map(|arg| arg.to_string_lossy().into_owned());
+    if let Some(command) = args.next() {
+        attributes.push(KeyValue::new(PROCESS_COMMAND, command.clone()));
+        let command_args = std::iter::once(command)
+            .chain(args)
+            .map(StringValue::from)
+            .collect();
+        attributes.push(KeyValue::new(
+            PROCESS_COMMAND_ARGS,
+            Value::Array(Array::String(command_args)),
+        ));
+    }
+
+    attributes
+}
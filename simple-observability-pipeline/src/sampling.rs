@@ -0,0 +1,205 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use opentelemetry::trace::{
+    Link, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId, TraceState,
+};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+
+use crate::config::env_var;
+
+pub const OTEL_TRACES_SAMPLER: &str = "OTEL_TRACES_SAMPLER";
+pub const OTEL_TRACES_SAMPLER_ARG: &str = "OTEL_TRACES_SAMPLER_ARG";
+
+const DEFAULT_TRACES_PER_SECOND: f64 = 100.0;
+
+/// Which traces are sampled by the tracer provider.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SamplerConfig {
+    AlwaysOn,
+    AlwaysOff,
+    /// Sample this fraction of traces, based on the trace ID.
+    TraceIdRatio(f64),
+    /// Sample at most this many traces per second. Spans with a parent follow its decision, so
+    /// traces are kept or dropped whole.
+    RateLimited {
+        traces_per_second: f64,
+    },
+    /// Follow the parent span's decision, and use the inner sampler for root spans.
+    ParentBased(Box<SamplerConfig>),
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        SamplerConfig::ParentBased(Box::new(SamplerConfig::AlwaysOn))
+    }
+}
+
+impl SamplerConfig {
+    pub fn parent_based(root: SamplerConfig) -> Self {
+        SamplerConfig::ParentBased(Box::new(root))
+    }
+
+    /// Reads the sampler from `OTEL_TRACES_SAMPLER` and `OTEL_TRACES_SAMPLER_ARG`, falling back to
+    /// the SDK default of `parentbased_always_on`.
+    pub fn from_env() -> Result<Self> {
+        Self::default().merge_env()
+    }
+
+    /// Replaces this sampler with the one configured by `OTEL_TRACES_SAMPLER`, if it is set and
+    /// not empty. An empty `OTEL_TRACES_SAMPLER_ARG` is the same as none.
+    ///
+    /// On top of the values defined by the spec, `rate_limited` and `parentbased_rate_limited`
+    /// are accepted, with `OTEL_TRACES_SAMPLER_ARG` as the number of traces per second.
+    pub fn merge_env(self) -> Result<Self> {
+        let Some(sampler) = env_var(OTEL_TRACES_SAMPLER) else {
+            return Ok(self);
+        };
+        let arg = env_var(OTEL_TRACES_SAMPLER_ARG);
+
+        let ratio = || parse_arg(arg.as_deref(), 1.0);
+        let traces_per_second = || parse_arg(arg.as_deref(), DEFAULT_TRACES_PER_SECOND);
+
+        Ok(match sampler.trim() {
+            "always_on" => SamplerConfig::AlwaysOn,
+            "always_off" => SamplerConfig::AlwaysOff,
+            "traceidratio" => SamplerConfig::TraceIdRatio(ratio()?),
+            "rate_limited" => SamplerConfig::RateLimited {
+                traces_per_second: traces_per_second()?,
+            },
+            "parentbased_always_on" => SamplerConfig::parent_based(SamplerConfig::AlwaysOn),
+            "parentbased_always_off" => SamplerConfig::parent_based(SamplerConfig::AlwaysOff),
+            "parentbased_traceidratio" => {
+                SamplerConfig::parent_based(SamplerConfig::TraceIdRatio(ratio()?))
+            }
+            "parentbased_rate_limited" => SamplerConfig::parent_based(SamplerConfig::RateLimited {
+                traces_per_second: traces_per_second()?,
+            }),
+            other => bail!("Unsupported {}: {}", OTEL_TRACES_SAMPLER, other),
+        })
+    }
+
+    pub fn build(&self) -> ConfiguredSampler {
+        ConfiguredSampler(self.boxed())
+    }
+
+    fn boxed(&self) -> Box<dyn ShouldSample> {
+        match self {
+            SamplerConfig::AlwaysOn => Box::new(Sampler::AlwaysOn),
+            SamplerConfig::AlwaysOff => Box::new(Sampler::AlwaysOff),
+            SamplerConfig::TraceIdRatio(ratio) => Box::new(Sampler::TraceIdRatioBased(*ratio)),
+            SamplerConfig::RateLimited { traces_per_second } => {
+                Box::new(RateLimitingSampler::new(*traces_per_second))
+            }
+            SamplerConfig::ParentBased(root) => Box::new(Sampler::ParentBased(root.boxed())),
+        }
+    }
+}
+
+fn parse_arg(arg: Option<&str>, default: f64) -> Result<f64> {
+    match arg {
+        Some(arg) => arg
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("Invalid {}: {}", OTEL_TRACES_SAMPLER_ARG, e)),
+        None => Ok(default),
+    }
+}
+
+/// The sampler built from a [`SamplerConfig`].
+#[derive(Debug, Clone)]
+pub struct ConfiguredSampler(Box<dyn ShouldSample>);
+
+impl ShouldSample for ConfiguredSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        self.0
+            .should_sample(parent_context, trace_id, name, span_kind, attributes, links)
+    }
+}
+
+/// Samples at most `traces_per_second` traces, using a token bucket that allows bursts of up to
+/// one second's worth of traces. Only root spans take a token. Spans with a parent, local or
+/// remote, are sampled if their parent was.
+#[derive(Debug, Clone)]
+pub struct RateLimitingSampler {
+    traces_per_second: f64,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimitingSampler {
+    pub fn new(traces_per_second: f64) -> Self {
+        let traces_per_second = traces_per_second.max(0.0);
+        Self {
+            traces_per_second,
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                tokens: traces_per_second.max(1.0),
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().expect("token bucket poisoned");
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * self.traces_per_second).min(self.traces_per_second.max(1.0));
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl ShouldSample for RateLimitingSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        _trace_id: TraceId,
+        _name: &str,
+        _span_kind: &SpanKind,
+        _attributes: &[KeyValue],
+        _links: &[Link],
+    ) -> SamplingResult {
+        let parent = parent_context
+            .map(|cx| cx.span().span_context().clone())
+            .filter(|parent| parent.is_valid());
+        let sampled = match parent {
+            Some(parent) => parent.is_sampled(),
+            None => self.traces_per_second > 0.0 && self.try_acquire(),
+        };
+        let decision = match sampled {
+            true => SamplingDecision::RecordAndSample,
+            false => SamplingDecision::Drop,
+        };
+
+        SamplingResult {
+            decision,
+            attributes: Vec::new(),
+            trace_state: match parent_context {
+                Some(cx) => cx.span().span_context().trace_state().clone(),
+                None => TraceState::default(),
+            },
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::BoxFuture;
+use once_cell::sync::OnceCell;
+use opentelemetry::global;
+use opentelemetry::logs::{LogError, LogResult};
+use opentelemetry::metrics::{Histogram, MeterProvider, MetricsError, ObservableCounter};
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::export::logs::{LogData, LogExporter};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::metrics::data::{
+    self, ExponentialHistogram, Gauge, ResourceMetrics, Sum, Temporality,
+};
+use opentelemetry_sdk::metrics::exporter::PushMetricsExporter;
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, TemporalitySelector};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, SdkMeterProvider};
+use opentelemetry_sdk::runtime::TrySendError;
+use opentelemetry_sdk::Resource;
+
+use crate::connector::ConnectionState;
+
+/// The name of the meter that the pipeline's own metrics are recorded with.
+pub const SELF_TELEMETRY_METER: &str = "simple-observability-pipeline";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Traces,
+    Metrics,
+    Logs,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 3] = [Signal::Traces, Signal::Metrics, Signal::Logs];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Signal::Traces => "traces",
+            Signal::Metrics => "metrics",
+            Signal::Logs => "logs",
+        }
+    }
+}
+
+impl std::str::FromStr for Signal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Signal::ALL
+            .into_iter()
+            .find(|signal| signal.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown signal: {}", s))
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct SignalStats {
+    exported_items: AtomicU64,
+    failed_exports: AtomicU64,
+    dropped_items: AtomicU64,
+    last_export: Mutex<LastExport>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct LastExport {
+    success: Option<SystemTime>,
+    failure: Option<(SystemTime, String)>,
+}
+
+/// Counters for the exports of all three signals, shared by the exporters created by
+/// [`crate::create_providers`].
+///
+/// The counters are kept in memory and published as observable instruments on the meter provider
+/// that the pipeline owns. Nothing here emits `tracing` events, so a failing export can't produce
+/// more telemetry to export.
+#[derive(Default)]
+pub struct PipelineTelemetry {
+    traces: SignalStats,
+    metrics: SignalStats,
+    logs: SignalStats,
+    instruments: OnceCell<Instruments>,
+}
+
+struct Instruments {
+    export_duration: Histogram<f64>,
+    _counters: Vec<ObservableCounter<u64>>,
+}
+
+impl PipelineTelemetry {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn stats(&self, signal: Signal) -> &SignalStats {
+        match signal {
+            Signal::Traces => &self.traces,
+            Signal::Metrics => &self.metrics,
+            Signal::Logs => &self.logs,
+        }
+    }
+
+    /// Publishes the counters through `meter_provider`.
+    pub(crate) fn register(self: &Arc<Self>, meter_provider: &SdkMeterProvider) {
+        let meter = meter_provider.meter(SELF_TELEMETRY_METER);
+
+        // The callbacks only hold a weak reference, so that the meter provider, which owns them,
+        // does not keep this struct alive.
+        let counter =
+            |name: &'static str, description: &'static str, read: fn(&SignalStats) -> u64| {
+                let telemetry = Arc::downgrade(self);
+                meter
+                    .u64_observable_counter(name)
+                    .with_description(description)
+                    .with_callback(move |observer| {
+                        let Some(telemetry) = Weak::upgrade(&telemetry) else {
+                            return;
+                        };
+                        for signal in Signal::ALL {
+                            observer.observe(
+                                read(telemetry.stats(signal)),
+                                &[KeyValue::new("signal", signal.as_str())],
+                            );
+                        }
+                    })
+                    .init()
+            };
+
+        let counters = vec![
+            counter(
+                "otel.exporter.items",
+                "Spans, log records and metric data points exported successfully",
+                |stats| stats.exported_items.load(Ordering::Relaxed),
+            ),
+            counter("otel.exporter.failures", "Exports that failed", |stats| {
+                stats.failed_exports.load(Ordering::Relaxed)
+            }),
+            counter(
+                "otel.processor.dropped_items",
+                "Spans and log records dropped because the batch queue was full",
+                |stats| stats.dropped_items.load(Ordering::Relaxed),
+            ),
+        ];
+
+        let export_duration = meter
+            .f64_histogram("otel.exporter.duration")
+            .with_description("Duration of exports")
+            .with_unit("ms")
+            .init();
+
+        let _ = self.instruments.set(Instruments {
+            export_duration,
+            _counters: counters,
+        });
+    }
+
+    fn record_export(&self, signal: Signal, items: u64, elapsed: Duration, error: Option<String>) {
+        let stats = self.stats(signal);
+        let now = SystemTime::now();
+        let outcome = match &error {
+            Some(_) => "failure",
+            None => "success",
+        };
+
+        match error {
+            Some(error) => {
+                stats.failed_exports.fetch_add(1, Ordering::Relaxed);
+                stats
+                    .last_export
+                    .lock()
+                    .expect("export stats poisoned")
+                    .failure = Some((now, error));
+            }
+            None => {
+                stats.exported_items.fetch_add(items, Ordering::Relaxed);
+                stats
+                    .last_export
+                    .lock()
+                    .expect("export stats poisoned")
+                    .success = Some(now);
+            }
+        }
+
+        if let Some(instruments) = self.instruments.get() {
+            instruments.export_duration.record(
+                elapsed.as_secs_f64() * 1000.0,
+                &[
+                    KeyValue::new("signal", signal.as_str()),
+                    KeyValue::new("outcome", outcome),
+                ],
+            );
+        }
+    }
+
+    fn record_dropped(&self, signal: Signal, items: u64) {
+        self.stats(signal)
+            .dropped_items
+            .fetch_add(items, Ordering::Relaxed);
+    }
+
+    pub fn signal_health(&self, signal: Signal) -> SignalHealth {
+        let stats = self.stats(signal);
+        let last_export = stats
+            .last_export
+            .lock()
+            .expect("export stats poisoned")
+            .clone();
+        let (last_failure, last_error) = match last_export.failure {
+            Some((at, error)) => (Some(at), Some(error)),
+            None => (None, None),
+        };
+
+        SignalHealth {
+            exported_items: stats.exported_items.load(Ordering::Relaxed),
+            failed_exports: stats.failed_exports.load(Ordering::Relaxed),
+            dropped_items: stats.dropped_items.load(Ordering::Relaxed),
+            last_success: last_export.success,
+            last_failure,
+            last_error,
+        }
+    }
+
+    pub(crate) fn health(&self, connection: Option<ConnectionState>) -> PipelineHealth {
+        PipelineHealth {
+            traces: self.signal_health(Signal::Traces),
+            metrics: self.signal_health(Signal::Metrics),
+            logs: self.signal_health(Signal::Logs),
+            connection,
+        }
+    }
+
+    /// Installs a global OpenTelemetry error handler that counts the items dropped by the batch
+    /// processors, and prints every error to stderr like the default handler does.
+    ///
+    /// Errors are not logged with `tracing`, as those events would be exported too.
+    pub(crate) fn install_error_handler(self: &Arc<Self>) -> Result<(), global::Error> {
+        let telemetry = Arc::downgrade(self);
+        global::set_error_handler(move |error| {
+            if let Some(telemetry) = Weak::upgrade(&telemetry) {
+                match &error {
+                    global::Error::Trace(TraceError::Other(e)) if is_channel_full(e.as_ref()) => {
+                        telemetry.record_dropped(Signal::Traces, 1)
+                    }
+                    global::Error::Log(LogError::Other(e)) if is_channel_full(e.as_ref()) => {
+                        telemetry.record_dropped(Signal::Logs, 1)
+                    }
+                    _ => {}
+                }
+            }
+
+            eprintln!("OpenTelemetry error occurred. {}", error);
+        })
+    }
+}
+
+fn is_channel_full(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<TrySendError>(),
+        Some(TrySendError::ChannelFull)
+    )
+}
+
+impl fmt::Debug for PipelineTelemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipelineTelemetry")
+            .field("traces", &self.traces)
+            .field("metrics", &self.metrics)
+            .field("logs", &self.logs)
+            .finish()
+    }
+}
+
+/// A snapshot of the exports of one signal.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignalHealth {
+    pub exported_items: u64,
+    pub failed_exports: u64,
+    pub dropped_items: u64,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    pub last_error: Option<String>,
+}
+
+impl SignalHealth {
+    /// Whether the most recent export succeeded, or nothing has been exported yet.
+    pub fn is_healthy(&self) -> bool {
+        match (self.last_success, self.last_failure) {
+            (_, None) => true,
+            (Some(success), Some(failure)) => success >= failure,
+            (None, Some(_)) => false,
+        }
+    }
+}
+
+/// A snapshot of the health of the pipeline, returned by
+/// [`crate::ObservabilityProviders::health`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PipelineHealth {
+    pub traces: SignalHealth,
+    pub metrics: SignalHealth,
+    pub logs: SignalHealth,
+    /// The state of the connection to the export target, if it is a Unix domain socket.
+    pub connection: Option<ConnectionState>,
+}
+
+impl PipelineHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.traces.is_healthy() && self.metrics.is_healthy() && self.logs.is_healthy()
+    }
+}
+
+/// Wraps an exporter to record every export in [`PipelineTelemetry`].
+#[derive(Debug)]
+pub(crate) struct InstrumentedExporter<E> {
+    inner: E,
+    telemetry: Arc<PipelineTelemetry>,
+}
+
+impl<E> InstrumentedExporter<E> {
+    pub(crate) fn new(inner: E, telemetry: Arc<PipelineTelemetry>) -> Self {
+        Self { inner, telemetry }
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for InstrumentedExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let items = batch.len() as u64;
+        let telemetry = self.telemetry.clone();
+        let start = Instant::now();
+        let export = self.inner.export(batch);
+
+        Box::pin(async move {
+            let result = export.await;
+            telemetry.record_export(
+                Signal::Traces,
+                items,
+                start.elapsed(),
+                result.as_ref().err().map(ToString::to_string),
+            );
+            result
+        })
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+#[tonic::async_trait]
+impl<E: LogExporter> LogExporter for InstrumentedExporter<E> {
+    async fn export<'a>(&mut self, batch: Vec<Cow<'a, LogData>>) -> LogResult<()> {
+        let items = batch.len() as u64;
+        let start = Instant::now();
+        let result = self.inner.export(batch).await;
+        self.telemetry.record_export(
+            Signal::Logs,
+            items,
+            start.elapsed(),
+            result.as_ref().err().map(ToString::to_string),
+        );
+        result
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+impl<E: AggregationSelector> AggregationSelector for InstrumentedExporter<E> {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.inner.aggregation(kind)
+    }
+}
+
+impl<E: TemporalitySelector> TemporalitySelector for InstrumentedExporter<E> {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.inner.temporality(kind)
+    }
+}
+
+#[tonic::async_trait]
+impl<E: PushMetricsExporter> PushMetricsExporter for InstrumentedExporter<E> {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> Result<(), MetricsError> {
+        let items = count_data_points(metrics);
+        let start = Instant::now();
+        let result = self.inner.export(metrics).await;
+        self.telemetry.record_export(
+            Signal::Metrics,
+            items,
+            start.elapsed(),
+            result.as_ref().err().map(ToString::to_string),
+        );
+        result
+    }
+
+    async fn force_flush(&self) -> Result<(), MetricsError> {
+        self.inner.force_flush().await
+    }
+
+    fn shutdown(&self) -> Result<(), MetricsError> {
+        self.inner.shutdown()
+    }
+}
+
+fn count_data_points(metrics: &ResourceMetrics) -> u64 {
+    metrics
+        .scope_metrics
+        .iter()
+        .flat_map(|scope| scope.metrics.iter())
+        .map(|metric| data_points(metric.data.as_any()) as u64)
+        .sum()
+}
+
+fn data_points(data: &dyn std::any::Any) -> usize {
+    macro_rules! count {
+        ($($ty:ty),*) => {
+            $(
+                if let Some(data) = data.downcast_ref::<$ty>() {
+                    return data.data_points.len();
+                }
+            )*
+        };
+    }
+
+    count!(
+        Sum<u64>,
+        Sum<i64>,
+        Sum<f64>,
+        Gauge<u64>,
+        Gauge<i64>,
+        Gauge<f64>,
+        data::Histogram<u64>,
+        data::Histogram<i64>,
+        data::Histogram<f64>,
+        ExponentialHistogram<u64>,
+        ExponentialHistogram<i64>,
+        ExponentialHistogram<f64>
+    );
+    0
+}
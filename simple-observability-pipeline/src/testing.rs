@@ -0,0 +1,340 @@
+//! In-memory exporters for testing instrumentation without a collector.
+//!
+//! ```ignore
+//! let (mut providers, telemetry) = create_test_providers(resource, &ProvidersConfig::default())?;
+//! let _guard = tracing::subscriber::set_default(providers.take_subscriber().unwrap());
+//!
+//! run_instrumented_code().await;
+//!
+//! telemetry.assert_span_with_parent("consumer", "recv_async");
+//! telemetry.assert_counter("service.request", 1.0, &[KeyValue::new("method", "GET")]);
+//! telemetry.assert_log("received value");
+//! ```
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use opentelemetry::logs::{AnyValue, LogResult};
+use opentelemetry::metrics::Result as MetricsResult;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::export::logs::{LogData, LogExporter};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::logs::LoggerProvider;
+use opentelemetry_sdk::metrics::data::{Gauge, ResourceMetrics, Sum, Temporality};
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, MetricReader, TemporalitySelector};
+use opentelemetry_sdk::metrics::{
+    Aggregation, InstrumentKind, ManualReader, Pipeline, SdkMeterProvider,
+};
+use opentelemetry_sdk::{trace as sdktrace, Resource};
+
+use crate::config::ProvidersConfig;
+use crate::providers::ObservabilityProviders;
+use crate::telemetry::{InstrumentedExporter, PipelineTelemetry};
+
+/// Creates the same providers and subscriber as [`crate::create_providers`], except that
+/// everything is kept in memory and can be inspected with the returned [`TestTelemetry`].
+///
+/// Spans and log records are exported as soon as they end, and metrics are collected whenever
+/// they are inspected, so there's no need to flush. `config.exporter` is ignored.
+pub fn create_test_providers(
+    resource: Resource,
+    config: &ProvidersConfig,
+) -> Result<(ObservabilityProviders, TestTelemetry)> {
+    let telemetry = PipelineTelemetry::new();
+    let spans = InMemorySpanExporter::default();
+    let logs = InMemoryLogExporter::default();
+
+    let tracer_provider = sdktrace::TracerProvider::builder()
+        .with_config(
+            sdktrace::Config::default()
+                .with_resource(resource.clone())
+                .with_sampler(config.sampler.build()),
+        )
+        .with_simple_exporter(InstrumentedExporter::new(spans.clone(), telemetry.clone()))
+        .build();
+
+    let aggregation_selector = config.metrics.aggregation_selector();
+    let reader = SharedReader(Arc::new(
+        ManualReader::builder()
+            .with_temporality_selector(config.metrics.temporality)
+            .with_aggregation_selector(move |kind: InstrumentKind| {
+                aggregation_selector.aggregation(kind)
+            })
+            .build(),
+    ));
+    let mut builder = SdkMeterProvider::builder()
+        .with_reader(reader.clone())
+        .with_resource(resource.clone());
+    for view in &config.metrics.views {
+        builder = builder.with_view(view.build()?);
+    }
+    let meter_provider = builder.build();
+    telemetry.register(&meter_provider);
+
+    let logger_provider = LoggerProvider::builder()
+        .with_resource(resource)
+        .with_simple_exporter(InstrumentedExporter::new(logs.clone(), telemetry.clone()))
+        .build();
+
+    let providers = crate::build_providers(
+        tracer_provider,
+        meter_provider,
+        logger_provider,
+        config,
+        telemetry,
+    )?;
+
+    Ok((
+        providers,
+        TestTelemetry {
+            spans,
+            logs,
+            reader,
+        },
+    ))
+}
+
+/// The telemetry recorded by the providers from [`create_test_providers`].
+///
+/// The `assert_*` methods panic with a description of what was recorded instead, so that they can
+/// be used directly in tests.
+#[derive(Debug, Clone)]
+pub struct TestTelemetry {
+    spans: InMemorySpanExporter,
+    logs: InMemoryLogExporter,
+    reader: SharedReader,
+}
+
+impl TestTelemetry {
+    /// Every span that has ended so far, in the order they ended.
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.spans.0.lock().expect("span store poisoned").clone()
+    }
+
+    pub fn find_span(&self, name: &str) -> Option<SpanData> {
+        self.spans().into_iter().find(|span| span.name == name)
+    }
+
+    /// Asserts that a span named `name` has ended, and returns the first one.
+    pub fn assert_span(&self, name: &str) -> SpanData {
+        match self.find_span(name) {
+            Some(span) => span,
+            None => panic!(
+                "No span named {:?}, recorded spans: {:?}",
+                name,
+                self.span_names()
+            ),
+        }
+    }
+
+    /// Asserts that a span named `name` has ended whose parent is a span named `parent`.
+    pub fn assert_span_with_parent(&self, name: &str, parent: &str) -> SpanData {
+        let spans = self.spans();
+        let child = spans.iter().find(|child| {
+            child.name == name
+                && spans.iter().any(|candidate| {
+                    candidate.name == parent
+                        && candidate.span_context.span_id() == child.parent_span_id
+                })
+        });
+
+        match child {
+            Some(child) => child.clone(),
+            None => {
+                let parents: Vec<_> = spans
+                    .iter()
+                    .filter(|span| span.name == name)
+                    .map(|span| {
+                        spans
+                            .iter()
+                            .find(|candidate| {
+                                candidate.span_context.span_id() == span.parent_span_id
+                            })
+                            .map(|parent| parent.name.clone())
+                    })
+                    .collect();
+                panic!(
+                    "No span named {:?} with parent {:?}, parents of the spans named {:?}: {:?}",
+                    name, parent, name, parents
+                )
+            }
+        }
+    }
+
+    /// Every log record emitted so far, in the order they were emitted.
+    pub fn logs(&self) -> Vec<LogData> {
+        self.logs.0.lock().expect("log store poisoned").clone()
+    }
+
+    /// Asserts that a log record was emitted with `body`, and returns the first one.
+    pub fn assert_log(&self, body: &str) -> LogData {
+        let logs = self.logs();
+        let found = logs.iter().find(
+            |log| matches!(&log.record.body, Some(AnyValue::String(b)) if b.as_str() == body),
+        );
+
+        match found {
+            Some(log) => log.clone(),
+            None => {
+                let bodies: Vec<_> = logs.iter().map(|log| &log.record.body).collect();
+                panic!(
+                    "No log record with body {:?}, recorded bodies: {:?}",
+                    body, bodies
+                )
+            }
+        }
+    }
+
+    /// The current value of the counter, up-down counter or gauge named `name`, summed over every
+    /// data point that has at least the given attributes.
+    ///
+    /// Returns `None` if no data point matches.
+    pub fn metric_value(&self, name: &str, attributes: &[KeyValue]) -> Option<f64> {
+        let mut metrics = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        self.reader
+            .collect(&mut metrics)
+            .expect("Failed to collect metrics");
+
+        let mut value = None;
+        for metric in metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| scope.metrics.iter())
+            .filter(|metric| metric.name == name)
+        {
+            for (point_attributes, point_value) in data_points(metric.data.as_any()) {
+                if attributes
+                    .iter()
+                    .all(|attribute| point_attributes.contains(attribute))
+                {
+                    *value.get_or_insert(0.0) += point_value;
+                }
+            }
+        }
+        value
+    }
+
+    /// Asserts that the counter named `name` has `value` for the given attributes. See
+    /// [`TestTelemetry::metric_value`].
+    pub fn assert_counter(&self, name: &str, value: f64, attributes: &[KeyValue]) {
+        match self.metric_value(name, attributes) {
+            Some(actual) => assert_eq!(
+                actual, value,
+                "Counter {:?} with attributes {:?} has the wrong value",
+                name, attributes
+            ),
+            None => panic!(
+                "No counter named {:?} with attributes {:?}",
+                name, attributes
+            ),
+        }
+    }
+
+    /// Forgets every span and log record recorded so far. Metrics are cumulative by default, so
+    /// they are not reset.
+    pub fn reset(&self) {
+        self.spans.0.lock().expect("span store poisoned").clear();
+        self.logs.0.lock().expect("log store poisoned").clear();
+    }
+
+    fn span_names(&self) -> Vec<Cow<'static, str>> {
+        self.spans().into_iter().map(|span| span.name).collect()
+    }
+}
+
+fn data_points(data: &dyn std::any::Any) -> Vec<(&[KeyValue], f64)> {
+    macro_rules! points {
+        ($($ty:ty),*) => {
+            $(
+                if let Some(data) = data.downcast_ref::<$ty>() {
+                    return data
+                        .data_points
+                        .iter()
+                        .map(|point| (point.attributes.as_slice(), point.value as f64))
+                        .collect();
+                }
+            )*
+        };
+    }
+
+    points!(
+        Sum<u64>,
+        Sum<i64>,
+        Sum<f64>,
+        Gauge<u64>,
+        Gauge<i64>,
+        Gauge<f64>
+    );
+    Vec::new()
+}
+
+#[derive(Debug, Clone, Default)]
+struct InMemorySpanExporter(Arc<Mutex<Vec<SpanData>>>);
+
+impl SpanExporter for InMemorySpanExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        self.0.lock().expect("span store poisoned").extend(batch);
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct InMemoryLogExporter(Arc<Mutex<Vec<LogData>>>);
+
+#[tonic::async_trait]
+impl LogExporter for InMemoryLogExporter {
+    async fn export<'a>(&mut self, batch: Vec<Cow<'a, LogData>>) -> LogResult<()> {
+        self.0
+            .lock()
+            .expect("log store poisoned")
+            .extend(batch.into_iter().map(Cow::into_owned));
+        Ok(())
+    }
+}
+
+/// A [`ManualReader`] that can be both registered with the meter provider and kept to collect
+/// from.
+#[derive(Clone)]
+struct SharedReader(Arc<ManualReader>);
+
+impl fmt::Debug for SharedReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl TemporalitySelector for SharedReader {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+impl AggregationSelector for SharedReader {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.0.aggregation(kind)
+    }
+}
+
+impl MetricReader for SharedReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricsResult<()> {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> MetricsResult<()> {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> MetricsResult<()> {
+        self.0.shutdown()
+    }
+}
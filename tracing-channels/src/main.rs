@@ -1,29 +1,13 @@
 use anyhow::Result;
-use once_cell::sync::Lazy;
 use simple_observability_pipeline::{
+    config::DEFAULT_GRPC_ENDPOINT,
     create_providers,
     opentelemetry::{global, KeyValue},
-    opentelemetry_sdk::{
-        logs::LoggerProvider, metrics::SdkMeterProvider, propagation::TraceContextPropagator,
-        Resource,
-    },
+    opentelemetry_sdk::{propagation::TraceContextPropagator, Resource},
+    ExporterConfig, ObservabilityProviders, ProvidersConfig, ResourceBuilder, SamplerConfig,
 };
-use tracing::{error, instrument, Instrument};
+use tracing::{instrument, Instrument};
 use tracing_channels::{new_bounded_channel, new_unbounded_channel, TracedReceiver, TracedSender};
-use tracing_subscriber::util::SubscriberInitExt;
-
-static RESOURCE: Lazy<Resource> = Lazy::new(|| {
-    Resource::new(vec![
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-            "basic-otlp-client",
-        ),
-        KeyValue::new(
-            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
-            "localhost",
-        ),
-    ])
-});
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let observability_rt = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
@@ -131,46 +115,30 @@ fn inner_trace() {
     tracing::info!("inner trace");
 }
 
+fn resource() -> Resource {
+    ResourceBuilder::new("basic-otlp-client")
+        .with_service_version(env!("CARGO_PKG_VERSION"))
+        .with_attribute(KeyValue::new(
+            opentelemetry_semantic_conventions::resource::URL_DOMAIN,
+            "localhost",
+        ))
+        .build()
+}
+
 async fn init_observability() -> Result<ObservabilityProviders> {
-    let (tracing_provider, metrics_provider, subscriber, logger_provider) =
-        create_providers(RESOURCE.clone(), false, false).await?;
+    // The channel loops produce a trace per message, so only keep a few of them per second
+    // unless OTEL_TRACES_SAMPLER says otherwise.
+    let sampler = SamplerConfig::parent_based(SamplerConfig::RateLimited {
+        traces_per_second: 5.0,
+    })
+    .merge_env()?;
+    let config = ProvidersConfig::new(ExporterConfig::grpc(DEFAULT_GRPC_ENDPOINT).merge_env()?)
+        .with_sampler(sampler);
+    let mut observability_providers = create_providers(resource(), &config).await?;
 
     // Set globals
     global::set_text_map_propagator(TraceContextPropagator::new());
-    global::set_tracer_provider(tracing_provider);
-    global::set_meter_provider(metrics_provider.clone());
-    subscriber.init();
-
-    Ok(ObservabilityProviders::new(
-        metrics_provider,
-        logger_provider,
-    ))
-}
+    observability_providers.install_globals()?;
 
-#[derive(Default)]
-struct ObservabilityProviders {
-    meter_provider: Option<SdkMeterProvider>,
-    logger_provider: Option<LoggerProvider>,
-}
-
-impl ObservabilityProviders {
-    fn new(meter_provider: SdkMeterProvider, logger_provider: LoggerProvider) -> Self {
-        Self {
-            meter_provider: Some(meter_provider),
-            logger_provider: Some(logger_provider),
-        }
-    }
-
-    fn shutdown(self) {
-        if let Some(meter_provider) = self.meter_provider {
-            if let Err(e) = meter_provider.shutdown() {
-                error!("Failed to shutdown metrics provider: {:?}", e);
-            }
-        }
-        if let Some(logger_provider) = self.logger_provider {
-            if let Err(e) = logger_provider.shutdown() {
-                error!("Failed to shutdown logger provider: {:?}", e);
-            }
-        }
-    }
+    Ok(observability_providers)
 }
@@ -0,0 +1,44 @@
+use simple_observability_pipeline::{
+    create_test_providers,
+    opentelemetry::global,
+    opentelemetry_sdk::{propagation::TraceContextPropagator, Resource},
+    ProvidersConfig,
+};
+use tracing::Instrument;
+use tracing_channels::new_bounded_channel;
+
+#[tokio::test]
+async fn receiver_spans_are_children_of_the_sender() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let (mut providers, telemetry) =
+        create_test_providers(Resource::empty(), &ProvidersConfig::default())
+            .expect("Failed to create test providers");
+    let _guard = tracing::subscriber::set_default(providers.take_subscriber().unwrap());
+
+    let (tx, rx) = new_bounded_channel::<bool>(1);
+    // `send_async` creates its span when called, so the producer span has to be entered around
+    // the call and not only the returned future.
+    async { tx.send_async(true).await }
+        .instrument(tracing::info_span!("producer"))
+        .await
+        .expect("Failed to send value");
+
+    let (_msg, span) = rx.recv_async().await.expect("Failed to receive value");
+    async { tracing::info!("received value") }
+        .instrument(span)
+        .await;
+
+    // The spans end once every handle to them is dropped.
+    drop(rx);
+
+    telemetry.assert_span_with_parent("send_async", "producer");
+    telemetry.assert_span_with_parent("recv_async", "send_async");
+    telemetry.assert_span_with_parent("consumer", "recv_async");
+
+    let log = telemetry.assert_log("received value");
+    let consumer = telemetry.assert_span("consumer");
+    assert_eq!(
+        log.record.trace_context.map(|cx| cx.span_id),
+        Some(consumer.span_context.span_id())
+    );
+}